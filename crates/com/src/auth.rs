@@ -0,0 +1,818 @@
+//! Authentication of hosts at the RPC server of the client application.
+//!
+//! After the wire format preface, the server sends its device id and a random challenge.
+//! The host then either
+//! - authenticates with the id of a key it was paired with and the HMAC-SHA256 of the challenge,
+//!   keyed with the shared secret, or
+//! - requests pairing. The device displays a one-time code and both sides run the
+//!   [CPace](https://datatracker.ietf.org/doc/draft-irtf-cfrg-cpace/) password authenticated key exchange
+//!   over ristretto255 with it. Only a peer that knows the code derives the same key, which both sides confirm.
+//!   An attacker intercepting the connection learns nothing to test guesses of the code offline,
+//!   it gets a single guess per displayed code, and pairing is locked after a few wrong guesses.
+//!   The shared secret is derived from the key and never sent over the network.
+//!
+//! The server answers with a [Status] byte, the RPC messages only follow a successful authentication.
+//!
+//! Over TLS, the fingerprint of the device certificate is bound into the handshake,
+//! so it fails when the connection is intercepted. Pairing pins the certificate in the [Credentials].
+
+use crate::tls::Fingerprint;
+use anyhow::anyhow;
+use core::time::Duration;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use futures::future::Either;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256, Sha512};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Timeout for receiving a handshake message.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the one-time code is displayed while waiting for the host to send its proof.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often the host signals that the user is still entering the code, below [HANDSHAKE_TIMEOUT].
+const PAIRING_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
+/// Wrong codes after which pairing is refused until the client application restarts.
+const MAX_PAIRING_FAILURES: u32 = 5;
+
+const MODE_AUTHENTICATE: u8 = b'a';
+const MODE_PAIR: u8 = b'P';
+/// Sent by the host while the user enters the code.
+const PAIR_ENTERING: u8 = b'.';
+/// Sent by the host before its public key, once the user entered the code.
+const PAIR_ENTERED: u8 = b'!';
+
+const AUTH_CONTEXT: &[u8] = b"pb-cheatsheet auth";
+const PAIR_GENERATOR_CONTEXT: &[u8] = b"pb-cheatsheet pair generator";
+const PAIR_KEY_CONTEXT: &[u8] = b"pb-cheatsheet pair key";
+const PAIR_HOST_CONFIRM_CONTEXT: &[u8] = b"pb-cheatsheet pair host confirm";
+const PAIR_DEVICE_CONFIRM_CONTEXT: &[u8] = b"pb-cheatsheet pair device confirm";
+const PAIR_SECRET_CONTEXT: &[u8] = b"pb-cheatsheet pair secret";
+
+pub type DeviceId = [u8; 16];
+pub type KeyId = [u8; 16];
+pub type Secret = [u8; 32];
+
+/// Result of the handshake, sent by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    /// The key is unknown or the proof of its possession is invalid.
+    Denied = 1,
+    /// Another host is currently pairing.
+    PairingBusy = 2,
+    /// The entered one-time code did not match, or the pairing was intercepted.
+    WrongCode = 3,
+    /// Too many wrong codes were entered, see [MAX_PAIRING_FAILURES].
+    PairingLocked = 4,
+}
+
+impl Status {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Status::Ok),
+            1 => Some(Status::Denied),
+            2 => Some(Status::PairingBusy),
+            3 => Some(Status::WrongCode),
+            4 => Some(Status::PairingLocked),
+            _ => None,
+        }
+    }
+
+    fn into_result(self) -> anyhow::Result<()> {
+        match self {
+            Status::Ok => Ok(()),
+            Status::Denied => Err(anyhow!(
+                "Authentication denied by the device, pair with it again"
+            )),
+            Status::PairingBusy => Err(anyhow!("The device is already pairing with another host")),
+            Status::WrongCode => Err(anyhow!("The entered code does not match")),
+            Status::PairingLocked => Err(anyhow!(
+                "Pairing is locked after too many wrong codes, restart the application on the device"
+            )),
+        }
+    }
+}
+
+/// The key a host is paired with a device.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Credentials {
+    #[serde(with = "hex_bytes")]
+    pub key_id: KeyId,
+    #[serde(with = "hex_bytes")]
+    pub secret: Secret,
+    /// Fingerprint of the device certificate, when paired over TLS.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "hex_bytes::option"
+    )]
+    pub certificate: Option<Fingerprint>,
+}
+
+/// The paired hosts of the server, persisted by the client application.
+pub trait PairingStore: Send + Sync {
+    /// Identifies the device towards hosts, which can be paired with multiple devices.
+    fn device_id(&self) -> DeviceId;
+
+    fn secret(&self, key_id: &KeyId) -> Option<Secret>;
+
+    /// Stores the key of a newly paired host.
+    fn insert(
+        &self,
+        key_id: KeyId,
+        secret: Secret,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Displays the one-time code to the user while pairing, `None` hides it again.
+    fn show_code(&self, code: Option<&str>);
+}
+
+/// How a peer was accepted by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accepted {
+    /// Authenticated with the key of a paired host, RPC messages follow.
+    Authenticated(KeyId),
+    /// Newly paired, the connection ends.
+    Paired(KeyId),
+}
+
+/// Only one host can pair at a time, so only one code is displayed.
+static PAIRING: AtomicBool = AtomicBool::new(false);
+/// Wrong codes entered since the last successful pairing, see [MAX_PAIRING_FAILURES].
+static PAIRING_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Held while pairing, hides the code and allows the next pairing when dropped.
+struct PairingGuard<'a, P: PairingStore> {
+    store: &'a P,
+}
+
+impl<'a, P: PairingStore> PairingGuard<'a, P> {
+    fn acquire(store: &'a P) -> Option<Self> {
+        (!PAIRING.swap(true, Ordering::AcqRel)).then_some(Self { store })
+    }
+}
+
+impl<P: PairingStore> Drop for PairingGuard<'_, P> {
+    fn drop(&mut self) {
+        self.store.show_code(None);
+        PAIRING.store(false, Ordering::Release);
+    }
+}
+
+/// Performs the server side of the handshake.
+///
+/// `certificate` is the fingerprint of the own certificate when the connection is secured with TLS.
+/// Returns an error when the peer is not authenticated.
+pub async fn accept<S>(
+    stream: &mut S,
+    store: &impl PairingStore,
+    certificate: Option<&Fingerprint>,
+) -> anyhow::Result<Accepted>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let device_id = store.device_id();
+    let challenge = random::<32>();
+    stream.write_all(&device_id).await?;
+    stream.write_all(&challenge).await?;
+    stream.flush().await?;
+
+    match timeout(HANDSHAKE_TIMEOUT, stream.read_u8()).await? {
+        MODE_AUTHENTICATE => {
+            let key_id = timeout(HANDSHAKE_TIMEOUT, read_array::<_, 16>(stream)).await?;
+            let tag = timeout(HANDSHAKE_TIMEOUT, read_array::<_, 32>(stream)).await?;
+            let valid = store.secret(&key_id).is_some_and(|secret| {
+                auth_mac(&secret, &device_id, &challenge, certificate)
+                    .verify_slice(&tag)
+                    .is_ok()
+            });
+            if !valid {
+                write_status(stream, Status::Denied).await?;
+                return Err(anyhow!("Authentication with key '{}' failed", hex(&key_id)));
+            }
+            write_status(stream, Status::Ok).await?;
+            Ok(Accepted::Authenticated(key_id))
+        }
+        MODE_PAIR => {
+            if PAIRING_FAILURES.load(Ordering::Acquire) >= MAX_PAIRING_FAILURES {
+                write_status(stream, Status::PairingLocked).await?;
+                return Err(anyhow!(
+                    "Rejected pairing, locked after {MAX_PAIRING_FAILURES} wrong codes until restarted"
+                ));
+            }
+            let Some(_guard) = PairingGuard::acquire(store) else {
+                write_status(stream, Status::PairingBusy).await?;
+                return Err(anyhow!("Rejected pairing, another host is pairing"));
+            };
+            pair_server(stream, store, &device_id, &challenge, certificate).await
+        }
+        mode => Err(anyhow!("Received invalid handshake mode '{mode:#x}'")),
+    }
+}
+
+/// Pairs with a new, random code for each attempt, so a wrong guess can't be repeated.
+async fn pair_server<S>(
+    stream: &mut S,
+    store: &impl PairingStore,
+    device_id: &DeviceId,
+    challenge: &[u8; 32],
+    certificate: Option<&Fingerprint>,
+) -> anyhow::Result<Accepted>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let code = format!("{:06}", OsRng.next_u32() % 1_000_000);
+    let exchange = KeyExchange::new(&code, device_id, challenge, certificate);
+    let public = exchange.public;
+    store.show_code(Some(&code));
+    stream.write_u8(Status::Ok as u8).await?;
+    stream.write_all(&public).await?;
+    stream.flush().await?;
+
+    // The host sends its public key once the user entered the code, until then it keeps the pairing alive,
+    // so a peer that doesn't pair blocks pairing for no longer than the handshake timeout
+    let deadline = tokio::time::Instant::now() + PAIRING_TIMEOUT;
+    loop {
+        if tokio::time::Instant::now() >= deadline {
+            return Err(anyhow!("Pairing timed out, the code was not entered"));
+        }
+        match timeout(HANDSHAKE_TIMEOUT, stream.read_u8()).await? {
+            PAIR_ENTERING => continue,
+            PAIR_ENTERED => break,
+            byte => return Err(anyhow!("Received invalid pairing message '{byte:#x}'")),
+        }
+    }
+    let host_public = timeout(HANDSHAKE_TIMEOUT, read_array::<_, 32>(stream)).await?;
+    let host_confirm = timeout(HANDSHAKE_TIMEOUT, read_array::<_, 32>(stream)).await?;
+    let transcript = transcript(device_id, challenge, certificate, &host_public, &public);
+    let Some(key) = exchange.finish(&host_public, &transcript).filter(|key| {
+        pair_mac(key, PAIR_HOST_CONFIRM_CONTEXT, &transcript)
+            .verify_slice(&host_confirm)
+            .is_ok()
+    }) else {
+        PAIRING_FAILURES.fetch_add(1, Ordering::AcqRel);
+        write_status(stream, Status::WrongCode).await?;
+        return Err(anyhow!(
+            "Pairing failed, the host entered a wrong code or the pairing was intercepted"
+        ));
+    };
+    PAIRING_FAILURES.store(0, Ordering::Release);
+    let key_id = random::<16>();
+    store.insert(key_id, pair_secret(&key, &transcript)).await?;
+    let device_confirm = pair_mac(&key, PAIR_DEVICE_CONFIRM_CONTEXT, &transcript).finalize();
+    stream.write_u8(Status::Ok as u8).await?;
+    stream.write_all(&device_confirm.into_bytes()).await?;
+    stream.write_all(&key_id).await?;
+    stream.flush().await?;
+    Ok(Accepted::Paired(key_id))
+}
+
+/// Performs the host side of the handshake, authenticating with the credentials of the device.
+///
+/// `certificate` is the fingerprint of the certificate the server presented over TLS,
+/// it has to match the pinned one.
+/// `credentials` looks up the credentials for the device id sent by the server.
+pub async fn authenticate<S>(
+    stream: &mut S,
+    certificate: Option<&Fingerprint>,
+    credentials: impl FnOnce(&DeviceId) -> Option<Credentials>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (device_id, challenge) = read_hello(stream).await?;
+    let credentials = credentials(&device_id).ok_or_else(|| {
+        anyhow!(
+            "Not paired with device '{}', pair with it first",
+            hex(&device_id)
+        )
+    })?;
+    if let Some(certificate) = certificate {
+        if credentials.certificate.as_ref() != Some(certificate) {
+            return Err(anyhow!(
+                "Certificate of device '{}' does not match the pinned certificate, pair with it again",
+                hex(&device_id)
+            ));
+        }
+    }
+    let tag = auth_mac(&credentials.secret, &device_id, &challenge, certificate).finalize();
+    stream.write_u8(MODE_AUTHENTICATE).await?;
+    stream.write_all(&credentials.key_id).await?;
+    stream.write_all(&tag.into_bytes()).await?;
+    stream.flush().await?;
+    read_status(stream, HANDSHAKE_TIMEOUT).await?.into_result()
+}
+
+/// Performs the host side of the pairing handshake.
+///
+/// `certificate` is the fingerprint of the certificate the server presented over TLS, it gets pinned.
+/// `read_code` is called once the device displays the one-time code and returns the code entered by the user.
+pub async fn pair<S, F>(
+    stream: &mut S,
+    certificate: Option<&Fingerprint>,
+    read_code: impl FnOnce() -> F,
+) -> anyhow::Result<(DeviceId, Credentials)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Future<Output = anyhow::Result<String>>,
+{
+    let (device_id, challenge) = read_hello(stream).await?;
+    stream.write_u8(MODE_PAIR).await?;
+    stream.flush().await?;
+
+    read_status(stream, HANDSHAKE_TIMEOUT)
+        .await?
+        .into_result()?;
+    let device_public = timeout(HANDSHAKE_TIMEOUT, read_array::<_, 32>(stream)).await?;
+
+    let code = {
+        let mut read_code = std::pin::pin!(read_code());
+        loop {
+            let keepalive = std::pin::pin!(tokio::time::sleep(PAIRING_KEEPALIVE_INTERVAL));
+            match futures::future::select(read_code.as_mut(), keepalive).await {
+                Either::Left((code, _)) => break code?,
+                Either::Right(_) => {
+                    stream.write_u8(PAIR_ENTERING).await?;
+                    stream.flush().await?;
+                }
+            }
+        }
+    };
+    let exchange = KeyExchange::new(code.trim(), &device_id, &challenge, certificate);
+    let public = exchange.public;
+    let transcript = transcript(&device_id, &challenge, certificate, &public, &device_public);
+    let key = exchange
+        .finish(&device_public, &transcript)
+        .ok_or_else(|| anyhow!("Received invalid pairing key from the device"))?;
+    let host_confirm = pair_mac(&key, PAIR_HOST_CONFIRM_CONTEXT, &transcript).finalize();
+    stream.write_u8(PAIR_ENTERED).await?;
+    stream.write_all(&public).await?;
+    stream.write_all(&host_confirm.into_bytes()).await?;
+    stream.flush().await?;
+    read_status(stream, HANDSHAKE_TIMEOUT)
+        .await?
+        .into_result()?;
+    let device_confirm = timeout(HANDSHAKE_TIMEOUT, read_array::<_, 32>(stream)).await?;
+    pair_mac(&key, PAIR_DEVICE_CONFIRM_CONTEXT, &transcript)
+        .verify_slice(&device_confirm)
+        .map_err(|_| anyhow!("Device did not confirm the pairing, it might be intercepted"))?;
+    let key_id = timeout(HANDSHAKE_TIMEOUT, read_array::<_, 16>(stream)).await?;
+
+    Ok((
+        device_id,
+        Credentials {
+            key_id,
+            secret: pair_secret(&key, &transcript),
+            certificate: certificate.copied(),
+        },
+    ))
+}
+
+async fn read_hello<S>(stream: &mut S) -> anyhow::Result<(DeviceId, [u8; 32])>
+where
+    S: AsyncRead + Unpin,
+{
+    let device_id = timeout(HANDSHAKE_TIMEOUT, read_array::<_, 16>(stream)).await?;
+    let challenge = timeout(HANDSHAKE_TIMEOUT, read_array::<_, 32>(stream)).await?;
+    Ok((device_id, challenge))
+}
+
+async fn read_status<S>(stream: &mut S, duration: Duration) -> anyhow::Result<Status>
+where
+    S: AsyncRead + Unpin,
+{
+    let byte = timeout(duration, stream.read_u8()).await?;
+    Status::from_byte(byte).ok_or_else(|| anyhow!("Received invalid handshake status '{byte:#x}'"))
+}
+
+async fn write_status<S>(stream: &mut S, status: Status) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_u8(status as u8).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_array<S, const N: usize>(stream: &mut S) -> std::io::Result<[u8; N]>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn timeout<T>(
+    duration: Duration,
+    future: impl Future<Output = std::io::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| anyhow!("Handshake timed out"))?
+        .map_err(Into::into)
+}
+
+fn auth_mac(
+    secret: &Secret,
+    device_id: &DeviceId,
+    challenge: &[u8; 32],
+    certificate: Option<&Fingerprint>,
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(AUTH_CONTEXT);
+    mac.update(device_id);
+    mac.update(challenge);
+    if let Some(certificate) = certificate {
+        mac.update(certificate);
+    }
+    mac
+}
+
+/// CPace key exchange with a generator derived from the one-time code and the session.
+///
+/// The public keys of peers using a different code, challenge or certificate
+/// are unrelated to the own generator, so they derive a different key.
+struct KeyExchange {
+    scalar: Scalar,
+    public: [u8; 32],
+}
+
+impl KeyExchange {
+    fn new(
+        code: &str,
+        device_id: &DeviceId,
+        challenge: &[u8; 32],
+        certificate: Option<&Fingerprint>,
+    ) -> Self {
+        let mut hasher = Sha512::new();
+        for field in [
+            PAIR_GENERATOR_CONTEXT,
+            code.as_bytes(),
+            device_id,
+            challenge,
+            certificate.map_or(&[], |certificate| certificate.as_slice()),
+        ] {
+            hasher.update((field.len() as u64).to_le_bytes());
+            hasher.update(field);
+        }
+        let mut uniform = [0; 64];
+        uniform.copy_from_slice(&hasher.finalize());
+        let generator = RistrettoPoint::from_uniform_bytes(&uniform);
+        let scalar = Scalar::from_bytes_mod_order_wide(&random::<64>());
+        Self {
+            scalar,
+            public: (generator * scalar).compress().to_bytes(),
+        }
+    }
+
+    /// Derives the key from the public key of the peer, `None` when it is invalid.
+    fn finish(self, peer_public: &[u8; 32], transcript: &[u8]) -> Option<[u8; 32]> {
+        let peer = CompressedRistretto(*peer_public)
+            .decompress()
+            .filter(|peer| !peer.is_identity())?;
+        let shared = (peer * self.scalar).compress();
+        Some(
+            pair_mac(shared.as_bytes(), PAIR_KEY_CONTEXT, transcript)
+                .finalize()
+                .into_bytes()
+                .into(),
+        )
+    }
+}
+
+/// Everything both sides agreed on while pairing.
+fn transcript(
+    device_id: &DeviceId,
+    challenge: &[u8; 32],
+    certificate: Option<&Fingerprint>,
+    host_public: &[u8; 32],
+    device_public: &[u8; 32],
+) -> Vec<u8> {
+    [
+        device_id.as_slice(),
+        challenge,
+        certificate.map_or(&[], |certificate| certificate.as_slice()),
+        host_public,
+        device_public,
+    ]
+    .concat()
+}
+
+fn pair_mac(key: &[u8; 32], context: &[u8], transcript: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(context);
+    mac.update(transcript);
+    mac
+}
+
+fn pair_secret(key: &[u8; 32], transcript: &[u8]) -> Secret {
+    pair_mac(key, PAIR_SECRET_CONTEXT, transcript)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// Random bytes from the operating system.
+pub fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Lowercase hex encoding, used for ids and secrets in persisted files.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Decodes lowercase or uppercase hex into a fixed size array.
+pub fn from_hex<const N: usize>(hex: &str) -> anyhow::Result<[u8; N]> {
+    if hex.len() != N * 2 {
+        return Err(anyhow!(
+            "Invalid hex length {}, expected {}",
+            hex.len(),
+            N * 2
+        ));
+    }
+    let mut bytes = [0; N];
+    for (byte, chunk) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(chunk)?, 16)?;
+    }
+    Ok(bytes)
+}
+
+/// Serializes fixed size byte arrays as hex strings.
+pub mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        bytes: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::hex(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        super::from_hex(&hex).map_err(serde::de::Error::custom)
+    }
+
+    /// Serializes optional fixed size byte arrays as hex strings.
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer, const N: usize>(
+            bytes: &Option<[u8; N]>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match bytes {
+                Some(bytes) => serializer.serialize_some(&super::super::hex(bytes)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+            deserializer: D,
+        ) -> Result<Option<[u8; N]>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|hex| super::super::from_hex(&hex).map_err(serde::de::Error::custom))
+                .transpose()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tokio::io::DuplexStream;
+
+    const CERTIFICATE: Fingerprint = [1; 32];
+
+    /// Pairing is limited to one at a time per process.
+    static PAIRING_TESTS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    #[derive(Default)]
+    struct TestStore {
+        secrets: Mutex<HashMap<KeyId, Secret>>,
+        code: Mutex<Option<String>>,
+    }
+
+    impl PairingStore for TestStore {
+        fn device_id(&self) -> DeviceId {
+            [7; 16]
+        }
+
+        fn secret(&self, key_id: &KeyId) -> Option<Secret> {
+            self.secrets.lock().unwrap().get(key_id).copied()
+        }
+
+        async fn insert(&self, key_id: KeyId, secret: Secret) -> anyhow::Result<()> {
+            self.secrets.lock().unwrap().insert(key_id, secret);
+            Ok(())
+        }
+
+        fn show_code(&self, code: Option<&str>) {
+            if let Some(code) = code {
+                *self.code.lock().unwrap() = Some(code.to_string());
+            }
+        }
+    }
+
+    /// Pairs through `host` and `device`, the host enters `code` or the displayed one.
+    ///
+    /// The device has the `CERTIFICATE`, the host sees `host_certificate`.
+    async fn pair_with(
+        store: &TestStore,
+        host: DuplexStream,
+        device: DuplexStream,
+        host_certificate: &Fingerprint,
+        code: Option<&str>,
+    ) -> (anyhow::Result<Accepted>, anyhow::Result<Credentials>) {
+        let _serial = PAIRING_TESTS.lock().await;
+        pair_serialized(store, host, device, host_certificate, code).await
+    }
+
+    /// See [pair_with], for tests already holding `PAIRING_TESTS`.
+    async fn pair_serialized(
+        store: &TestStore,
+        mut host: DuplexStream,
+        mut device: DuplexStream,
+        host_certificate: &Fingerprint,
+        code: Option<&str>,
+    ) -> (anyhow::Result<Accepted>, anyhow::Result<Credentials>) {
+        let device = async move { accept(&mut device, store, Some(&CERTIFICATE)).await };
+        let host = async move {
+            pair(&mut host, Some(host_certificate), || async {
+                Ok(code
+                    .map(str::to_string)
+                    .or_else(|| store.code.lock().unwrap().clone())
+                    .unwrap())
+            })
+            .await
+            .map(|(_, credentials)| credentials)
+        };
+        tokio::join!(device, host)
+    }
+
+    /// Connects host and device, flipping a bit of the byte at `offset` the device sends.
+    fn tampering_proxy(offset: usize) -> (DuplexStream, DuplexStream) {
+        let (host, proxy_host) = tokio::io::duplex(1024);
+        let (proxy_device, device) = tokio::io::duplex(1024);
+        let (mut from_host, mut to_host) = tokio::io::split(proxy_host);
+        let (mut from_device, mut to_device) = tokio::io::split(proxy_device);
+        tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut from_host, &mut to_device).await;
+            let _ = to_device.shutdown().await;
+        });
+        tokio::spawn(async move {
+            let mut position = 0;
+            let mut buf = [0; 256];
+            while let Ok(n @ 1..) = from_device.read(&mut buf).await {
+                if (position..position + n).contains(&offset) {
+                    buf[offset - position] ^= 1;
+                }
+                position += n;
+                if to_host.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+            let _ = to_host.shutdown().await;
+        });
+        (host, device)
+    }
+
+    #[tokio::test]
+    async fn pairs_with_displayed_code() {
+        let store = TestStore::default();
+        let (host, device) = tokio::io::duplex(1024);
+        let (accepted, credentials) = pair_with(&store, host, device, &CERTIFICATE, None).await;
+        let credentials = credentials.unwrap();
+        assert_eq!(accepted.unwrap(), Accepted::Paired(credentials.key_id));
+        assert_eq!(store.secret(&credentials.key_id), Some(credentials.secret));
+        assert_eq!(credentials.certificate, Some(CERTIFICATE));
+
+        let (mut host, mut device) = tokio::io::duplex(1024);
+        let (accepted, authenticated) = tokio::join!(
+            accept(&mut device, &store, Some(&CERTIFICATE)),
+            authenticate(&mut host, Some(&CERTIFICATE), |_| Some(credentials.clone()))
+        );
+        authenticated.unwrap();
+        assert_eq!(
+            accepted.unwrap(),
+            Accepted::Authenticated(credentials.key_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_code() {
+        let store = TestStore::default();
+        let (host, device) = tokio::io::duplex(1024);
+        let (accepted, credentials) =
+            pair_with(&store, host, device, &CERTIFICATE, Some("1234567")).await;
+        assert!(accepted.is_err());
+        assert!(credentials
+            .unwrap_err()
+            .to_string()
+            .contains("code does not match"));
+        assert!(store.secrets.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_certificate() {
+        let store = TestStore::default();
+        let (host, device) = tokio::io::duplex(1024);
+        let (accepted, credentials) = pair_with(&store, host, device, &[2; 32], None).await;
+        assert!(accepted.is_err());
+        assert!(credentials.is_err());
+        assert!(store.secrets.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_transcript() {
+        let store = TestStore::default();
+        // In the challenge following the device id
+        let (host, device) = tampering_proxy(20);
+        let (accepted, credentials) = pair_with(&store, host, device, &CERTIFICATE, None).await;
+        assert!(accepted.is_err());
+        assert!(credentials.is_err());
+        assert!(store.secrets.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn locks_pairing_after_wrong_codes() {
+        let _serial = PAIRING_TESTS.lock().await;
+        let store = TestStore::default();
+        PAIRING_FAILURES.store(MAX_PAIRING_FAILURES - 1, Ordering::Release);
+
+        let (host, device) = tokio::io::duplex(1024);
+        let (accepted, credentials) =
+            pair_serialized(&store, host, device, &CERTIFICATE, Some("1234567")).await;
+        assert!(accepted.is_err());
+        assert!(credentials.is_err());
+        assert_eq!(
+            PAIRING_FAILURES.load(Ordering::Acquire),
+            MAX_PAIRING_FAILURES
+        );
+
+        // Not even the displayed code pairs anymore
+        let (host, device) = tokio::io::duplex(1024);
+        let (accepted, credentials) =
+            pair_serialized(&store, host, device, &CERTIFICATE, None).await;
+        assert!(accepted.is_err());
+        assert!(credentials.unwrap_err().to_string().contains("locked"));
+        assert!(store.secrets.lock().unwrap().is_empty());
+
+        PAIRING_FAILURES.store(0, Ordering::Release);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn releases_pairing_of_silent_peer() {
+        let _serial = PAIRING_TESTS.lock().await;
+        let store = TestStore::default();
+        let (mut host, mut device) = tokio::io::duplex(1024);
+        let start = tokio::time::Instant::now();
+        let host = async move {
+            read_hello(&mut host).await.unwrap();
+            host.write_u8(MODE_PAIR).await.unwrap();
+            host.flush().await.unwrap();
+            // Keeps the connection open without entering a code
+            tokio::time::sleep(PAIRING_TIMEOUT).await;
+        };
+        let device = async {
+            let accepted = accept(&mut device, &store, Some(&CERTIFICATE)).await;
+            (accepted, start.elapsed())
+        };
+        let ((accepted, elapsed), ()) = tokio::join!(device, host);
+        assert!(accepted.unwrap_err().to_string().contains("timed out"));
+        assert!(elapsed < PAIRING_TIMEOUT);
+        assert!(!PAIRING.load(Ordering::Acquire));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pairs_when_entering_code_takes_long() {
+        let _serial = PAIRING_TESTS.lock().await;
+        let store = TestStore::default();
+        let (mut host, mut device) = tokio::io::duplex(1024);
+        let host = async {
+            pair(&mut host, Some(&CERTIFICATE), || async {
+                tokio::time::sleep(HANDSHAKE_TIMEOUT * 10).await;
+                Ok(store.code.lock().unwrap().clone().unwrap())
+            })
+            .await
+        };
+        let (accepted, paired) =
+            tokio::join!(accept(&mut device, &store, Some(&CERTIFICATE)), host);
+        let (_, credentials) = paired.unwrap();
+        assert_eq!(accepted.unwrap(), Accepted::Paired(credentials.key_id));
+    }
+}
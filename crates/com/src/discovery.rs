@@ -0,0 +1,198 @@
+//! Discovery of client applications in the local network through mDNS/DNS-SD.
+//!
+//! The client advertises a service of type [SERVICE_TYPE] with the port of its RPC server,
+//! the name of the device as `device` and its hex encoded [crate::auth::DeviceId] as `id` TXT property.
+
+use core::net::{IpAddr, SocketAddr};
+use core::time::Duration;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use tracing::{debug, warn};
+
+/// The DNS-SD service type the client application is advertised with.
+pub const SERVICE_TYPE: &str = "_pb-cheatsheet._tcp.local.";
+const DEVICE_PROPERTY: &str = "device";
+const ID_PROPERTY: &str = "id";
+
+/// A client application found in the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// The advertised service instance name.
+    pub instance: String,
+    /// The name of the device.
+    pub name: String,
+    /// The hex encoded device id, `None` when the advertisement doesn't carry it,
+    /// e.g. when another responder announces the same service type.
+    pub id: Option<String>,
+    pub addrs: Vec<IpAddr>,
+    pub port: u16,
+}
+
+impl Device {
+    /// The RPC server address, the client only listens on IPv4.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.addrs
+            .iter()
+            .find(|addr| addr.is_ipv4())
+            .map(|addr| SocketAddr::new(*addr, self.port))
+    }
+
+    /// Whether the device is selected by `device`, either its name, instance name or id.
+    ///
+    /// Names are compared case insensitive.
+    pub fn matches(&self, device: &str) -> bool {
+        self.name.eq_ignore_ascii_case(device)
+            || self.instance.eq_ignore_ascii_case(device)
+            || self
+                .id
+                .as_deref()
+                .is_some_and(|id| id.eq_ignore_ascii_case(device))
+    }
+
+    fn from_service_info(info: &ServiceInfo) -> Self {
+        let instance = info
+            .get_fullname()
+            .strip_suffix(SERVICE_TYPE)
+            .unwrap_or(info.get_fullname())
+            .trim_end_matches('.')
+            .to_string();
+        let mut addrs = info.get_addresses().iter().copied().collect::<Vec<_>>();
+        addrs.sort();
+        Self {
+            name: info
+                .get_property_val_str(DEVICE_PROPERTY)
+                .map(str::to_string)
+                .unwrap_or_else(|| instance.clone()),
+            id: info.get_property_val_str(ID_PROPERTY).map(str::to_string),
+            instance,
+            addrs,
+            port: info.get_port(),
+        }
+    }
+}
+
+/// Advertises the client application as long as it is alive.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    /// Advertises the RPC server listening on `port` under the given device name and id.
+    ///
+    /// The addresses are kept up to date when network interfaces change,
+    /// for example when the device reconnects to a WiFi network.
+    pub fn new(device_name: &str, device_id: &str, port: u16) -> anyhow::Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let host_name = format!("{}.local.", host_label(device_name));
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            device_name,
+            &host_name,
+            (),
+            port,
+            &[(DEVICE_PROPERTY, device_name), (ID_PROPERTY, device_id)][..],
+        )?
+        .enable_addr_auto();
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info)?;
+        debug!("Advertising '{fullname}' on port {port}");
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            warn!("Unregistering mDNS service failed, Err: {e:?}");
+        }
+        if let Err(e) = self.daemon.shutdown() {
+            warn!("Shutting down mDNS daemon failed, Err: {e:?}");
+        }
+    }
+}
+
+/// Browses for client applications until `timeout` elapsed.
+///
+/// With a `device` filter only the [Device::matches] are returned,
+/// as soon as one with an IPv4 address is found.
+/// The addresses of a device might be resolved one after another.
+pub async fn discover(timeout: Duration, device: Option<&str>) -> anyhow::Result<Vec<Device>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let mut devices: Vec<Device> = Vec::new();
+
+    let browse = async {
+        while let Ok(event) = events.recv_async().await {
+            let ServiceEvent::ServiceResolved(info) = event else {
+                continue;
+            };
+            let found = Device::from_service_info(&info);
+            debug!("Discovered device {found:?}");
+            if device.is_some_and(|device| !found.matches(device)) {
+                continue;
+            }
+            let reachable = found.socket_addr().is_some();
+            match devices.iter_mut().find(|d| d.instance == found.instance) {
+                Some(existing) => *existing = found,
+                None => devices.push(found),
+            }
+            if device.is_some() && reachable {
+                break;
+            }
+        }
+    };
+    // Browsing only ends when the timeout elapsed or the selected device was found
+    let _ = tokio::time::timeout(timeout, browse).await;
+
+    if let Err(e) = daemon.shutdown() {
+        warn!("Shutting down mDNS daemon failed, Err: {e:?}");
+    }
+    devices.sort_by(|a, b| a.name.cmp(&b.name).then(a.instance.cmp(&b.instance)));
+    Ok(devices)
+}
+
+/// Converts a name into a valid DNS label.
+fn host_label(name: &str) -> String {
+    let label = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        "pb-cheatsheet".to_string()
+    } else {
+        label.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn discovers_advertised_device() {
+        let id = crate::auth::hex(&crate::auth::random::<16>());
+        let _advertisement = Advertisement::new("Test Device", &id, 50123).unwrap();
+
+        let devices = discover(Duration::from_secs(10), Some(&id)).await.unwrap();
+        let [device] = devices.as_slice() else {
+            panic!("Expected the advertised device, found {devices:?}");
+        };
+        assert_eq!(device.name, "Test Device");
+        assert_eq!(device.id.as_deref(), Some(id.as_str()));
+        assert_eq!(device.port, 50123);
+        assert!(device.matches("test device"));
+    }
+
+    #[test]
+    fn host_label_is_valid() {
+        assert_eq!(host_label("PocketBook Era (Home)"), "pocketbook-era--home");
+        assert_eq!(host_label("--"), "pb-cheatsheet");
+    }
+}
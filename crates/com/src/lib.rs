@@ -1,21 +1,244 @@
+pub mod auth;
+pub mod discovery;
+pub mod tls;
+pub mod transport;
+
 use core::fmt::Display;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::time::SystemTime;
 
 pub const RPC_PORT: u16 = 50051;
+/// Version of the RPC protocol, incremented on incompatible changes.
+///
+/// Compatible additions are announced through [Capabilities] instead.
+pub const PROTOCOL_VERSION: u32 = 3;
 
 #[tarpc::service]
 pub trait World {
-    async fn focused_window(info: FocusedWindowInfo);
-    async fn get_info() -> Info;
-    async fn upload_cheatsheet(image: CheatsheetImage, name: String, tags: HashSet<String>);
-    async fn remove_cheatsheet(name: String);
-    async fn upload_screenshot(screenshot: CheatsheetImage, name: Option<String>);
-    async fn clear_screenshot();
-    async fn add_cheatsheet_tags(name: String, tags: HashSet<String>);
-    async fn remove_cheatsheet_tags(name: String, either: TagsEither);
-    async fn add_wm_class_tags(wm_class: String, tags: HashSet<String>);
-    async fn remove_wm_class_tags(wm_class: String, either: TagsEither);
+    /// Negotiate the protocol, the first request of a host after connecting.
+    ///
+    /// Receives the protocol version of the host.
+    async fn handshake(protocol_version: u32) -> Result<Capabilities, ComError>;
+    async fn focused_window(info: FocusedWindowInfo) -> Result<(), ComError>;
+    async fn get_info() -> Result<Info, ComError>;
+    /// Begin a chunked image upload or resume a matching one that was interrupted.
+    async fn begin_upload(
+        target: UploadTarget,
+        header: UploadHeader,
+    ) -> Result<UploadSession, ComError>;
+    /// Upload the next chunk of image data, starting at `offset`.
+    ///
+    /// Returns the number of bytes received so far.
+    async fn upload_chunk(
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+        checksum: u32,
+    ) -> Result<u64, ComError>;
+    /// Finish the upload once all image data is received.
+    async fn commit_upload(id: u64) -> Result<(), ComError>;
+    async fn remove_cheatsheet(name: String) -> Result<(), ComError>;
+    async fn clear_screenshot() -> Result<(), ComError>;
+    async fn add_cheatsheet_tags(name: String, tags: HashSet<String>) -> Result<(), ComError>;
+    async fn remove_cheatsheet_tags(name: String, either: TagsEither) -> Result<(), ComError>;
+    /// Set the priority of the cheatsheet, see [CheatsheetTags::priority].
+    async fn set_cheatsheet_priority(name: String, priority: i32) -> Result<(), ComError>;
+    /// Set or remove the selector of the cheatsheet, see [CheatsheetTags::selector].
+    async fn set_cheatsheet_selector(
+        name: String,
+        selector: Option<String>,
+    ) -> Result<(), ComError>;
+    async fn add_wm_class_tags(wm_class: String, tags: HashSet<String>) -> Result<(), ComError>;
+    async fn remove_wm_class_tags(wm_class: String, either: TagsEither) -> Result<(), ComError>;
+    /// Requires [Feature::MatchRules].
+    async fn get_match_rules() -> Result<Vec<MatchRule>, ComError>;
+    /// Add the match rule or replace the rule with the same name.
+    ///
+    /// Requires [Feature::MatchRules].
+    async fn set_match_rule(rule: MatchRule) -> Result<(), ComError>;
+    /// Requires [Feature::MatchRules].
+    async fn remove_match_rule(name: String) -> Result<(), ComError>;
+}
+
+/// Errors returned by the client application when it was not able to handle a request.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[non_exhaustive]
+pub enum ComError {
+    /// No cheatsheet with the given name is stored on the device.
+    CheatsheetNotFound { name: String },
+    /// The cheatsheet does not have the given tag.
+    CheatsheetTagNotFound { name: String, tag: String },
+    /// No tags are associated with the given wm class.
+    WmClassNotFound { wm_class: String },
+    /// The wm class does not have the given tag.
+    WmClassTagNotFound { wm_class: String, tag: String },
+    /// The display of the client application is not initialized yet.
+    DisplayNotInitialized,
+    /// The client application is not able to handle requests, e.g. because it is shutting down.
+    Unavailable,
+    /// No upload session with the given id is in progress.
+    UploadSessionNotFound { id: u64 },
+    /// The chunk does not start where the received data ends.
+    UploadOffsetMismatch { expected: u64, offset: u64 },
+    /// The amount of received data does not match the announced length.
+    UploadLengthMismatch { expected: u64, received: u64 },
+    /// The checksum of the received data range does not match.
+    ChecksumMismatch { offset: u64, len: u64 },
+    /// The image data could not be processed.
+    InvalidImage,
+    /// The client application does not support the image format.
+    UnsupportedImageFormat { format: ImageFormat },
+    /// No match rule with the given name is stored on the device.
+    MatchRuleNotFound { name: String },
+    /// The glob or regex of a match rule is invalid.
+    InvalidPattern { pattern: String, reason: String },
+    /// The tag selector expression of a cheatsheet is invalid.
+    InvalidSelector { selector: String, reason: String },
+    /// The announced upload exceeds what the client application accepts.
+    UploadTooLarge { len: u64, max: u64 },
+    /// The host speaks a different [PROTOCOL_VERSION] than the client application.
+    UnsupportedProtocol { version: u32, supported: u32 },
+    /// The cheatsheet library of the client application could not be loaded,
+    /// it refuses changes so it does not overwrite the stored library.
+    LibraryUnavailable { reason: String },
+}
+
+impl Display for ComError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ComError::CheatsheetNotFound { name } => {
+                write!(f, "Cheatsheet with name '{name}' not found.")
+            }
+            ComError::CheatsheetTagNotFound { name, tag } => {
+                write!(f, "Cheatsheet '{name}' does not have tag '{tag}'.")
+            }
+            ComError::WmClassNotFound { wm_class } => {
+                write!(f, "Wm class '{wm_class}' not found.")
+            }
+            ComError::WmClassTagNotFound { wm_class, tag } => {
+                write!(f, "Wm class '{wm_class}' does not have tag '{tag}'.")
+            }
+            ComError::DisplayNotInitialized => {
+                write!(f, "Display of the client is not initialized.")
+            }
+            ComError::Unavailable => write!(f, "Client is not able to handle requests."),
+            ComError::UploadSessionNotFound { id } => {
+                write!(f, "Upload session with id '{id}' not found.")
+            }
+            ComError::UploadOffsetMismatch { expected, offset } => {
+                write!(
+                    f,
+                    "Upload chunk offset {offset} does not match expected offset {expected}."
+                )
+            }
+            ComError::UploadLengthMismatch { expected, received } => {
+                write!(
+                    f,
+                    "Received {received} bytes of upload, expected {expected} bytes."
+                )
+            }
+            ComError::ChecksumMismatch { offset, len } => {
+                write!(f, "Checksum mismatch of {len} bytes at offset {offset}.")
+            }
+            ComError::InvalidImage => write!(f, "Image data could not be processed."),
+            ComError::UnsupportedImageFormat { format } => {
+                write!(f, "Image format '{format:?}' is not supported.")
+            }
+            ComError::MatchRuleNotFound { name } => {
+                write!(f, "Match rule with name '{name}' not found.")
+            }
+            ComError::InvalidPattern { pattern, reason } => {
+                write!(f, "Pattern '{pattern}' is invalid: {reason}")
+            }
+            ComError::InvalidSelector { selector, reason } => {
+                write!(f, "Selector '{selector}' is invalid: {reason}")
+            }
+            ComError::UploadTooLarge { len, max } => {
+                write!(
+                    f,
+                    "Upload of {len} bytes exceeds the maximum of {max} bytes."
+                )
+            }
+            ComError::UnsupportedProtocol { version, supported } => {
+                write!(
+                    f,
+                    "Protocol version {version} is not supported, the client speaks version {supported}."
+                )
+            }
+            ComError::LibraryUnavailable { reason } => {
+                write!(
+                    f,
+                    "Cheatsheet library of the client could not be loaded, it is read-only: {reason}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ComError {}
+
+/// What the client application supports, returned by the handshake.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Capabilities {
+    /// See [PROTOCOL_VERSION].
+    pub protocol_version: u32,
+    pub image_formats: Vec<ImageFormat>,
+    pub features: Vec<Feature>,
+    pub build: BuildInfo,
+}
+
+impl Capabilities {
+    pub fn supports_format(&self, format: ImageFormat) -> bool {
+        self.image_formats.contains(&format)
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+/// Optional features of the client application.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[non_exhaustive]
+pub enum Feature {
+    /// Cheatsheets are split into pages at the uploaded page breaks.
+    PageBreaks,
+    /// The info reports content hashes of the stored cheatsheets, see [content_hash].
+    ContentHash,
+    /// Tags are activated by match rules on the focused window, see [MatchRule].
+    MatchRules,
+    /// A feature of a newer client application.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Build of the client application.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BuildInfo {
+    pub version: String,
+    /// The pocketbook SDK version it was built against.
+    pub sdk: Option<String>,
+    pub debug: bool,
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.version)?;
+        if let Some(sdk) = &self.sdk {
+            write!(f, " (SDK {sdk})")?;
+        }
+        if self.debug {
+            write!(f, " [debug]")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -49,6 +272,7 @@ impl Default for FocusedWindowInfo {
 pub struct Info {
     pub screen_width: u32,
     pub screen_height: u32,
+    pub screen_dpi: u32,
     pub screen_orientation: ScreenOrientation,
     pub cheatsheets: Vec<CheatsheetTags>,
     pub wm_classes: Vec<WmClassTags>,
@@ -58,6 +282,25 @@ pub struct Info {
 pub struct CheatsheetTags {
     pub name: String,
     pub tags: Vec<String>,
+    /// Cheatsheets with a higher priority are shown first.
+    ///
+    /// Ranks below the number of tags shared with the focused window
+    /// and the priority of the match rules activating them, see [MatchRule::priority].
+    pub priority: i32,
+    /// Boolean expression over the tags activated for the focused window,
+    /// e.g. `vim & !neovim` or `(git | gitk) & terminal`.
+    ///
+    /// When set, the cheatsheet is shown when the selector matches
+    /// instead of when it shares one of its tags with the window.
+    pub selector: Option<String>,
+    /// See [content_hash].
+    pub content_hash: String,
+    pub width: u32,
+    pub height: u32,
+    /// The format the image is stored in on the device.
+    pub format: ImageFormat,
+    /// When the cheatsheet image was last uploaded.
+    pub modified: Option<SystemTime>,
 }
 
 #[derive(Debug, Clone, Default, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
@@ -66,12 +309,112 @@ pub struct WmClassTags {
     pub tags: Vec<String>,
 }
 
+/// Activates tags for focused windows matching its patterns.
+///
+/// All patterns that are set have to match. The tags of all matching rules are activated,
+/// together with the tags of the wm class of the window.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MatchRule {
+    /// Identifies the rule, setting a rule with the same name replaces it.
+    pub name: String,
+    /// Matched against [FocusedWindowInfo::wm_class].
+    #[serde(default)]
+    pub wm_class: Option<Pattern>,
+    /// Matched against [FocusedWindowInfo::wm_class_instance].
+    #[serde(default)]
+    pub wm_class_instance: Option<Pattern>,
+    /// Matched against [FocusedWindowInfo::title].
+    #[serde(default)]
+    pub title: Option<Pattern>,
+    /// Of the cheatsheets sharing as many tags with the focused window,
+    /// the ones activated by rules with a higher priority are shown first.
+    ///
+    /// The tags of the wm class have priority 0.
+    #[serde(default)]
+    pub priority: i32,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Pattern {
+    /// Matches the entire string, `*` matches any sequence of characters and `?` a single one.
+    Glob(String),
+    /// Matches if the regular expression matches anywhere in the string.
+    Regex(String),
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Glob(glob) => write!(f, "glob '{glob}'"),
+            Pattern::Regex(regex) => write!(f, "regex '{regex}'"),
+        }
+    }
+}
+
 #[derive(
-    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
 )]
 #[non_exhaustive]
 pub enum ImageFormat {
+    #[default]
     Gray8,
+    /// Gray8 compressed with zlib.
+    Gray8Deflate,
+    /// Black and white, 8 pixels per byte. A set bit is white.
+    ///
+    /// Each row starts at a new byte, the most significant bits hold the leftmost pixel.
+    Gray1,
+    /// 16 gray levels, 2 pixels per byte.
+    ///
+    /// Each row starts at a new byte, the most significant bits hold the leftmost pixel.
+    Gray4,
+    /// A format of a newer protocol, received from a newer host or client application.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ImageFormat {
+    /// The format of the image data after decompressing it.
+    pub fn uncompressed(self) -> Self {
+        match self {
+            ImageFormat::Gray8Deflate => ImageFormat::Gray8,
+            format => format,
+        }
+    }
+
+    /// The format of the image data after compressing it.
+    pub fn compressed(self) -> Self {
+        match self {
+            ImageFormat::Gray8 => ImageFormat::Gray8Deflate,
+            format => format,
+        }
+    }
+
+    pub fn is_compressed(self) -> bool {
+        self != self.uncompressed()
+    }
+
+    /// The length of the image data with the size after decompressing it, `None` for unknown formats.
+    pub fn data_len(self, width: u32, height: u32) -> Option<u64> {
+        let width = u64::from(width);
+        let row_len = match self.uncompressed() {
+            ImageFormat::Gray8 => width,
+            ImageFormat::Gray4 => width.div_ceil(2),
+            ImageFormat::Gray1 => width.div_ceil(8),
+            _ => return None,
+        };
+        Some(row_len * u64::from(height))
+    }
 }
 
 #[derive(
@@ -116,6 +459,147 @@ impl Default for CheatsheetImage {
     }
 }
 
+impl CheatsheetImage {
+    /// Compresses the image data. Returns the image unchanged if it is already compressed.
+    ///
+    /// Formats without a compressed variant are returned unchanged as well.
+    pub fn compressed(self) -> anyhow::Result<Self> {
+        let format = self.format.compressed();
+        if format == self.format {
+            return Ok(self);
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&self.data)?;
+        Ok(Self {
+            format,
+            data: encoder.finish()?,
+            ..self
+        })
+    }
+
+    /// Decompresses the image data. Borrows the image if it is not compressed.
+    pub fn decompressed(&self) -> anyhow::Result<Cow<'_, Self>> {
+        if !self.format.is_compressed() {
+            return Ok(Cow::Borrowed(self));
+        }
+        let mut data = Vec::new();
+        ZlibDecoder::new(self.data.as_slice()).read_to_end(&mut data)?;
+        Ok(Cow::Owned(Self {
+            format: self.format.uncompressed(),
+            byte_order: self.byte_order,
+            width: self.width,
+            height: self.height,
+            data,
+        }))
+    }
+
+    /// Checks that the image data matches its size and format.
+    ///
+    /// Compressed data is decompressed up to the expected length only,
+    /// so data decompressing to more than announced can't exhaust the memory.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.width == 0 || self.height == 0 {
+            anyhow::bail!("Image is empty, size {}x{}", self.width, self.height);
+        }
+        let expected = self
+            .format
+            .data_len(self.width, self.height)
+            .ok_or_else(|| anyhow::anyhow!("Image format '{:?}' is unknown", self.format))?;
+        let len = if self.format.is_compressed() {
+            let mut decoder = ZlibDecoder::new(self.data.as_slice()).take(expected + 1);
+            std::io::copy(&mut decoder, &mut std::io::sink())?
+        } else {
+            self.data.len() as u64
+        };
+        if len != expected {
+            anyhow::bail!(
+                "Image data of a {}x{} '{:?}' image has {len} bytes, expected {expected} bytes",
+                self.width,
+                self.height,
+                self.format
+            );
+        }
+        Ok(())
+    }
+
+    /// The header announcing the image in a chunked upload.
+    pub fn upload_header(&self) -> UploadHeader {
+        UploadHeader {
+            format: self.format,
+            byte_order: self.byte_order,
+            width: self.width,
+            height: self.height,
+            len: self.data.len() as u64,
+            checksum: checksum(&self.data),
+        }
+    }
+}
+
+/// What an uploaded image is used for once the upload is committed.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum UploadTarget {
+    Cheatsheet {
+        name: String,
+        tags: HashSet<String>,
+        /// Rows at which the image is split into pages, in ascending order.
+        ///
+        /// When empty, images taller than the screen are paged by the screen height.
+        page_breaks: Vec<u32>,
+    },
+    Screenshot {
+        name: Option<String>,
+    },
+}
+
+/// Describes the image of a chunked upload.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct UploadHeader {
+    pub format: ImageFormat,
+    pub byte_order: ByteOrder,
+    pub width: u32,
+    pub height: u32,
+    /// Length of the image data in bytes.
+    pub len: u64,
+    /// Checksum of the entire image data.
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct UploadSession {
+    pub id: u64,
+    /// Offset to continue uploading from, non-zero when a previous upload gets resumed.
+    pub offset: u64,
+}
+
+/// The checksum used to verify uploaded data.
+pub fn checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// Hash identifying the displayed content of a cheatsheet, as hex encoded SHA-256.
+///
+/// Covers the uncompressed image and the rows it is split into pages at,
+/// so the hash does not depend on whether or how the image is compressed.
+pub fn content_hash(image: &CheatsheetImage, page_breaks: &[u32]) -> anyhow::Result<String> {
+    use sha2::Digest;
+
+    let image = image.decompressed()?;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(format!("{:?}:{:?}", image.format, image.byte_order).as_bytes());
+    hasher.update(image.width.to_le_bytes());
+    hasher.update(image.height.to_le_bytes());
+    hasher.update((page_breaks.len() as u32).to_le_bytes());
+    for row in page_breaks {
+        hasher.update(row.to_le_bytes());
+    }
+    hasher.update(&image.data);
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
 #[derive(
     Debug, Clone, Copy, PartialEq, PartialOrd, Default, serde:: Serialize, serde::Deserialize,
 )]
@@ -0,0 +1,167 @@
+//! TLS for the RPC transport.
+//!
+//! The client application generates a self-signed certificate once.
+//! Hosts don't verify it against certificate authorities. Instead its SHA-256 fingerprint
+//! gets pinned when pairing and is checked on every following connection, see [crate::auth].
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// The name the certificate is issued for, hosts connect with it regardless of the address.
+const SERVER_NAME: &str = "pb-cheatsheet";
+
+/// SHA-256 hash of a DER encoded certificate.
+pub type Fingerprint = [u8; 32];
+
+pub fn fingerprint(cert_der: &[u8]) -> Fingerprint {
+    Sha256::digest(cert_der).into()
+}
+
+/// Self-signed certificate and private key of the device.
+#[derive(Debug)]
+pub struct Identity {
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl Identity {
+    pub fn generate() -> anyhow::Result<Self> {
+        let key_pair = rcgen::KeyPair::generate()?;
+        let cert =
+            rcgen::CertificateParams::new(vec![SERVER_NAME.to_string()])?.self_signed(&key_pair)?;
+        Ok(Self {
+            cert: cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(key_pair.serialize_der()),
+        })
+    }
+
+    /// Restores a persisted identity from the DER encoded certificate and PKCS#8 private key.
+    pub fn from_der(cert_der: Vec<u8>, key_der: Vec<u8>) -> Self {
+        Self {
+            cert: CertificateDer::from(cert_der),
+            key: PrivatePkcs8KeyDer::from(key_der),
+        }
+    }
+
+    pub fn cert_der(&self) -> &[u8] {
+        &self.cert
+    }
+
+    pub fn key_der(&self) -> &[u8] {
+        self.key.secret_pkcs8_der()
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        fingerprint(&self.cert)
+    }
+}
+
+/// Accepts TLS connections with the identity of the device.
+#[derive(Clone)]
+pub struct Acceptor {
+    pub(crate) acceptor: TlsAcceptor,
+    pub(crate) fingerprint: Fingerprint,
+}
+
+impl Acceptor {
+    pub fn new(identity: &Identity) -> anyhow::Result<Self> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![identity.cert.clone()],
+                PrivateKeyDer::Pkcs8(identity.key.clone_key()),
+            )?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            fingerprint: identity.fingerprint(),
+        })
+    }
+}
+
+impl std::fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Acceptor")
+            .field("fingerprint", &crate::auth::hex(&self.fingerprint))
+            .finish_non_exhaustive()
+    }
+}
+
+pub(crate) fn connector() -> anyhow::Result<TlsConnector> {
+    let provider = provider();
+    let config = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier { provider }))
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+pub(crate) fn server_name() -> ServerName<'static> {
+    ServerName::try_from(SERVER_NAME).expect("Valid DNS name")
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/// Accepts any certificate the server proves to own the key of.
+///
+/// Whether it is the pinned certificate is checked after the handshake,
+/// once the device is known.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,328 @@
+//! The RPC transport between host and client.
+//!
+//! The host secures the connection with TLS, see [crate::tls]. Plain TCP is still accepted
+//! for debugging, the client tells both apart by the first byte of a TLS handshake.
+//! The host then sends a single preface byte announcing the [WireFormat]
+//! it encodes its messages with. The client reads it and answers in the same format.
+//! Finally the host has to authenticate, see [crate::auth].
+
+use crate::auth::{self, Accepted, Credentials, DeviceId, PairingStore};
+use crate::tls::{self, Fingerprint};
+use bytes::{Bytes, BytesMut};
+use core::fmt::Display;
+use core::net::SocketAddr;
+use core::str::FromStr;
+use core::time::Duration;
+use std::io;
+use std::marker::PhantomData;
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll};
+use tarpc::serde_transport::Transport;
+use tarpc::tokio_serde::formats::{Bincode, Json};
+use tarpc::tokio_serde::{Deserializer, Serializer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsStream;
+use tokio_util::codec::LengthDelimitedCodec;
+use tracing::debug;
+
+/// Timeout for receiving the wire format preface of a new connection.
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Timeout for the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Content type of the TLS record a handshake starts with.
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+/// The format RPC messages are encoded with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum WireFormat {
+    /// Compact binary encoding.
+    #[default]
+    Bincode,
+    /// Human-readable encoding, intended for debugging.
+    Json,
+}
+
+impl WireFormat {
+    fn preface(self) -> u8 {
+        match self {
+            WireFormat::Bincode => b'b',
+            WireFormat::Json => b'j',
+        }
+    }
+
+    fn from_preface(preface: u8) -> Option<Self> {
+        match preface {
+            b'b' => Some(WireFormat::Bincode),
+            b'j' => Some(WireFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl Display for WireFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WireFormat::Bincode => write!(f, "bincode"),
+            WireFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(WireFormat::Bincode),
+            "json" => Ok(WireFormat::Json),
+            _ => Err(anyhow::anyhow!(
+                "Unknown wire format '{s}', expected 'bincode' or 'json'"
+            )),
+        }
+    }
+}
+
+/// Codec that encodes messages in the [WireFormat] selected at runtime.
+#[derive(Debug)]
+pub struct WireCodec<Item, SinkItem> {
+    format: WireFormat,
+    ghost: PhantomData<fn(SinkItem) -> Item>,
+}
+
+impl<Item, SinkItem> WireCodec<Item, SinkItem> {
+    pub fn new(format: WireFormat) -> Self {
+        Self {
+            format,
+            ghost: PhantomData,
+        }
+    }
+}
+
+impl<Item, SinkItem> Serializer<SinkItem> for WireCodec<Item, SinkItem>
+where
+    SinkItem: serde::Serialize,
+{
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> Result<Bytes, Self::Error> {
+        match self.format {
+            WireFormat::Bincode => pin!(Bincode::<Item, SinkItem>::default()).serialize(item),
+            WireFormat::Json => pin!(Json::<Item, SinkItem>::default())
+                .serialize(item)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+impl<Item, SinkItem> Deserializer<Item> for WireCodec<Item, SinkItem>
+where
+    for<'a> Item: serde::Deserialize<'a>,
+{
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<Item, Self::Error> {
+        match self.format {
+            WireFormat::Bincode => pin!(Bincode::<Item, SinkItem>::default()).deserialize(src),
+            WireFormat::Json => pin!(Json::<Item, SinkItem>::default())
+                .deserialize(src)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// Connection between host and client, either secured with TLS or plain TCP.
+#[derive(Debug)]
+pub enum RpcStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl RpcStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, RpcStream::Tls(_))
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            RpcStream::Plain(stream) => stream,
+            RpcStream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+}
+
+impl AsyncRead for RpcStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RpcStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            RpcStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for RpcStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            RpcStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            RpcStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RpcStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            RpcStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RpcStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            RpcStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+pub type WireTransport<Item, SinkItem> =
+    Transport<RpcStream, Item, SinkItem, WireCodec<Item, SinkItem>>;
+
+/// Connect to the RPC server, announce the wire format and authenticate.
+///
+/// Without `tls` messages are sent unencrypted, intended for debugging.
+/// `credentials` looks up the credentials the host is paired with the device.
+pub async fn connect<Item, SinkItem>(
+    addr: SocketAddr,
+    format: WireFormat,
+    tls: bool,
+    credentials: impl FnOnce(&DeviceId) -> Option<Credentials>,
+) -> anyhow::Result<WireTransport<Item, SinkItem>>
+where
+    for<'a> Item: serde::Deserialize<'a>,
+    SinkItem: serde::Serialize,
+{
+    let (mut stream, certificate) = open(addr, tls).await?;
+    stream.write_u8(format.preface()).await?;
+    auth::authenticate(&mut stream, certificate.as_ref(), credentials).await?;
+    Ok(new_transport(stream, format))
+}
+
+/// Connect to the RPC server and pair with the device.
+///
+/// `read_code` returns the one-time code displayed on the device, entered by the user.
+pub async fn pair<F>(
+    addr: SocketAddr,
+    tls: bool,
+    read_code: impl FnOnce() -> F,
+) -> anyhow::Result<(DeviceId, Credentials)>
+where
+    F: core::future::Future<Output = anyhow::Result<String>>,
+{
+    let (mut stream, certificate) = open(addr, tls).await?;
+    stream.write_u8(WireFormat::default().preface()).await?;
+    auth::pair(&mut stream, certificate.as_ref(), read_code).await
+}
+
+/// Opens the connection, returns the fingerprint of the server certificate with `tls`.
+async fn open(addr: SocketAddr, tls: bool) -> anyhow::Result<(RpcStream, Option<Fingerprint>)> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    if !tls {
+        return Ok((RpcStream::Plain(stream), None));
+    }
+    let stream = tokio::time::timeout(
+        TLS_HANDSHAKE_TIMEOUT,
+        tls::connector()?.connect(tls::server_name(), stream),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+    let certificate = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| tls::fingerprint(cert))
+        .ok_or_else(|| anyhow::anyhow!("Server did not present a certificate"))?;
+    Ok((RpcStream::Tls(Box::new(stream.into())), Some(certificate)))
+}
+
+/// Set up the transport of an accepted connection in the wire format announced by the peer,
+/// after it authenticated.
+///
+/// The connection is secured with TLS when the peer starts a TLS handshake.
+/// Returns `None` when the peer paired, the connection then ends.
+pub async fn accept<Item, SinkItem>(
+    stream: TcpStream,
+    store: &impl PairingStore,
+    acceptor: &tls::Acceptor,
+) -> anyhow::Result<Option<WireTransport<Item, SinkItem>>>
+where
+    for<'a> Item: serde::Deserialize<'a>,
+    SinkItem: serde::Serialize,
+{
+    stream.set_nodelay(true)?;
+    let mut first = [0; 1];
+    let n = tokio::time::timeout(PREFACE_TIMEOUT, stream.peek(&mut first))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Receiving preface timed out"))??;
+    if n == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let (mut stream, certificate) = if first[0] == TLS_HANDSHAKE_RECORD {
+        let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.acceptor.accept(stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        (
+            RpcStream::Tls(Box::new(stream.into())),
+            Some(acceptor.fingerprint),
+        )
+    } else {
+        (RpcStream::Plain(stream), None)
+    };
+    let preface = tokio::time::timeout(PREFACE_TIMEOUT, stream.read_u8())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Receiving preface timed out"))??;
+    let format = WireFormat::from_preface(preface).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Received invalid wire format preface '{preface:#x}'"),
+        )
+    })?;
+    match auth::accept(&mut stream, store, certificate.as_ref()).await? {
+        Accepted::Authenticated(key_id) => {
+            debug!(
+                "Accepted {} connection with wire format '{format}', authenticated with key '{}'",
+                if stream.is_tls() { "TLS" } else { "plain TCP" },
+                auth::hex(&key_id)
+            );
+            Ok(Some(new_transport(stream, format)))
+        }
+        Accepted::Paired(key_id) => {
+            debug!("Paired with new key '{}'", auth::hex(&key_id));
+            Ok(None)
+        }
+    }
+}
+
+fn new_transport<Item, SinkItem>(
+    stream: RpcStream,
+    format: WireFormat,
+) -> WireTransport<Item, SinkItem>
+where
+    for<'a> Item: serde::Deserialize<'a>,
+    SinkItem: serde::Serialize,
+{
+    let framed = LengthDelimitedCodec::builder().new_framed(stream);
+    tarpc::serde_transport::new(framed, WireCodec::new(format))
+}
@@ -0,0 +1,124 @@
+//! Credentials the host is paired with devices.
+//!
+//! Stored as JSON, mapping the device id to the key id and shared secret:
+//!
+//! ```json
+//! {
+//!   "5f0e..": { "key_id": "9ab1..", "secret": "c3d4.." }
+//! }
+//! ```
+
+use anyhow::Context;
+use pb_cheatsheet_com::auth::{self, Credentials, DeviceId};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub(crate) struct CredentialStore {
+    devices: BTreeMap<String, Credentials>,
+}
+
+impl CredentialStore {
+    /// `$XDG_CONFIG_HOME/pb-cheatsheet/credentials.json`, falling back to `~/.config`.
+    pub(crate) fn default_path() -> PathBuf {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .unwrap_or_default();
+        config_dir.join("pb-cheatsheet").join("credentials.json")
+    }
+
+    /// Loads the store, which is empty when the file does not exist yet.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Read credentials '{}'", path.display()))
+            }
+        };
+        let devices = serde_json::from_str(&contents)
+            .with_context(|| format!("Parse credentials '{}'", path.display()))?;
+        Ok(Self { devices })
+    }
+
+    pub(crate) fn get(&self, device_id: &DeviceId) -> Option<Credentials> {
+        self.devices.get(&auth::hex(device_id)).cloned()
+    }
+
+    pub(crate) fn insert(&mut self, device_id: &DeviceId, credentials: Credentials) {
+        self.devices.insert(auth::hex(device_id), credentials);
+    }
+
+    /// Saves the store, only readable by the current user.
+    ///
+    /// Written to a temporary file next to it first, which is then renamed over it,
+    /// so a crash while saving never loses the stored credentials.
+    pub(crate) fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let dir = path
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("Credentials '{}' have no parent", path.display()))?;
+        std::fs::create_dir_all(dir)?;
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = dir.join(tmp_name);
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&tmp_path)
+            .with_context(|| format!("Open credentials '{}'", tmp_path.display()))?;
+        // The mode only applies to newly created files
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(serde_json::to_string_pretty(&self.devices)?.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Replace credentials '{}'", path.display()))?;
+        // Persist the rename itself
+        std::fs::File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_replaces_credentials() {
+        let dir =
+            std::env::temp_dir().join(format!("pb-cheatsheet-credentials-{}", std::process::id()));
+        let path = dir.join("credentials.json");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, "{}").unwrap();
+        #[cfg(unix)]
+        std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o644))
+            .unwrap();
+
+        let credentials = Credentials {
+            key_id: [1; 16],
+            secret: [2; 32],
+            certificate: None,
+        };
+        let mut store = CredentialStore::default();
+        store.insert(&[3; 16], credentials.clone());
+        store.save(&path).unwrap();
+
+        let loaded = CredentialStore::load(&path).unwrap();
+        assert_eq!(loaded.get(&[3; 16]), Some(credentials));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!dir.join("credentials.json.tmp").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
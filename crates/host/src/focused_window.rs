@@ -0,0 +1,161 @@
+//! Providers of the focused window info for different desktop environments and window managers.
+
+pub(crate) mod gnome;
+pub(crate) mod hyprland;
+pub(crate) mod kwin;
+pub(crate) mod sway;
+pub(crate) mod x11;
+
+use anyhow::anyhow;
+use core::fmt::Display;
+use pb_cheatsheet_com::FocusedWindowInfo;
+use std::future::Future;
+use tokio::sync::mpsc;
+
+/// Retrieves info about the currently focused window.
+pub(crate) trait FocusedWindowProvider: Send {
+    fn focused_window(&mut self) -> impl Future<Output = anyhow::Result<FocusedWindowInfo>> + Send;
+
+    /// Waits until the focused window or its title possibly changed.
+    ///
+    /// Returns an error when the backend is not able to notify about changes,
+    /// the focused window then has to be polled.
+    fn changed(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Err(anyhow!("Backend does not notify about focus changes")) }
+    }
+}
+
+/// The desktop environment or window manager the focused window info is retrieved from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub(crate) enum Backend {
+    /// Detect the backend from the environment variables of the session.
+    #[default]
+    Auto,
+    /// GNOME Shell with the `focused-window-dbus` extension, which has to be polled.
+    Gnome,
+    /// KDE Plasma, through a KWin script reporting over D-Bus.
+    Kwin,
+    /// Sway or i3, through their IPC socket.
+    #[value(alias = "i3")]
+    Sway,
+    /// Hyprland, through its IPC socket.
+    Hyprland,
+    /// Any EWMH compliant X11 window manager, through the `_NET_ACTIVE_WINDOW` root window property.
+    X11,
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Auto => write!(f, "auto"),
+            Backend::Gnome => write!(f, "gnome"),
+            Backend::Kwin => write!(f, "kwin"),
+            Backend::Sway => write!(f, "sway"),
+            Backend::Hyprland => write!(f, "hyprland"),
+            Backend::X11 => write!(f, "x11"),
+        }
+    }
+}
+
+impl Backend {
+    /// Detects the backend from the environment variables of the session.
+    ///
+    /// Window manager specific IPC sockets are preferred, X11 is used as fallback.
+    pub(crate) fn detect() -> anyhow::Result<Self> {
+        let is_set = |var: &str| std::env::var_os(var).is_some_and(|v| !v.is_empty());
+        let desktop = std::env::var("XDG_CURRENT_DESKTOP")
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        if is_set("HYPRLAND_INSTANCE_SIGNATURE") {
+            Ok(Backend::Hyprland)
+        } else if is_set("SWAYSOCK") || is_set("I3SOCK") {
+            Ok(Backend::Sway)
+        } else if desktop.split(':').any(|d| d == "gnome") {
+            Ok(Backend::Gnome)
+        } else if desktop.split(':').any(|d| d == "kde") {
+            Ok(Backend::Kwin)
+        } else if is_set("DISPLAY") {
+            Ok(Backend::X11)
+        } else {
+            Err(anyhow!(
+                "Unable to detect the focused window backend, select one explicitly"
+            ))
+        }
+    }
+
+    /// Connects to the provider of the backend, detecting it first with [Backend::Auto].
+    pub(crate) async fn connect(self) -> anyhow::Result<AnyProvider> {
+        let backend = match self {
+            Backend::Auto => Self::detect()?,
+            backend => backend,
+        };
+        println!("Using focused window backend '{backend}'");
+        let provider = match backend {
+            Backend::Auto => unreachable!("Backend was detected"),
+            Backend::Gnome => AnyProvider::Gnome(gnome::GnomeShell::connect().await?),
+            Backend::Kwin => AnyProvider::Kwin(kwin::KWin::connect().await?),
+            Backend::Sway => AnyProvider::Sway(sway::SwayIpc::connect()?),
+            Backend::Hyprland => AnyProvider::Hyprland(hyprland::Hyprland::connect()?),
+            Backend::X11 => AnyProvider::X11(x11::X11Ewmh::connect().await?),
+        };
+        Ok(provider)
+    }
+}
+
+/// The provider of one of the backends.
+pub(crate) enum AnyProvider {
+    Gnome(gnome::GnomeShell),
+    Kwin(kwin::KWin),
+    Sway(sway::SwayIpc),
+    Hyprland(hyprland::Hyprland),
+    X11(x11::X11Ewmh),
+}
+
+impl FocusedWindowProvider for AnyProvider {
+    async fn focused_window(&mut self) -> anyhow::Result<FocusedWindowInfo> {
+        match self {
+            AnyProvider::Gnome(provider) => provider.focused_window().await,
+            AnyProvider::Kwin(provider) => provider.focused_window().await,
+            AnyProvider::Sway(provider) => provider.focused_window().await,
+            AnyProvider::Hyprland(provider) => provider.focused_window().await,
+            AnyProvider::X11(provider) => provider.focused_window().await,
+        }
+    }
+
+    async fn changed(&mut self) -> anyhow::Result<()> {
+        match self {
+            AnyProvider::Gnome(provider) => provider.changed().await,
+            AnyProvider::Kwin(provider) => provider.changed().await,
+            AnyProvider::Sway(provider) => provider.changed().await,
+            AnyProvider::Hyprland(provider) => provider.changed().await,
+            AnyProvider::X11(provider) => provider.changed().await,
+        }
+    }
+}
+
+/// Receives notifications about possible changes of the focused window
+/// from a task or thread that listens to the events of a backend.
+///
+/// Waiting for a change is cancel safe, unlike reading the events directly.
+pub(crate) struct ChangeEvents {
+    rx: mpsc::UnboundedReceiver<()>,
+}
+
+impl ChangeEvents {
+    /// The sender is dropped by the listener when it stops.
+    pub(crate) fn channel() -> (mpsc::UnboundedSender<()>, Self) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (tx, Self { rx })
+    }
+
+    /// Waits for the next change, skipping the changes that are already queued.
+    pub(crate) async fn changed(&mut self) -> anyhow::Result<()> {
+        self.rx
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("Listening to focus change events stopped"))?;
+        while self.rx.try_recv().is_ok() {}
+        Ok(())
+    }
+}
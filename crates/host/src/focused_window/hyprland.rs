@@ -0,0 +1,131 @@
+use super::{ChangeEvents, FocusedWindowProvider};
+use anyhow::{anyhow, Context};
+use pb_cheatsheet_com::FocusedWindowInfo;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tracing::warn;
+
+/// Events of the event socket that indicate a change of the focused window.
+const FOCUS_EVENTS: [&str; 4] = [
+    "activewindow>>",
+    "activewindowv2>>",
+    "windowtitle>>",
+    "windowtitlev2>>",
+];
+
+/// Retrieves the focused window from the IPC socket of Hyprland.
+///
+/// Changes are noticed by listening to the event socket.
+pub(crate) struct Hyprland {
+    socket_path: PathBuf,
+    event_socket_path: PathBuf,
+    /// Listened to on first use.
+    events: Option<ChangeEvents>,
+}
+
+impl Hyprland {
+    pub(crate) fn connect() -> anyhow::Result<Self> {
+        let socket_dir = Self::socket_dir()?;
+        Ok(Self {
+            socket_path: socket_dir.join(".socket.sock"),
+            event_socket_path: socket_dir.join(".socket2.sock"),
+            events: None,
+        })
+    }
+
+    /// The directory containing the sockets of the running Hyprland instance.
+    ///
+    /// Hyprland versions before v0.40 place them in `/tmp/hypr`.
+    fn socket_dir() -> anyhow::Result<PathBuf> {
+        let signature = std::env::var("HYPRLAND_INSTANCE_SIGNATURE")
+            .context("'HYPRLAND_INSTANCE_SIGNATURE' is not set")?;
+        let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(|dir| PathBuf::from(dir).join("hypr").join(&signature))
+            .filter(|dir| dir.exists());
+        let dir = runtime_dir.unwrap_or_else(|| PathBuf::from("/tmp/hypr").join(&signature));
+        if !dir.exists() {
+            return Err(anyhow!(
+                "Hyprland socket directory '{}' does not exist",
+                dir.display()
+            ));
+        }
+        Ok(dir)
+    }
+
+    /// Sends a command and returns its reply.
+    async fn request(&self, command: &str) -> anyhow::Result<Vec<u8>> {
+        let mut stream = UnixStream::connect(&self.socket_path)
+            .await
+            .with_context(|| format!("Connecting to '{}'", self.socket_path.display()))?;
+        stream.write_all(command.as_bytes()).await?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        Ok(reply)
+    }
+
+    /// Listens to the event socket for events that indicate a change of the focused window.
+    async fn listen(&self) -> anyhow::Result<ChangeEvents> {
+        let stream = UnixStream::connect(&self.event_socket_path)
+            .await
+            .with_context(|| format!("Connecting to '{}'", self.event_socket_path.display()))?;
+
+        let (tx, events) = ChangeEvents::channel();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stream).lines();
+            loop {
+                let event = match lines.next_line().await {
+                    Ok(Some(event)) => event,
+                    Ok(None) => {
+                        warn!("Hyprland event socket closed");
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Reading Hyprland event failed, Err: {e:?}");
+                        break;
+                    }
+                };
+                if !FOCUS_EVENTS.iter().any(|prefix| event.starts_with(prefix)) {
+                    continue;
+                }
+                if tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(events)
+    }
+}
+
+impl FocusedWindowProvider for Hyprland {
+    async fn focused_window(&mut self) -> anyhow::Result<FocusedWindowInfo> {
+        let reply = self.request("j/activewindow").await?;
+        let window: serde_json::Value = serde_json::from_slice(&reply)?;
+        // Hyprland replies with an empty object when no window is focused
+        if window.get("address").is_none() {
+            return Ok(FocusedWindowInfo::default());
+        }
+
+        Ok(FocusedWindowInfo {
+            title: window["title"].as_str().unwrap_or_default().to_string(),
+            wm_class: window["class"].as_str().unwrap_or_default().to_string(),
+            wm_class_instance: window["initialClass"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            pid: window["pid"].as_u64().unwrap_or(u64::MAX),
+            focus: true,
+        })
+    }
+
+    async fn changed(&mut self) -> anyhow::Result<()> {
+        let events = match &mut self.events {
+            Some(events) => events,
+            None => {
+                let events = self.listen().await?;
+                self.events.insert(events)
+            }
+        };
+        events.changed().await
+    }
+}
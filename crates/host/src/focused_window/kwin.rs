@@ -0,0 +1,158 @@
+use super::FocusedWindowProvider;
+use anyhow::{anyhow, Context};
+use pb_cheatsheet_com::FocusedWindowInfo;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::debug;
+use zbus::{interface, proxy, Connection};
+
+const PLUGIN_NAME: &str = "pb-cheatsheet-focused-window";
+const OBJECT_PATH: &str = "/org/pbcheatsheet/FocusedWindow";
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reports the active window and changes of its caption to the D-Bus object of the host.
+///
+/// `%SERVICE%` gets replaced with the unique bus name of the host connection.
+/// Supports KWin 6 (`windowActivated`) as well as KWin 5 (`clientActivated`).
+const SCRIPT: &str = r#"
+var active = null;
+
+function report() {
+    if (!active) {
+        return;
+    }
+    callDBus("%SERVICE%", "/org/pbcheatsheet/FocusedWindow", "org.pbcheatsheet.FocusedWindow", "Update",
+        String(active.caption), String(active.resourceClass), String(active.resourceName), String(active.pid));
+}
+
+function activated(window) {
+    if (active) {
+        active.captionChanged.disconnect(report);
+    }
+    active = window;
+    if (active) {
+        active.captionChanged.connect(report);
+    }
+    report();
+}
+
+if (workspace.windowActivated !== undefined) {
+    workspace.windowActivated.connect(activated);
+    activated(workspace.activeWindow);
+} else {
+    workspace.clientActivated.connect(activated);
+    activated(workspace.activeClient);
+}
+"#;
+
+#[proxy(
+    default_service = "org.kde.KWin",
+    default_path = "/Scripting",
+    interface = "org.kde.kwin.Scripting"
+)]
+trait Scripting {
+    #[zbus(name = "loadScript")]
+    async fn load_script(&self, file_path: &str, plugin_name: &str) -> zbus::Result<i32>;
+    #[zbus(name = "unloadScript")]
+    async fn unload_script(&self, plugin_name: &str) -> zbus::Result<bool>;
+    #[zbus(name = "start")]
+    async fn start(&self) -> zbus::Result<()>;
+}
+
+/// Receives the active window reported by the KWin script.
+struct FocusedWindowReceiver {
+    tx: watch::Sender<Option<FocusedWindowInfo>>,
+}
+
+#[interface(name = "org.pbcheatsheet.FocusedWindow")]
+impl FocusedWindowReceiver {
+    async fn update(
+        &self,
+        title: String,
+        wm_class: String,
+        wm_class_instance: String,
+        pid: String,
+    ) {
+        let info = FocusedWindowInfo {
+            title,
+            wm_class,
+            wm_class_instance,
+            pid: pid.parse().unwrap_or(u64::MAX),
+            focus: true,
+        };
+        debug!("KWin reported active window: {info:?}");
+        self.tx.send_replace(Some(info));
+    }
+}
+
+/// Retrieves the focused window from KDE Plasma's KWin.
+///
+/// KWin does not expose the active window over D-Bus,
+/// so a script gets loaded into KWin which reports it back to the host.
+pub(crate) struct KWin {
+    // Keeps the D-Bus object serving the reports alive
+    _connection: Connection,
+    rx: watch::Receiver<Option<FocusedWindowInfo>>,
+}
+
+impl KWin {
+    pub(crate) async fn connect() -> anyhow::Result<Self> {
+        let connection = Connection::session().await?;
+        let (tx, mut rx) = watch::channel(None);
+        connection
+            .object_server()
+            .at(OBJECT_PATH, FocusedWindowReceiver { tx })
+            .await?;
+        let service = connection
+            .unique_name()
+            .ok_or_else(|| anyhow!("D-Bus connection does not have a unique name"))?
+            .to_string();
+
+        // Only writable by the user, unlike the shared temporary directory,
+        // so no one else can swap in a script KWin runs in the session
+        let script_dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| PathBuf::from(dir).join("pb-cheatsheet"))
+            .ok_or_else(|| anyhow!("'XDG_RUNTIME_DIR' is not set"))?;
+        std::fs::create_dir_all(&script_dir).context("Creating KWin script directory")?;
+        let script_path = script_dir.join(format!("{PLUGIN_NAME}.js"));
+        std::fs::write(&script_path, SCRIPT.replace("%SERVICE%", &service))
+            .context("Writing KWin script")?;
+        let scripting = ScriptingProxy::new(&connection).await?;
+        // A script of a previous run reports to a connection that no longer exists
+        scripting.unload_script(PLUGIN_NAME).await?;
+        let script_path = script_path
+            .to_str()
+            .ok_or_else(|| anyhow!("KWin script path is not valid UTF-8"))?;
+        scripting
+            .load_script(script_path, PLUGIN_NAME)
+            .await
+            .context("Loading KWin script")?;
+        scripting.start().await.context("Starting KWin script")?;
+
+        tokio::time::timeout(REPORT_TIMEOUT, rx.wait_for(Option::is_some))
+            .await
+            .context("Waiting for KWin script to report the active window")??;
+        Ok(Self {
+            _connection: connection,
+            rx,
+        })
+    }
+}
+
+impl FocusedWindowProvider for KWin {
+    async fn focused_window(&mut self) -> anyhow::Result<FocusedWindowInfo> {
+        self.rx
+            .borrow_and_update()
+            .clone()
+            .ok_or_else(|| anyhow!("KWin script did not report the active window yet"))
+    }
+
+    async fn changed(&mut self) -> anyhow::Result<()> {
+        self.rx
+            .changed()
+            .await
+            .map_err(|_| anyhow!("KWin report receiver was dropped"))
+    }
+}
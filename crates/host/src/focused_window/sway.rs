@@ -0,0 +1,164 @@
+use super::{ChangeEvents, FocusedWindowProvider};
+use anyhow::{anyhow, Context};
+use pb_cheatsheet_com::FocusedWindowInfo;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tracing::warn;
+
+const IPC_MAGIC: &[u8; 6] = b"i3-ipc";
+const IPC_SUBSCRIBE: u32 = 2;
+const IPC_GET_TREE: u32 = 4;
+
+/// Retrieves the focused window from the IPC socket of Sway or i3.
+///
+/// Changes are noticed by subscribing to window and workspace events.
+pub(crate) struct SwayIpc {
+    socket_path: PathBuf,
+    /// Subscribed to on first use.
+    events: Option<ChangeEvents>,
+}
+
+impl SwayIpc {
+    pub(crate) fn connect() -> anyhow::Result<Self> {
+        let socket_path = std::env::var_os("SWAYSOCK")
+            .or_else(|| std::env::var_os("I3SOCK"))
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| anyhow!("Neither 'SWAYSOCK' nor 'I3SOCK' is set"))?;
+        Ok(Self {
+            socket_path: socket_path.into(),
+            events: None,
+        })
+    }
+
+    async fn connect_stream(&self) -> anyhow::Result<UnixStream> {
+        UnixStream::connect(&self.socket_path)
+            .await
+            .with_context(|| format!("Connecting to '{}'", self.socket_path.display()))
+    }
+
+    /// Sends a message of the i3 IPC protocol and returns the JSON reply.
+    async fn request(
+        &self,
+        message_type: u32,
+        payload: &[u8],
+    ) -> anyhow::Result<serde_json::Value> {
+        let mut stream = self.connect_stream().await?;
+        write_message(&mut stream, message_type, payload).await?;
+        let (_, reply) = read_message(&mut stream).await?;
+        Ok(serde_json::from_slice(&reply)?)
+    }
+
+    /// Subscribes to the events that indicate a change of the focused window.
+    async fn subscribe(&self) -> anyhow::Result<ChangeEvents> {
+        let mut stream = self.connect_stream().await?;
+        write_message(&mut stream, IPC_SUBSCRIBE, br#"["window", "workspace"]"#).await?;
+        let (_, reply) = read_message(&mut stream).await?;
+        let reply: serde_json::Value = serde_json::from_slice(&reply)?;
+        if reply["success"].as_bool() != Some(true) {
+            return Err(anyhow!("Subscribing to IPC events failed, reply: {reply}"));
+        }
+
+        let (tx, events) = ChangeEvents::channel();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = read_message(&mut stream).await {
+                    warn!("Reading IPC event failed, Err: {e:?}");
+                    break;
+                }
+                if tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(events)
+    }
+}
+
+/// Writes a message with the header of the i3 IPC protocol.
+async fn write_message(
+    stream: &mut UnixStream,
+    message_type: u32,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let mut message = IPC_MAGIC.to_vec();
+    message.extend((payload.len() as u32).to_ne_bytes());
+    message.extend(message_type.to_ne_bytes());
+    message.extend(payload);
+    stream.write_all(&message).await?;
+    Ok(())
+}
+
+/// Reads a reply or event message of the i3 IPC protocol, returning its type and payload.
+async fn read_message(stream: &mut UnixStream) -> anyhow::Result<(u32, Vec<u8>)> {
+    let mut header = [0; IPC_MAGIC.len() + 8];
+    stream.read_exact(&mut header).await?;
+    if &header[..IPC_MAGIC.len()] != IPC_MAGIC {
+        return Err(anyhow!("Invalid IPC message header"));
+    }
+    let len = u32::from_ne_bytes(header[IPC_MAGIC.len()..][..4].try_into()?);
+    let message_type = u32::from_ne_bytes(header[IPC_MAGIC.len() + 4..].try_into()?);
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok((message_type, payload))
+}
+
+impl FocusedWindowProvider for SwayIpc {
+    async fn focused_window(&mut self) -> anyhow::Result<FocusedWindowInfo> {
+        let tree = self.request(IPC_GET_TREE, &[]).await?;
+        let Some(node) = find_focused(&tree) else {
+            // For example when an empty workspace is focused
+            return Ok(FocusedWindowInfo::default());
+        };
+
+        let title = node["name"].as_str().unwrap_or_default().to_string();
+        // Native wayland windows have an app id, X11 windows have window properties
+        let (wm_class, wm_class_instance) = match node["app_id"].as_str() {
+            Some(app_id) => (app_id.to_string(), app_id.to_string()),
+            None => {
+                let properties = &node["window_properties"];
+                (
+                    properties["class"].as_str().unwrap_or_default().to_string(),
+                    properties["instance"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                )
+            }
+        };
+        // i3 does not report the pid
+        let pid = node["pid"].as_u64().unwrap_or(u64::MAX);
+
+        Ok(FocusedWindowInfo {
+            title,
+            wm_class,
+            wm_class_instance,
+            pid,
+            focus: true,
+        })
+    }
+
+    async fn changed(&mut self) -> anyhow::Result<()> {
+        let events = match &mut self.events {
+            Some(events) => events,
+            None => {
+                let events = self.subscribe().await?;
+                self.events.insert(events)
+            }
+        };
+        events.changed().await
+    }
+}
+
+/// Finds the focused window in the tree of nodes.
+fn find_focused(node: &serde_json::Value) -> Option<&serde_json::Value> {
+    let is_window = node["type"] == "con" || node["type"] == "floating_con";
+    if node["focused"].as_bool() == Some(true) && is_window {
+        return Some(node);
+    }
+    ["nodes", "floating_nodes"]
+        .into_iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(find_focused)
+}
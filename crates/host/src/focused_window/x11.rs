@@ -0,0 +1,197 @@
+use super::{ChangeEvents, FocusedWindowProvider};
+use pb_cheatsheet_com::FocusedWindowInfo;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::warn;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window,
+};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+/// Retrieves the focused window from the `_NET_ACTIVE_WINDOW` property of the root window,
+/// which is maintained by EWMH compliant X11 window managers.
+///
+/// Changes are noticed through property change events of the root and the active window.
+pub(crate) struct X11Ewmh {
+    inner: Arc<Inner>,
+    /// Listened to on first use.
+    events: Option<ChangeEvents>,
+}
+
+struct Inner {
+    connection: RustConnection,
+    root: Window,
+    atoms: Atoms,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Atoms {
+    net_active_window: Atom,
+    net_wm_name: Atom,
+    net_wm_pid: Atom,
+    utf8_string: Atom,
+}
+
+impl X11Ewmh {
+    pub(crate) async fn connect() -> anyhow::Result<Self> {
+        let inner = tokio::task::spawn_blocking(|| -> anyhow::Result<Inner> {
+            let (connection, screen) = x11rb::connect(None)?;
+            let root = connection.setup().roots[screen].root;
+            let intern = |name: &[u8]| -> anyhow::Result<Atom> {
+                Ok(connection.intern_atom(false, name)?.reply()?.atom)
+            };
+            let atoms = Atoms {
+                net_active_window: intern(b"_NET_ACTIVE_WINDOW")?,
+                net_wm_name: intern(b"_NET_WM_NAME")?,
+                net_wm_pid: intern(b"_NET_WM_PID")?,
+                utf8_string: intern(b"UTF8_STRING")?,
+            };
+            Ok(Inner {
+                connection,
+                root,
+                atoms,
+            })
+        })
+        .await??;
+        Ok(Self {
+            inner: Arc::new(inner),
+            events: None,
+        })
+    }
+}
+
+impl FocusedWindowProvider for X11Ewmh {
+    async fn focused_window(&mut self) -> anyhow::Result<FocusedWindowInfo> {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || inner.focused_window()).await?
+    }
+
+    async fn changed(&mut self) -> anyhow::Result<()> {
+        let events = match &mut self.events {
+            Some(events) => events,
+            None => {
+                let (tx, events) = ChangeEvents::channel();
+                let inner = self.inner.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = inner.listen(tx) {
+                        warn!("Listening to X11 events failed, Err: {e:?}");
+                    }
+                });
+                self.events.insert(events)
+            }
+        };
+        events.changed().await
+    }
+}
+
+impl Inner {
+    fn active_window(&self) -> anyhow::Result<Window> {
+        Ok(self
+            .property(self.root, self.atoms.net_active_window, AtomEnum::WINDOW)?
+            .value32()
+            .and_then(|mut values| values.next())
+            .unwrap_or(x11rb::NONE))
+    }
+
+    fn focused_window(&self) -> anyhow::Result<FocusedWindowInfo> {
+        let window = self.active_window()?;
+        if window == x11rb::NONE {
+            return Ok(FocusedWindowInfo::default());
+        }
+
+        let mut title = self
+            .property(window, self.atoms.net_wm_name, self.atoms.utf8_string)?
+            .value;
+        if title.is_empty() {
+            title = self
+                .property(window, AtomEnum::WM_NAME.into(), AtomEnum::ANY)?
+                .value;
+        }
+        // Contains the null terminated instance and class name
+        let wm_class = self
+            .property(window, AtomEnum::WM_CLASS.into(), AtomEnum::STRING)?
+            .value;
+        let mut wm_class = wm_class
+            .split(|byte| *byte == 0)
+            .map(|name| String::from_utf8_lossy(name).into_owned());
+        let wm_class_instance = wm_class.next().unwrap_or_default();
+        let wm_class = wm_class.next().unwrap_or_default();
+        let pid = self
+            .property(window, self.atoms.net_wm_pid, AtomEnum::CARDINAL)?
+            .value32()
+            .and_then(|mut values| values.next())
+            .map(u64::from)
+            .unwrap_or(u64::MAX);
+
+        Ok(FocusedWindowInfo {
+            title: String::from_utf8_lossy(&title).into_owned(),
+            wm_class,
+            wm_class_instance,
+            pid,
+            focus: true,
+        })
+    }
+
+    /// Sends a change for property changes of the active window and its title,
+    /// blocking until the connection fails or the receiver is dropped.
+    fn listen(&self, tx: mpsc::UnboundedSender<()>) -> anyhow::Result<()> {
+        self.select_property_events(self.root, true)?;
+        let mut active = self.active_window()?;
+        self.select_property_events(active, true)?;
+        self.connection.flush()?;
+
+        loop {
+            let Event::PropertyNotify(event) = self.connection.wait_for_event()? else {
+                // Also skips errors of windows that were destroyed before selecting their events
+                continue;
+            };
+            if event.window == self.root && event.atom == self.atoms.net_active_window {
+                let window = self.active_window()?;
+                if window != active {
+                    self.select_property_events(active, false)?;
+                    self.select_property_events(window, true)?;
+                    self.connection.flush()?;
+                    active = window;
+                }
+            } else if event.window != active
+                || (event.atom != self.atoms.net_wm_name
+                    && event.atom != Atom::from(AtomEnum::WM_NAME))
+            {
+                continue;
+            }
+            if tx.send(()).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn select_property_events(&self, window: Window, select: bool) -> anyhow::Result<()> {
+        if window == x11rb::NONE {
+            return Ok(());
+        }
+        let event_mask = if select {
+            EventMask::PROPERTY_CHANGE
+        } else {
+            EventMask::NO_EVENT
+        };
+        self.connection.change_window_attributes(
+            window,
+            &ChangeWindowAttributesAux::new().event_mask(event_mask),
+        )?;
+        Ok(())
+    }
+
+    fn property(
+        &self,
+        window: Window,
+        property: Atom,
+        property_type: impl Into<Atom>,
+    ) -> anyhow::Result<x11rb::protocol::xproto::GetPropertyReply> {
+        Ok(self
+            .connection
+            .get_property(false, window, property, property_type, 0, u32::MAX / 4)?
+            .reply()?)
+    }
+}
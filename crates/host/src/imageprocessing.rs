@@ -1,26 +1,212 @@
-use pb_cheatsheet_com::CheatsheetImage;
+use crate::render::{self, Document};
+use pb_cheatsheet_com::{Capabilities, CheatsheetImage, ImageFormat, Info};
 use std::path::PathBuf;
+use tracing::warn;
 
 /// In clockwise direction
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-#[allow(unused)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, serde::Deserialize,
+)]
+#[serde(try_from = "u32")]
 #[allow(clippy::enum_variant_names)]
 pub(crate) enum Rotate {
+    #[value(name = "0")]
     Rotate0Deg,
-
+    #[value(name = "90")]
     Rotate90Deg,
+    #[value(name = "180")]
     Rotate180Deg,
+    #[value(name = "270")]
     Rotate270Deg,
 }
 
+impl TryFrom<u32> for Rotate {
+    type Error = String;
+
+    fn try_from(degrees: u32) -> Result<Self, Self::Error> {
+        match degrees {
+            0 => Ok(Rotate::Rotate0Deg),
+            90 => Ok(Rotate::Rotate90Deg),
+            180 => Ok(Rotate::Rotate180Deg),
+            270 => Ok(Rotate::Rotate270Deg),
+            _ => Err(format!(
+                "invalid rotation {degrees}, expected one of 0, 90, 180 or 270"
+            )),
+        }
+    }
+}
+
+/// The number of gray levels of the prepared image.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, clap::ValueEnum, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum GrayDepth {
+    /// Black and white.
+    Gray1,
+    /// 16 gray levels.
+    Gray4,
+    /// 256 gray levels.
+    #[default]
+    Gray8,
+}
+
+impl GrayDepth {
+    fn bits(self) -> u32 {
+        match self {
+            GrayDepth::Gray1 => 1,
+            GrayDepth::Gray4 => 4,
+            GrayDepth::Gray8 => 8,
+        }
+    }
+
+    fn levels(self) -> u32 {
+        1 << self.bits()
+    }
+
+    pub(crate) fn format(self) -> ImageFormat {
+        match self {
+            GrayDepth::Gray1 => ImageFormat::Gray1,
+            GrayDepth::Gray4 => ImageFormat::Gray4,
+            GrayDepth::Gray8 => ImageFormat::Gray8,
+        }
+    }
+}
+
+/// How the gray values are dithered when reducing the gray depth.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, clap::ValueEnum, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Dither {
+    /// Round to the nearest gray level.
+    None,
+    /// Diffuse the quantization error to neighboring pixels.
+    #[default]
+    FloydSteinberg,
+    /// Apply a 8x8 Bayer threshold matrix.
+    Ordered,
+}
+
+/// How the image is fitted to the device screen.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, clap::ValueEnum, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum FitMode {
+    /// Scale to fit inside the screen, padding the remaining area with white.
+    #[default]
+    Contain,
+    /// Scale to fill the screen, cropping what exceeds it.
+    Cover,
+    /// Keep the original size, cropping what exceeds the screen.
+    Original,
+    /// Scale to the screen width. Taller images are paged vertically on the device.
+    FitWidth,
+    /// Stretch to the screen size, ignoring the aspect ratio.
+    Stretch,
+}
+
+impl FitMode {
+    /// The scale a document page with the given size in points is rendered with,
+    /// so that it does not need to be upscaled when fitted to the screen.
+    ///
+    /// With [FitMode::Original] the page is rendered at its physical size on the screen.
+    /// Degenerate pages without a width or height are rendered unscaled, [fit] rejects them afterwards.
+    fn pixel_per_pt(self, page_width: f32, page_height: f32, screen_info: &Info) -> f32 {
+        let width_scale = screen_info.screen_width as f32 / page_width;
+        let height_scale = screen_info.screen_height as f32 / page_height;
+        let scale = match self {
+            FitMode::Contain => width_scale.min(height_scale),
+            FitMode::Cover | FitMode::Stretch => width_scale.max(height_scale),
+            FitMode::Original => screen_info.screen_dpi as f32 / 72.0,
+            FitMode::FitWidth => width_scale,
+        };
+        if scale.is_finite() && scale > 0.0 {
+            scale
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Options for preparing an image for the device screen.
+#[derive(Debug, Clone, Copy, Default, clap::Args, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct PrepareOptions {
+    /// Rotation in clockwise direction.{n}
+    /// Defaults to 0 for cheatsheets and 270 for screenshots.
+    #[arg(long, value_enum)]
+    pub(crate) rotate: Option<Rotate>,
+    /// How the image is fitted to the screen.
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) fit: FitMode,
+    /// Gray depth the image is reduced to.
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) depth: GrayDepth,
+    /// Dithering applied when reducing the gray depth.
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) dither: Dither,
+}
+
+impl PrepareOptions {
+    /// Falls back to 256 gray levels, supported by every client application,
+    /// when the client does not support the gray depth.
+    pub(crate) fn negotiate(mut self, capabilities: &Capabilities) -> Self {
+        if !capabilities.supports_format(self.depth.format()) {
+            warn!(
+                "Client application {} does not support gray depth '{:?}', falling back to '{:?}'",
+                capabilities.build,
+                self.depth,
+                GrayDepth::Gray8
+            );
+            self.depth = GrayDepth::Gray8;
+        }
+        self
+    }
+}
+
+/// An image prepared for the device screen.
+#[derive(Debug, Clone)]
+pub(crate) struct PreparedImage {
+    pub(crate) image: CheatsheetImage,
+    /// Rows at which the image is split into pages.
+    pub(crate) page_breaks: Vec<u32>,
+}
+
+impl PreparedImage {
+    /// See [pb_cheatsheet_com::content_hash].
+    pub(crate) fn content_hash(&self) -> anyhow::Result<String> {
+        pb_cheatsheet_com::content_hash(&self.image, &self.page_breaks)
+    }
+}
+
+/// Loads the image and prepares it for the screen of the client.
+///
+/// Typst, PDF and SVG documents are rendered first, raster images are decoded.
+/// When `split_pages` is set, images taller than the screen get page breaks,
+/// preferably at blank rows.
 pub(crate) async fn load_prepare_image(
     image: PathBuf,
-    width: u32,
-    height: u32,
+    screen_info: &Info,
     rotate: Rotate,
     invert: bool,
-) -> anyhow::Result<CheatsheetImage> {
-    let mut img = image::ImageReader::open(image)?.decode()?;
+    options: PrepareOptions,
+    split_pages: bool,
+) -> anyhow::Result<PreparedImage> {
+    let (width, height) = (screen_info.screen_width, screen_info.screen_height);
+    let mut img = match Document::from_path(&image) {
+        Some(document) => render::render(&image, document, |page_width, page_height| {
+            let (page_width, page_height) = match rotate {
+                Rotate::Rotate90Deg | Rotate::Rotate270Deg => (page_height, page_width),
+                Rotate::Rotate0Deg | Rotate::Rotate180Deg => (page_width, page_height),
+            };
+            options
+                .fit
+                .pixel_per_pt(page_width, page_height, screen_info)
+        })?,
+        None => image::ImageReader::open(image)?.decode()?,
+    };
     if invert {
         img.invert();
     }
@@ -30,15 +216,294 @@ pub(crate) async fn load_prepare_image(
         Rotate::Rotate180Deg => img.rotate180(),
         Rotate::Rotate270Deg => img.rotate270(),
     };
-    let data = img
-        .resize_exact(width, height, image::imageops::FilterType::Gaussian)
-        .into_luma8()
-        .into_raw();
-    Ok(CheatsheetImage {
-        format: pb_cheatsheet_com::ImageFormat::Gray8,
-        byte_order: pb_cheatsheet_com::ByteOrder::BigEndian,
-        width,
-        height,
-        data,
+    let img = fit(img, width, height, options.fit)?;
+    let page_breaks = if split_pages {
+        page_breaks(&img, height)
+    } else {
+        Vec::new()
+    };
+    let (width, height) = img.dimensions();
+    let data = quantize(img, options.depth, options.dither);
+    Ok(PreparedImage {
+        image: CheatsheetImage {
+            format: options.depth.format(),
+            byte_order: pb_cheatsheet_com::ByteOrder::BigEndian,
+            width,
+            height,
+            data,
+        },
+        page_breaks,
     })
 }
+
+/// Finds the rows at which the image is split into pages that are at most the page height tall.
+///
+/// Each page is cut at the last blank row in its lower half, so that lines of text are not cut in half.
+/// Pages without such a row are cut at the page height.
+fn page_breaks(img: &image::GrayImage, page_height: u32) -> Vec<u32> {
+    let mut breaks = Vec::new();
+    let mut start = 0;
+
+    while img.height() - start > page_height {
+        let end = start + page_height;
+        let min_end = start + (page_height / 2).max(1);
+        let cut = (min_end..=end)
+            .rev()
+            .find(|&row| is_blank_row(img, row))
+            .unwrap_or(end);
+        breaks.push(cut);
+        start = cut;
+    }
+    breaks
+}
+
+/// Whether all pixels of the row have nearly the same gray value.
+fn is_blank_row(img: &image::GrayImage, row: u32) -> bool {
+    const TOLERANCE: u8 = 16;
+
+    let (min, max) = (0..img.width())
+        .map(|x| img.get_pixel(x, row).0[0])
+        .fold((u8::MAX, u8::MIN), |(min, max), v| (min.min(v), max.max(v)));
+    max.saturating_sub(min) <= TOLERANCE
+}
+
+/// Fits the image to the screen with the given width and height.
+///
+/// Fails when the image or the screen is empty.
+fn fit(
+    img: image::DynamicImage,
+    width: u32,
+    height: u32,
+    fit: FitMode,
+) -> anyhow::Result<image::GrayImage> {
+    const FILTER: image::imageops::FilterType = image::imageops::FilterType::Gaussian;
+
+    if img.width() == 0 || img.height() == 0 {
+        anyhow::bail!("Image is empty, size {}x{}", img.width(), img.height());
+    }
+    if width == 0 || height == 0 {
+        anyhow::bail!("Screen of the client is empty, size {width}x{height}");
+    }
+    let img = match fit {
+        FitMode::Contain => {
+            let img = img.resize(width, height, FILTER).into_luma8();
+            let mut canvas = image::GrayImage::from_pixel(width, height, image::Luma([0xff]));
+            let x = (width - img.width()) / 2;
+            let y = (height - img.height()) / 2;
+            image::imageops::overlay(&mut canvas, &img, x as i64, y as i64);
+            canvas
+        }
+        FitMode::Cover => img.resize_to_fill(width, height, FILTER).into_luma8(),
+        FitMode::Original => {
+            let crop_width = img.width().min(width);
+            let crop_height = img.height().min(height);
+            let x = (img.width() - crop_width) / 2;
+            let y = (img.height() - crop_height) / 2;
+            img.crop_imm(x, y, crop_width, crop_height).into_luma8()
+        }
+        FitMode::FitWidth => {
+            let scaled_height =
+                (img.height() as u64 * width as u64 / img.width() as u64).max(1) as u32;
+            img.resize_exact(width, scaled_height, FILTER).into_luma8()
+        }
+        FitMode::Stretch => img.resize_exact(width, height, FILTER).into_luma8(),
+    };
+    Ok(img)
+}
+
+/// Reduces the gray values of the image to the gray depth and packs them into rows of bytes.
+fn quantize(img: image::GrayImage, depth: GrayDepth, dither: Dither) -> Vec<u8> {
+    if depth == GrayDepth::Gray8 {
+        return img.into_raw();
+    }
+    let (width, height) = img.dimensions();
+    let levels = match dither {
+        Dither::None => quantize_nearest(&img, depth),
+        Dither::FloydSteinberg => quantize_floyd_steinberg(&img, depth),
+        Dither::Ordered => quantize_ordered(&img, depth),
+    };
+    pack(&levels, width, height, depth.bits())
+}
+
+/// Maps a gray value to the nearest gray level.
+fn nearest_level(value: f32, depth: GrayDepth) -> u8 {
+    let max_level = (depth.levels() - 1) as f32;
+    (value * max_level / 255.0).round().clamp(0.0, max_level) as u8
+}
+
+/// Maps a gray level back to its gray value.
+fn level_value(level: u8, depth: GrayDepth) -> f32 {
+    level as f32 * 255.0 / (depth.levels() - 1) as f32
+}
+
+fn quantize_nearest(img: &image::GrayImage, depth: GrayDepth) -> Vec<u8> {
+    img.pixels()
+        .map(|p| nearest_level(p.0[0] as f32, depth))
+        .collect()
+}
+
+fn quantize_floyd_steinberg(img: &image::GrayImage, depth: GrayDepth) -> Vec<u8> {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let mut values = img.pixels().map(|p| p.0[0] as f32).collect::<Vec<f32>>();
+    let mut levels = vec![0; width * height];
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let level = nearest_level(values[i], depth);
+            let error = values[i] - level_value(level, depth);
+            levels[i] = level;
+
+            if x + 1 < width {
+                values[i + 1] += error * 7.0 / 16.0;
+            }
+            if y + 1 < height {
+                if x > 0 {
+                    values[i + width - 1] += error * 3.0 / 16.0;
+                }
+                values[i + width] += error * 5.0 / 16.0;
+                if x + 1 < width {
+                    values[i + width + 1] += error * 1.0 / 16.0;
+                }
+            }
+        }
+    }
+    levels
+}
+
+fn quantize_ordered(img: &image::GrayImage, depth: GrayDepth) -> Vec<u8> {
+    const BAYER_8X8: [[u8; 8]; 8] = [
+        [0, 32, 8, 40, 2, 34, 10, 42],
+        [48, 16, 56, 24, 50, 18, 58, 26],
+        [12, 44, 4, 36, 14, 46, 6, 38],
+        [60, 28, 52, 20, 62, 30, 54, 22],
+        [3, 35, 11, 43, 1, 33, 9, 41],
+        [51, 19, 59, 27, 49, 17, 57, 25],
+        [15, 47, 7, 39, 13, 45, 5, 37],
+        [63, 31, 55, 23, 61, 29, 53, 21],
+    ];
+    let step = 255.0 / (depth.levels() - 1) as f32;
+
+    img.enumerate_pixels()
+        .map(|(x, y, p)| {
+            let threshold = BAYER_8X8[y as usize % 8][x as usize % 8] as f32 / 64.0 - 0.5;
+            nearest_level(p.0[0] as f32 + threshold * step, depth)
+        })
+        .collect()
+}
+
+/// Packs gray levels into bytes, each row starting at a new byte.
+/// The most significant bits hold the leftmost pixel.
+fn pack(levels: &[u8], width: u32, height: u32, bits: u32) -> Vec<u8> {
+    if width == 0 {
+        return Vec::new();
+    }
+    let pixels_per_byte = (8 / bits) as usize;
+    let bytes_per_row = (width as usize).div_ceil(pixels_per_byte);
+    let mut data = vec![0; bytes_per_row * height as usize];
+
+    for (row, row_levels) in levels.chunks(width as usize).enumerate() {
+        for (x, level) in row_levels.iter().enumerate() {
+            let shift = 8 - bits as usize * (x % pixels_per_byte + 1);
+            data[row * bytes_per_row + x / pixels_per_byte] |= level << shift;
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rows alternate between black and white pixels, so they are not blank.
+    fn text_image(width: u32, height: u32, blank_rows: &[u32]) -> image::GrayImage {
+        image::GrayImage::from_fn(width, height, |x, y| {
+            if blank_rows.contains(&y) || x % 2 == 0 {
+                image::Luma([0xff])
+            } else {
+                image::Luma([0x00])
+            }
+        })
+    }
+
+    #[test]
+    fn page_breaks_at_blank_rows() {
+        let img = text_image(10, 250, &[20, 80, 170]);
+        // Blank rows in the upper half of a page are not used
+        assert_eq!(page_breaks(&img, 100), vec![80, 170]);
+    }
+
+    #[test]
+    fn page_breaks_at_page_height_without_blank_rows() {
+        let img = text_image(10, 250, &[]);
+        assert_eq!(page_breaks(&img, 100), vec![100, 200]);
+    }
+
+    #[test]
+    fn no_page_breaks_when_fitting() {
+        let img = text_image(10, 100, &[]);
+        assert!(page_breaks(&img, 100).is_empty());
+    }
+
+    #[test]
+    fn pack_rows_start_at_new_byte() {
+        let levels = [1; 10 * 2];
+        assert_eq!(pack(&levels, 10, 2, 1), vec![0xff, 0xc0, 0xff, 0xc0]);
+    }
+
+    #[test]
+    fn pack_leftmost_pixel_in_most_significant_bits() {
+        assert_eq!(pack(&[0x1, 0x2, 0x3], 3, 1, 4), vec![0x12, 0x30]);
+    }
+
+    #[test]
+    fn pack_empty() {
+        assert!(pack(&[], 0, 4, 1).is_empty());
+    }
+
+    #[test]
+    fn quantize_nearest() {
+        let img = image::GrayImage::from_raw(4, 1, vec![0, 100, 200, 255]).unwrap();
+        assert_eq!(
+            quantize(img.clone(), GrayDepth::Gray1, Dither::None),
+            vec![0b0011_0000]
+        );
+        assert_eq!(
+            quantize(img.clone(), GrayDepth::Gray4, Dither::None),
+            vec![0x06, 0xcf]
+        );
+        assert_eq!(
+            quantize(img, GrayDepth::Gray8, Dither::None),
+            vec![0, 100, 200, 255]
+        );
+    }
+
+    #[test]
+    fn quantize_dithered_keeps_mean_gray() {
+        let img = image::GrayImage::from_pixel(16, 16, image::Luma([0x80]));
+        for dither in [Dither::FloydSteinberg, Dither::Ordered] {
+            let data = quantize(img.clone(), GrayDepth::Gray1, dither);
+            let white = data.iter().map(|byte| byte.count_ones()).sum::<u32>();
+            assert!(
+                (120..=136).contains(&white),
+                "{dither:?}: {white} white pixels"
+            );
+        }
+    }
+
+    #[test]
+    fn fit_rejects_empty_sizes() {
+        let empty = image::DynamicImage::new_luma8(0, 10);
+        assert!(fit(empty, 100, 100, FitMode::FitWidth).is_err());
+        let img = image::DynamicImage::new_luma8(10, 10);
+        assert!(fit(img.clone(), 0, 100, FitMode::FitWidth).is_err());
+        assert!(fit(img, 100, 0, FitMode::Contain).is_err());
+    }
+
+    #[test]
+    fn fit_width_keeps_aspect_ratio() {
+        let img = image::DynamicImage::new_luma8(10, 40);
+        let fitted = fit(img, 100, 200, FitMode::FitWidth).unwrap();
+        assert_eq!(fitted.dimensions(), (100, 400));
+    }
+}
@@ -1,19 +1,30 @@
-pub(crate) mod dbus;
+pub(crate) mod credentials;
+pub(crate) mod focused_window;
 pub(crate) mod imageprocessing;
+pub(crate) mod render;
+pub(crate) mod rpc;
+pub(crate) mod sync;
 
 use anyhow::{anyhow, Context};
 use clap::Parser;
 use core::net::SocketAddr;
-use imageprocessing::Rotate;
-use pb_cheatsheet_com::{FocusedWindowInfo, TagsEither, WorldClient};
-use std::collections::HashSet;
+use credentials::CredentialStore;
+use focused_window::FocusedWindowProvider;
+use imageprocessing::{FitMode, PrepareOptions, PreparedImage, Rotate};
+use pb_cheatsheet_com::transport::WireFormat;
+use pb_cheatsheet_com::{
+    Capabilities, CheatsheetImage, ComError, Feature, FocusedWindowInfo, Info, MatchRule, Pattern,
+    TagsEither, UploadHeader, UploadTarget, WorldClient,
+};
+use rpc::{Endpoint, ReconnectingClient, ServerAddr};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::time::{Duration, Instant};
+use tarpc::client::RpcError;
+use tarpc::context;
 use tarpc::context::Context as TarpcContext;
-use tarpc::tokio_serde::formats::Json;
-use tarpc::{client, context};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 /// pb-cheatsheet-host
 ///
@@ -21,9 +32,25 @@ use tracing::{debug, error};
 /// to display cheatsheet's (images) depending on the current focused window.
 #[derive(Debug, clap::Parser)]
 struct Cli {
-    /// The RPC server address of the client application.
+    /// The RPC server address of the client application.{n}
+    /// Discovered in the local network through mDNS when omitted.
     #[arg(short = 'a', long, env = "PB_CHEATSHEET_RPC_ADDR")]
-    rpc_addr: String,
+    rpc_addr: Option<SocketAddr>,
+    /// Name or id of the device to discover, required when there are several in the network.{n}
+    /// Listed by the 'discover' command.
+    #[arg(long, env = "PB_CHEATSHEET_DEVICE", conflicts_with = "rpc_addr")]
+    device: Option<String>,
+    /// The format RPC messages are encoded with. 'json' is intended for debugging.
+    #[arg(long, env = "PB_CHEATSHEET_WIRE_FORMAT", default_value_t = WireFormat::default())]
+    wire_format: WireFormat,
+    /// Connect without TLS, RPC messages including window titles and screenshots{n}
+    /// are sent unencrypted. Intended for debugging.
+    #[arg(long, env = "PB_CHEATSHEET_PLAIN")]
+    plain: bool,
+    /// The file the credentials of paired devices are stored in.{n}
+    /// Defaults to '$XDG_CONFIG_HOME/pb-cheatsheet/credentials.json'.
+    #[arg(long, env = "PB_CHEATSHEET_CREDENTIALS")]
+    credentials: Option<PathBuf>,
     #[command(subcommand)]
     cmd: Command,
 }
@@ -32,12 +59,34 @@ struct Cli {
 enum Command {
     /// Continuously report focused window info to the client.{n}
     /// Intended to be run as a service.
-    ReportFocusedWindow,
+    ReportFocusedWindow {
+        /// Where the focused window info is retrieved from.
+        #[arg(long, value_enum, env = "PB_CHEATSHEET_FOCUS_BACKEND", default_value_t)]
+        backend: focused_window::Backend,
+        /// Milliseconds the focused window has to stay the same before it is reported.{n}
+        /// Avoids refreshing the e-ink screen on quick window switches.
+        #[arg(long, value_name = "MS", default_value_t = 300)]
+        debounce: u64,
+        /// Milliseconds between polling the focused window,{n}
+        /// when the backend does not notify about focus changes.
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        poll_interval: u64,
+    },
+    /// Pair with the client application, required once before connecting to it.{n}
+    /// Prompts for the one-time code displayed on the device.
+    Pair,
+    /// List the devices running the client application in the local network.
+    Discover {
+        /// Seconds to browse for devices.
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
     /// Get information stored on device.
     GetInfo,
     /// Upload a new chaetsheet that gets displayed when the added tags match the tags{n}
     /// that are added to the wm class of the reported window.{n}
-    /// The image size is adjusted depending on the reported screen info of the client.
+    /// The image size is adjusted depending on the reported screen info of the client.{n}
+    /// Typst (`.typ`), PDF and SVG documents are rendered at the screen size of the client.
     UploadCheatsheet {
         /// The cheatsheet name.
         #[arg(short, long)]
@@ -45,6 +94,15 @@ enum Command {
         /// Associated tags.
         #[arg(short, long)]
         tags: Vec<String>,
+        #[command(flatten)]
+        options: PrepareOptions,
+        /// Split tall images into multiple pages, preferably at blank rows.{n}
+        /// Implies `--fit fit-width`.
+        #[arg(long)]
+        split_pages: bool,
+        /// Upload the image even if the device already has an identical cheatsheet.
+        #[arg(long)]
+        force: bool,
         /// Path to the image
         image: PathBuf,
     },
@@ -61,6 +119,8 @@ enum Command {
         /// Whether the image colors should be inverted.
         #[arg(short, long)]
         invert: bool,
+        #[command(flatten)]
+        options: PrepareOptions,
     },
     /// Clear the screenshot.
     ClearScreenshot,
@@ -84,6 +144,29 @@ enum Command {
         #[arg(short, long)]
         all: bool,
     },
+    /// Set the priority of a cheatsheet to reorder it.{n}
+    /// Cheatsheets sharing more tags with the focused window are still shown first,{n}
+    /// then by the priority of the match rules activating them, then by this priority.
+    SetCheatsheetPriority {
+        /// The cheatsheet name.
+        #[arg(short, long)]
+        name: String,
+        /// Cheatsheets with a higher priority are shown first.
+        #[arg(short, long, allow_negative_numbers = true)]
+        priority: i32,
+    },
+    /// Set a selector expression over the tags of the focused window, e.g. `vim & !neovim`.{n}
+    /// The cheatsheet is then only shown when the selector matches,{n}
+    /// instead of whenever it shares a tag with the window.
+    SetCheatsheetSelector {
+        /// The cheatsheet name.
+        #[arg(short, long)]
+        name: String,
+        /// The selector, combining tags with `&`, `|`, `!` and parentheses.{n}
+        /// The selector is removed when omitted.
+        #[arg(short, long)]
+        selector: Option<String>,
+    },
     /// Add wm class tags.
     AddWmClassTags {
         /// The wm class.
@@ -104,8 +187,62 @@ enum Command {
         #[arg(short, long)]
         all: bool,
     },
+    /// Add a rule that activates tags for focused windows matching its patterns,{n}
+    /// or replace the rule with the same name.{n}
+    /// All given patterns have to match. Globs match the entire string, regexes anywhere in it.
+    SetMatchRule {
+        /// The rule name.
+        #[arg(short, long)]
+        name: String,
+        /// Pattern for the wm class.
+        #[arg(short, long)]
+        wm_class: Option<String>,
+        /// Pattern for the wm class instance.
+        #[arg(short, long)]
+        instance: Option<String>,
+        /// Pattern for the window title.
+        #[arg(long)]
+        title: Option<String>,
+        /// The patterns are regular expressions instead of globs.
+        #[arg(short, long)]
+        regex: bool,
+        /// Of the cheatsheets sharing as many tags with the window,{n}
+        /// the ones activated by rules with a higher priority are shown first.{n}
+        /// Wm class tags have priority 0.
+        #[arg(short, long, default_value_t = 0, allow_negative_numbers = true)]
+        priority: i32,
+        /// Activated tags.
+        #[arg(short, long)]
+        tags: Vec<String>,
+    },
+    /// Remove a match rule.
+    RemoveMatchRule {
+        /// The rule name.
+        name: String,
+    },
+    /// Synchronize the cheatsheets and wm class tags on the device with a manifest.{n}
+    /// Cheatsheets missing or changed on the device are uploaded, tags are added and removed{n}
+    /// and cheatsheets and wm classes that are not in the manifest are removed.
+    Sync {
+        /// Path to the TOML (`.toml`) or YAML (`.yaml`, `.yml`) manifest.
+        manifest: PathBuf,
+        /// Only print the planned changes without applying them.
+        #[arg(long)]
+        dry_run: bool,
+        /// Upload all cheatsheets in the manifest, also the ones that are unchanged on the device.
+        #[arg(long)]
+        reupload: bool,
+    },
 }
 
+/// Size of the chunks images are uploaded in.
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+/// How often an interrupted upload or sync is resumed before giving up.
+const UPLOAD_MAX_RETRIES: u32 = 5;
+/// Interval the focused window info is reported again even if it did not change.
+/// Notices lost connections and restores the state of a restarted client application.
+const FOCUSED_WINDOW_KEEPALIVE: Duration = Duration::from_secs(30);
+
 pub fn long_rpc_context() -> TarpcContext {
     let mut context = context::current();
     context.deadline = Instant::now() + Duration::from_secs(60);
@@ -117,12 +254,16 @@ async fn main() -> anyhow::Result<()> {
     setup_tracing()?;
     let cli = Cli::parse();
     let quit_token = tokio_util::sync::CancellationToken::new();
-    let dbus_connection = zbus::Connection::session().await?;
-    let server_addr: SocketAddr = cli.rpc_addr.parse()?;
-    let mut transport = tarpc::serde_transport::tcp::connect(server_addr, Json::default);
-    println!("Connecting to RPC server with address: '{server_addr:?}'");
-    transport.config_mut().max_frame_length(usize::MAX);
-    let rpc_client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    let server_addr = match cli.rpc_addr {
+        Some(addr) => ServerAddr::Fixed(addr),
+        None => ServerAddr::Discover {
+            device: cli.device.clone(),
+        },
+    };
+    let credentials = cli
+        .credentials
+        .clone()
+        .unwrap_or_else(CredentialStore::default_path);
 
     // Ctrl-C quit task
     let quit_token_c = quit_token.clone();
@@ -134,28 +275,92 @@ async fn main() -> anyhow::Result<()> {
         quit_token_c.cancel();
     });
 
+    // The device might not be reachable yet when running as a service, so it is connected lazily
+    if let Command::ReportFocusedWindow {
+        backend,
+        debounce,
+        poll_interval,
+    } = cli.cmd
+    {
+        let provider = backend.connect().await?;
+        run_report_focused_window(
+            provider,
+            ReconnectingClient::new(server_addr, cli.wire_format, !cli.plain, credentials),
+            quit_token.clone(),
+            Duration::from_millis(poll_interval),
+            Duration::from_millis(debounce),
+        )
+        .await;
+        return Ok(());
+    }
+    if let Command::Discover { timeout } = cli.cmd {
+        run_discover(Duration::from_secs(timeout)).await?;
+        return Ok(());
+    }
+
+    let endpoint = Endpoint {
+        addr: server_addr.resolve().await?,
+        wire_format: cli.wire_format,
+        tls: !cli.plain,
+        credentials,
+    };
+    if let Command::Pair = cli.cmd {
+        run_pair(&endpoint).await?;
+        return Ok(());
+    }
+    println!(
+        "Connecting to RPC server with address: '{:?}'",
+        endpoint.addr
+    );
+    let (rpc_client, capabilities) = rpc::connect(&endpoint).await?;
+
     match cli.cmd {
-        Command::ReportFocusedWindow => {
-            run_report_focused_window(dbus_connection, rpc_client, quit_token.clone()).await;
+        Command::ReportFocusedWindow { .. } | Command::Discover { .. } | Command::Pair => {
+            unreachable!("Handled before connecting")
         }
         Command::GetInfo => {
-            run_get_info(rpc_client, quit_token).await?;
+            run_get_info(rpc_client, &capabilities, quit_token).await?;
         }
-        Command::UploadCheatsheet { image, name, tags } => {
-            upload_cheatsheet_image(
+        Command::UploadCheatsheet {
+            image,
+            name,
+            tags,
+            options,
+            split_pages,
+            force,
+        } => {
+            run_upload_cheatsheet(
                 rpc_client,
+                &endpoint,
+                &capabilities,
                 quit_token.clone(),
                 image,
                 name,
                 tags.into_iter().collect(),
+                options,
+                split_pages,
+                force,
             )
             .await?;
         }
         Command::RemoveCheatsheet { name } => {
             run_remove_cheatsheet(rpc_client, quit_token, name).await?;
         }
-        Command::Screenshot { name, invert } => {
-            run_upload_screenshot(rpc_client, quit_token.clone(), name, invert).await?;
+        Command::Screenshot {
+            name,
+            invert,
+            options,
+        } => {
+            run_upload_screenshot(
+                rpc_client,
+                &endpoint,
+                &capabilities,
+                quit_token.clone(),
+                name,
+                invert,
+                options,
+            )
+            .await?;
         }
         Command::ClearScreenshot => {
             run_clear_screenshot(rpc_client, quit_token).await?;
@@ -172,6 +377,12 @@ async fn main() -> anyhow::Result<()> {
             };
             run_remove_cheatsheet_tags(rpc_client, quit_token, name, either).await?;
         }
+        Command::SetCheatsheetPriority { name, priority } => {
+            run_set_cheatsheet_priority(rpc_client, quit_token, name, priority).await?;
+        }
+        Command::SetCheatsheetSelector { name, selector } => {
+            run_set_cheatsheet_selector(rpc_client, quit_token, name, selector).await?;
+        }
         Command::AddWmClassTags { wm_class, tags } => {
             run_add_wm_class_tags(rpc_client, quit_token, wm_class, tags.into_iter().collect())
                 .await?;
@@ -188,6 +399,51 @@ async fn main() -> anyhow::Result<()> {
             };
             run_remove_wm_class_tags(rpc_client, quit_token, wm_class, either).await?;
         }
+        Command::SetMatchRule {
+            name,
+            wm_class,
+            instance,
+            title,
+            regex,
+            priority,
+            tags,
+        } => {
+            let pattern = |pattern: String| {
+                if regex {
+                    Pattern::Regex(pattern)
+                } else {
+                    Pattern::Glob(pattern)
+                }
+            };
+            let rule = MatchRule {
+                name,
+                wm_class: wm_class.map(pattern),
+                wm_class_instance: instance.map(pattern),
+                title: title.map(pattern),
+                priority,
+                tags,
+            };
+            run_set_match_rule(rpc_client, &capabilities, quit_token, rule).await?;
+        }
+        Command::RemoveMatchRule { name } => {
+            run_remove_match_rule(rpc_client, &capabilities, quit_token, name).await?;
+        }
+        Command::Sync {
+            manifest,
+            dry_run,
+            reupload,
+        } => {
+            run_sync(
+                rpc_client,
+                &endpoint,
+                &capabilities,
+                quit_token,
+                manifest,
+                dry_run,
+                reupload,
+            )
+            .await?;
+        }
     }
 
     Ok(())
@@ -209,18 +465,19 @@ fn setup_tracing() -> Result<(), tracing::dispatcher::SetGlobalDefaultError> {
 
 #[tracing::instrument(skip_all)]
 async fn run_report_focused_window(
-    dbus_connection: zbus::Connection,
-    rpc_client: WorldClient,
+    mut provider: impl FocusedWindowProvider + 'static,
+    mut rpc_client: ReconnectingClient,
     quit_token: CancellationToken,
+    poll_interval: Duration,
+    debounce: Duration,
 ) {
     let (focused_window_tx, mut focus_window_rx) =
         tokio::sync::watch::channel::<FocusedWindowInfo>(FocusedWindowInfo::default());
 
-    // focused window D-Bus poll task
+    // focused window watch task
     let quit_token_c = quit_token.clone();
     tokio::task::spawn(async move {
-        let mut poll_interval = tokio::time::interval(Duration::from_millis(1000));
-        let mut last_info = match dbus::get_focused_window_info(&dbus_connection).await {
+        let mut last_info = match provider.focused_window().await {
             Ok(i) => i,
             Err(e) => {
                 error!("Get initial focused window info failed, aborting application. Err: {e:?}");
@@ -228,29 +485,54 @@ async fn run_report_focused_window(
                 return;
             }
         };
+        // Report the initial focused window right away
+        focused_window_tx.send_replace(last_info.clone());
+        let mut polling = false;
+        // A change is only reported once the focused window stayed the same for the debounce duration
+        let mut pending_info: Option<FocusedWindowInfo> = None;
+        let debounce_sleep = tokio::time::sleep(debounce);
+        tokio::pin!(debounce_sleep);
 
         loop {
             tokio::select! {
-                _ = poll_interval.tick() => {},
+                res = provider.changed(), if !polling => {
+                    if let Err(e) = res {
+                        println!("Falling back to polling the focused window every {}ms: {e:#}", poll_interval.as_millis());
+                        polling = true;
+                    }
+                },
+                _ = tokio::time::sleep(poll_interval), if polling => {},
+                _ = &mut debounce_sleep, if pending_info.is_some() => {
+                    let Some(info) = pending_info.take() else {
+                        continue;
+                    };
+                    println!("Reporting focused window change:\n{info:#?}");
+                    debug!("Sending focused window change..");
+                    if focused_window_tx.send(info.clone()).is_err() {
+                        error!("Send changed focused window info to RPC client task, receiving side closed.");
+                        quit_token_c.cancel();
+                        break;
+                    }
+                    last_info = info;
+                    debug!("Sent focused window change");
+                    continue;
+                },
                 _ = quit_token_c.cancelled() => break,
             }
-            let info = match dbus::get_focused_window_info(&dbus_connection).await {
+            let info = match provider.focused_window().await {
                 Ok(i) => i,
                 Err(e) => {
-                    error!("Poll focused window info from D-Bus, Err: {e:?}");
+                    error!("Get focused window info, Err: {e:?}");
                     continue;
                 }
             };
             if info != last_info {
-                println!("Reporting focused window change:\n{info:#?}");
-                debug!("Sending focused window change..");
-                if focused_window_tx.send(info.clone()).is_err() {
-                    error!("Send changed focused window info to RPC client task, receiving side closed.");
-                    quit_token_c.cancel();
-                    break;
-                }
-                last_info = info;
-                debug!("Sent focused window change");
+                debounce_sleep
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + debounce);
+                pending_info = Some(info);
+            } else {
+                pending_info = None;
             }
         }
     });
@@ -260,14 +542,26 @@ async fn run_report_focused_window(
     tokio::spawn(async move {
         loop {
             tokio::select! {
-                _ = focus_window_rx.changed() => {
-                    let info = focus_window_rx.borrow_and_update().clone();
-                    if let Err(e) = rpc_client.focused_window(context::current(), info).await {
-                        error!("Report focused window info over RPC, Err: {e:?}");
-                    }
+                res = focus_window_rx.changed() => if res.is_err() {
+                    break;
                 },
+                _ = tokio::time::sleep(FOCUSED_WINDOW_KEEPALIVE) => {},
                 _ = quit_token_c.cancelled() => break
             }
+            // Retried until reconnected, always sending the latest focused window info
+            let res = rpc_client
+                .call(&quit_token_c, |client| {
+                    let info = focus_window_rx.borrow_and_update().clone();
+                    async move { client.focused_window(context::current(), info).await }
+                })
+                .await;
+            match res {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    error!("Client failed to handle reported focused window info, Err: {e}")
+                }
+                None => break,
+            }
         }
     });
 
@@ -275,15 +569,64 @@ async fn run_report_focused_window(
     println!("Exiting..");
 }
 
+#[tracing::instrument(skip_all)]
+async fn run_discover(timeout: Duration) -> anyhow::Result<()> {
+    let devices = pb_cheatsheet_com::discovery::discover(timeout, None).await?;
+    if devices.is_empty() {
+        println!("No devices found");
+    }
+    for device in devices {
+        let addr = device
+            .socket_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "none".to_string());
+        println!("{}", rpc::describe_device(&device, addr));
+    }
+    Ok(())
+}
+
+async fn run_pair(endpoint: &Endpoint) -> anyhow::Result<()> {
+    println!("Pairing with device at address: '{}'", endpoint.addr);
+    let device_id = rpc::pair(endpoint, || async {
+        tokio::task::spawn_blocking(|| {
+            println!("Enter the code displayed on the device:");
+            let mut code = String::new();
+            std::io::stdin()
+                .read_line(&mut code)
+                .context("Read pairing code")?;
+            Ok(code)
+        })
+        .await?
+    })
+    .await?;
+    println!(
+        "Paired with device '{}', credentials stored in '{}'",
+        pb_cheatsheet_com::auth::hex(&device_id),
+        endpoint.credentials.display()
+    );
+    if !endpoint.tls {
+        println!("Paired without TLS, the certificate of the device is not pinned");
+    }
+    Ok(())
+}
+
 #[tracing::instrument(skip_all)]
 async fn run_get_info(
     rpc_client: WorldClient,
+    capabilities: &Capabilities,
     _quit_token: CancellationToken,
 ) -> anyhow::Result<()> {
-    let info = rpc_client.get_info(context::current()).await?;
+    let info = rpc_client.get_info(context::current()).await??;
     println!(
-        "\nscreen width: {}, height: {}, orientation: {}",
-        info.screen_width, info.screen_height, info.screen_orientation
+        "\nclient: {}, protocol version: {}, image formats: {:?}, features: {:?}",
+        capabilities.build,
+        capabilities.protocol_version,
+        capabilities.image_formats,
+        capabilities.features
+    );
+    println!(
+        "screen width: {}, height: {}, dpi: {}, orientation: {}",
+        info.screen_width, info.screen_height, info.screen_dpi, info.screen_orientation
     );
 
     println!("\ncheatsheets tags:");
@@ -298,6 +641,25 @@ async fn run_get_info(
             print!("{tag}");
         }
         println!("]");
+        let modified = sheet_tags
+            .modified
+            .map(|modified| {
+                chrono::DateTime::<chrono::Local>::from(modified)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        println!(
+            "    {}x{} {:?}, priority: {}, modified: {modified}, hash: {}",
+            sheet_tags.width,
+            sheet_tags.height,
+            sheet_tags.format,
+            sheet_tags.priority,
+            sheet_tags.content_hash
+        );
+        if let Some(selector) = &sheet_tags.selector {
+            println!("    selector: {selector}");
+        }
     }
     println!("\nwm classes tags:");
     for wm_class_tags in info.wm_classes.iter() {
@@ -312,34 +674,160 @@ async fn run_get_info(
         }
         println!("]");
     }
+    if capabilities.supports(Feature::MatchRules) {
+        let match_rules = rpc_client.get_match_rules(context::current()).await??;
+        println!("\nmatch rules:");
+        for rule in match_rules.iter() {
+            println!(
+                "  {} (priority {}) : [{}]",
+                rule.name,
+                rule.priority,
+                rule.tags.join(", ")
+            );
+            let patterns = [
+                ("wm class", &rule.wm_class),
+                ("instance", &rule.wm_class_instance),
+                ("title", &rule.title),
+            ]
+            .into_iter()
+            .filter_map(|(field, pattern)| Some(format!("{field}: {}", pattern.as_ref()?)))
+            .collect::<Vec<String>>();
+            if patterns.is_empty() {
+                println!("    matches all windows");
+            } else {
+                println!("    {}", patterns.join(", "));
+            }
+        }
+    }
     println!();
     Ok(())
 }
 
 #[tracing::instrument(skip_all)]
-async fn upload_cheatsheet_image(
+#[allow(clippy::too_many_arguments)]
+async fn run_upload_cheatsheet(
     rpc_client: WorldClient,
+    endpoint: &Endpoint,
+    capabilities: &Capabilities,
     quit_token: CancellationToken,
     image: PathBuf,
     name: String,
     tags: HashSet<String>,
+    options: PrepareOptions,
+    split_pages: bool,
+    force: bool,
 ) -> anyhow::Result<()> {
-    let screen_info = rpc_client.get_info(context::current()).await?;
-    debug!("Preparing cheatsheet image");
-    let image = tokio::select! {
-        image = imageprocessing::load_prepare_image(image, screen_info.screen_width, screen_info.screen_height, Rotate::Rotate0Deg, false) => {
-            image.context("Load and prepare image from file")?
-        },
-        _ = quit_token.cancelled() => return Ok(())
+    let screen_info = rpc_client.get_info(context::current()).await??;
+    let Some(prepared) = prepare_cheatsheet_image(
+        &screen_info,
+        capabilities,
+        &quit_token,
+        image,
+        options,
+        split_pages,
+    )
+    .await?
+    else {
+        return Ok(());
     };
 
-    println!("Uploading image..");
-    tokio::select! {
-        res = rpc_client.upload_cheatsheet(long_rpc_context(), image, name, tags) => {
-            res.context("Upload image to client")?
+    let content_hash = prepared.content_hash()?;
+    let unchanged = screen_info.cheatsheets.iter().find(|sheet| {
+        capabilities.supports(Feature::ContentHash)
+            && sheet.name == name
+            && sheet.content_hash == content_hash
+    });
+    if let (Some(sheet), false) = (unchanged, force) {
+        println!("Cheatsheet '{name}' is unchanged on the device, skipping upload.");
+        let current_tags = sheet.tags.iter().cloned().collect::<HashSet<String>>();
+        let add = tags
+            .difference(&current_tags)
+            .cloned()
+            .collect::<HashSet<String>>();
+        let remove = current_tags
+            .difference(&tags)
+            .cloned()
+            .collect::<HashSet<String>>();
+        if !add.is_empty() {
+            run_add_cheatsheet_tags(rpc_client.clone(), quit_token.clone(), name.clone(), add)
+                .await?;
         }
-        _ = quit_token.cancelled() => return Ok(())
+        if !remove.is_empty() {
+            let either = TagsEither::Tags(remove);
+            run_remove_cheatsheet_tags(rpc_client, quit_token, name, either).await?;
+        }
+        return Ok(());
+    }
+
+    let mut rpc_client =
+        ReconnectingClient::connected(endpoint, rpc_client).with_max_failures(UPLOAD_MAX_RETRIES);
+    upload_prepared_cheatsheet(
+        &mut rpc_client,
+        capabilities,
+        &quit_token,
+        name,
+        tags,
+        prepared,
+    )
+    .await
+}
+
+/// Prepares the image for the screen of the device, as far as the client application supports it.
+///
+/// Returns `None` when cancelled.
+async fn prepare_cheatsheet_image(
+    screen_info: &Info,
+    capabilities: &Capabilities,
+    quit_token: &CancellationToken,
+    image: PathBuf,
+    options: PrepareOptions,
+    mut split_pages: bool,
+) -> anyhow::Result<Option<PreparedImage>> {
+    let mut options = options.negotiate(capabilities);
+    if split_pages && !capabilities.supports(Feature::PageBreaks) {
+        warn!(
+            "Client application {} does not support page breaks, not splitting pages",
+            capabilities.build
+        );
+        split_pages = false;
+    }
+    if split_pages {
+        options.fit = FitMode::FitWidth;
+    }
+    debug!("Preparing cheatsheet image");
+    tokio::select! {
+        prepared = imageprocessing::load_prepare_image(image, screen_info, options.rotate.unwrap_or(Rotate::Rotate0Deg), false, options, split_pages) => {
+            let prepared = prepared.context("Load and prepare image from file")?;
+            if !prepared.page_breaks.is_empty() {
+                debug!("Split cheatsheet image at rows {:?}", prepared.page_breaks);
+            }
+            Ok(Some(prepared))
+        },
+        _ = quit_token.cancelled() => Ok(None)
     }
+}
+
+#[tracing::instrument(skip_all)]
+async fn upload_prepared_cheatsheet(
+    rpc_client: &mut ReconnectingClient,
+    capabilities: &Capabilities,
+    quit_token: &CancellationToken,
+    name: String,
+    tags: HashSet<String>,
+    prepared: PreparedImage,
+) -> anyhow::Result<()> {
+    let image = compress(prepared.image, capabilities)?;
+
+    println!("Uploading image..");
+    let target = UploadTarget::Cheatsheet {
+        name,
+        tags,
+        page_breaks: prepared.page_breaks,
+    };
+    let Some(res) = upload_image(rpc_client, quit_token, target, image).await else {
+        return Ok(());
+    };
+    res.context("Upload image to client")?;
     println!("Upload finished.");
     Ok(())
 }
@@ -352,16 +840,19 @@ async fn run_remove_cheatsheet(
 ) -> anyhow::Result<()> {
     rpc_client
         .remove_cheatsheet(context::current(), name)
-        .await?;
+        .await??;
     Ok(())
 }
 
 #[tracing::instrument(skip_all)]
 async fn run_upload_screenshot(
     rpc_client: WorldClient,
+    endpoint: &Endpoint,
+    capabilities: &Capabilities,
     quit_token: CancellationToken,
     name: Option<String>,
     invert: bool,
+    options: PrepareOptions,
 ) -> anyhow::Result<()> {
     let screenshot_req = ashpd::desktop::screenshot::Screenshot::request()
         .interactive(true)
@@ -377,32 +868,128 @@ async fn run_upload_screenshot(
     };
     debug!("Got screenshot path '{:?}'", screenshot);
 
-    let screen_info = rpc_client.get_info(context::current()).await?;
+    let screen_info = rpc_client.get_info(context::current()).await??;
+    let options = options.negotiate(capabilities);
     debug!("Preparing screenshot");
     let image = tokio::select! {
-        image = imageprocessing::load_prepare_image(screenshot, screen_info.screen_width, screen_info.screen_height, Rotate::Rotate270Deg, invert) => {
-            image.context("Load and prepare screenshot from file")?
+        prepared = imageprocessing::load_prepare_image(screenshot, &screen_info, options.rotate.unwrap_or(Rotate::Rotate270Deg), invert, options, false) => {
+            compress(prepared.context("Load and prepare screenshot from file")?.image, capabilities)?
         },
         _ = quit_token.cancelled() => return Ok(())
     };
 
     println!("Uploading screenshot..");
-    tokio::select! {
-        res = rpc_client.upload_screenshot(long_rpc_context(), image, name) => {
-            res.context("Upload screenshot to client")?
-        }
-        _ = quit_token.cancelled() => return Ok(())
-    }
+    let target = UploadTarget::Screenshot { name };
+    let mut rpc_client =
+        ReconnectingClient::connected(endpoint, rpc_client).with_max_failures(UPLOAD_MAX_RETRIES);
+    let Some(res) = upload_image(&mut rpc_client, &quit_token, target, image).await else {
+        return Ok(());
+    };
+    res.context("Upload screenshot to client")?;
     println!("Upload finished.");
     Ok(())
 }
 
+/// Compresses the image if the client application supports the compressed format.
+fn compress(
+    image: CheatsheetImage,
+    capabilities: &Capabilities,
+) -> anyhow::Result<CheatsheetImage> {
+    if capabilities.supports_format(image.format.compressed()) {
+        image.compressed()
+    } else {
+        Ok(image)
+    }
+}
+
+/// Upload the image in chunks.
+///
+/// When the connection drops, the client gets reconnected and the upload is resumed.
+/// Returns `None` when cancelled.
+async fn upload_image(
+    rpc_client: &mut ReconnectingClient,
+    quit_token: &CancellationToken,
+    target: UploadTarget,
+    image: CheatsheetImage,
+) -> Option<anyhow::Result<()>> {
+    let header = image.upload_header();
+    let (target, header, data) = (&target, &header, &image.data);
+    let mut attempts = 0;
+
+    rpc_client
+        .call(quit_token, |client| {
+            attempts += 1;
+            if attempts > 1 {
+                println!("Upload interrupted, resuming..");
+            }
+            async move { try_upload_image(&client, target, header, data).await }
+        })
+        .await
+}
+
+async fn try_upload_image(
+    rpc_client: &WorldClient,
+    target: &UploadTarget,
+    header: &UploadHeader,
+    data: &[u8],
+) -> Result<Result<(), ComError>, RpcError> {
+    let session = match rpc_client
+        .begin_upload(context::current(), target.clone(), header.clone())
+        .await?
+    {
+        Ok(session) => session,
+        Err(e) => return Ok(Err(e)),
+    };
+    let mut offset = session.offset;
+    if offset > 0 {
+        println!("Resuming upload at {offset}/{} bytes", header.len);
+    }
+
+    while offset < header.len {
+        // The offset is reported by the client, so it is not trusted to be inside the data
+        let Some(start) = usize::try_from(offset)
+            .ok()
+            .filter(|start| *start <= data.len())
+        else {
+            return Ok(Err(ComError::UploadLengthMismatch {
+                expected: data.len() as u64,
+                received: offset,
+            }));
+        };
+        let chunk = data[start..(start + UPLOAD_CHUNK_SIZE).min(data.len())].to_vec();
+        let checksum = pb_cheatsheet_com::checksum(&chunk);
+        debug!("Uploading chunk at offset {offset}");
+        let next_offset = match rpc_client
+            .upload_chunk(context::current(), session.id, offset, chunk, checksum)
+            .await?
+        {
+            Ok(received) => received,
+            // The client already received more than assumed, continue from there
+            Err(ComError::UploadOffsetMismatch { expected, .. }) => expected,
+            Err(e) => return Ok(Err(e)),
+        };
+        // Would send the same chunks forever
+        if next_offset <= offset {
+            return Ok(Err(ComError::UploadOffsetMismatch {
+                expected: next_offset,
+                offset,
+            }));
+        }
+        offset = next_offset;
+    }
+
+    // Decompressing, validating and hashing the image takes a while on the device
+    rpc_client
+        .commit_upload(long_rpc_context(), session.id)
+        .await
+}
+
 #[tracing::instrument(skip_all)]
 async fn run_clear_screenshot(
     rpc_client: WorldClient,
     _quit_token: CancellationToken,
 ) -> anyhow::Result<()> {
-    rpc_client.clear_screenshot(context::current()).await?;
+    rpc_client.clear_screenshot(context::current()).await??;
     Ok(())
 }
 
@@ -415,7 +1002,7 @@ async fn run_add_cheatsheet_tags(
 ) -> anyhow::Result<()> {
     rpc_client
         .add_cheatsheet_tags(context::current(), name, tags)
-        .await?;
+        .await??;
     Ok(())
 }
 
@@ -428,7 +1015,33 @@ async fn run_remove_cheatsheet_tags(
 ) -> anyhow::Result<()> {
     rpc_client
         .remove_cheatsheet_tags(context::current(), name, either)
-        .await?;
+        .await??;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn run_set_cheatsheet_priority(
+    rpc_client: WorldClient,
+    _quit_token: CancellationToken,
+    name: String,
+    priority: i32,
+) -> anyhow::Result<()> {
+    rpc_client
+        .set_cheatsheet_priority(context::current(), name, priority)
+        .await??;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn run_set_cheatsheet_selector(
+    rpc_client: WorldClient,
+    _quit_token: CancellationToken,
+    name: String,
+    selector: Option<String>,
+) -> anyhow::Result<()> {
+    rpc_client
+        .set_cheatsheet_selector(context::current(), name, selector)
+        .await??;
     Ok(())
 }
 
@@ -441,7 +1054,7 @@ async fn run_add_wm_class_tags(
 ) -> anyhow::Result<()> {
     rpc_client
         .add_wm_class_tags(context::current(), wm_class, tags)
-        .await?;
+        .await??;
     Ok(())
 }
 
@@ -454,6 +1067,249 @@ async fn run_remove_wm_class_tags(
 ) -> anyhow::Result<()> {
     rpc_client
         .remove_wm_class_tags(context::current(), wm_class, either)
-        .await?;
+        .await??;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn run_set_match_rule(
+    rpc_client: WorldClient,
+    capabilities: &Capabilities,
+    _quit_token: CancellationToken,
+    rule: MatchRule,
+) -> anyhow::Result<()> {
+    require_match_rules(capabilities)?;
+    rpc_client
+        .set_match_rule(context::current(), rule)
+        .await??;
     Ok(())
 }
+
+#[tracing::instrument(skip_all)]
+async fn run_remove_match_rule(
+    rpc_client: WorldClient,
+    capabilities: &Capabilities,
+    _quit_token: CancellationToken,
+    name: String,
+) -> anyhow::Result<()> {
+    require_match_rules(capabilities)?;
+    rpc_client
+        .remove_match_rule(context::current(), name)
+        .await??;
+    Ok(())
+}
+
+fn require_match_rules(capabilities: &Capabilities) -> anyhow::Result<()> {
+    if !capabilities.supports(Feature::MatchRules) {
+        return Err(anyhow!(
+            "Client application {} does not support match rules. Update the client application",
+            capabilities.build
+        ));
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn run_sync(
+    rpc_client: WorldClient,
+    endpoint: &Endpoint,
+    capabilities: &Capabilities,
+    quit_token: CancellationToken,
+    manifest: PathBuf,
+    dry_run: bool,
+    reupload: bool,
+) -> anyhow::Result<()> {
+    let manifest = sync::Manifest::load(&manifest).context("Load manifest")?;
+    let mut rpc_client =
+        ReconnectingClient::connected(endpoint, rpc_client).with_max_failures(UPLOAD_MAX_RETRIES);
+    let Some(info) = rpc_client
+        .call(&quit_token, |client| async move {
+            client.get_info(context::current()).await
+        })
+        .await
+    else {
+        return Ok(());
+    };
+    let info = info?;
+
+    // Without content hashes every sheet would appear changed anyway
+    let reupload = reupload || !capabilities.supports(Feature::ContentHash);
+    // Only the sheets on the device need their content hash to be planned
+    let mut prepared_sheets = HashMap::new();
+    let mut content_hashes = HashMap::new();
+    if !reupload {
+        for sheet in manifest.sheets.iter() {
+            if !info.cheatsheets.iter().any(|s| s.name == sheet.name) {
+                continue;
+            }
+            let Some(prepared) =
+                prepare_sync_sheet(&info, capabilities, &quit_token, sheet).await?
+            else {
+                return Ok(());
+            };
+            content_hashes.insert(sheet.name.clone(), prepared.content_hash()?);
+            prepared_sheets.insert(sheet.name.clone(), prepared);
+        }
+    }
+    let actions = manifest.plan(&info, &content_hashes, reupload);
+
+    if actions.is_empty() {
+        println!("Device is already in sync.");
+        return Ok(());
+    }
+    println!("\nplanned changes:");
+    for action in actions.iter() {
+        println!("  {action}");
+    }
+    println!();
+    if dry_run {
+        return Ok(());
+    }
+
+    // Prepared before applying any change, so failing sheets don't leave the device half synced
+    for action in actions.iter() {
+        let sync::Action::UploadCheatsheet { sheet, .. } = action else {
+            continue;
+        };
+        if prepared_sheets.contains_key(&sheet.name) {
+            continue;
+        }
+        let Some(prepared) = prepare_sync_sheet(&info, capabilities, &quit_token, sheet).await?
+        else {
+            return Ok(());
+        };
+        prepared_sheets.insert(sheet.name.clone(), prepared);
+    }
+
+    for action in actions {
+        if quit_token.is_cancelled() {
+            return Ok(());
+        }
+        println!("Applying: {action}");
+        let res = match action {
+            sync::Action::UploadCheatsheet { sheet, .. } => {
+                let prepared = prepared_sheets
+                    .remove(&sheet.name)
+                    .ok_or_else(|| anyhow!("Cheatsheet '{}' was not prepared", sheet.name))?;
+                upload_prepared_cheatsheet(
+                    &mut rpc_client,
+                    capabilities,
+                    &quit_token,
+                    sheet.name,
+                    sheet.tags.into_iter().collect(),
+                    prepared,
+                )
+                .await?;
+                continue;
+            }
+            sync::Action::RemoveCheatsheet { name } => {
+                let name = &name;
+                rpc_client
+                    .call(&quit_token, |client| async move {
+                        client
+                            .remove_cheatsheet(context::current(), name.clone())
+                            .await
+                    })
+                    .await
+            }
+            sync::Action::AddCheatsheetTags { name, tags } => {
+                let (name, tags) = (&name, &tags.into_iter().collect::<HashSet<String>>());
+                rpc_client
+                    .call(&quit_token, |client| async move {
+                        client
+                            .add_cheatsheet_tags(context::current(), name.clone(), tags.clone())
+                            .await
+                    })
+                    .await
+            }
+            sync::Action::RemoveCheatsheetTags { name, tags } => {
+                let (name, either) = (&name, &TagsEither::Tags(tags.into_iter().collect()));
+                rpc_client
+                    .call(&quit_token, |client| async move {
+                        client
+                            .remove_cheatsheet_tags(
+                                context::current(),
+                                name.clone(),
+                                either.clone(),
+                            )
+                            .await
+                    })
+                    .await
+            }
+            sync::Action::SetCheatsheetPriority { name, priority } => {
+                let name = &name;
+                rpc_client
+                    .call(&quit_token, |client| async move {
+                        client
+                            .set_cheatsheet_priority(context::current(), name.clone(), priority)
+                            .await
+                    })
+                    .await
+            }
+            sync::Action::SetCheatsheetSelector { name, selector } => {
+                let (name, selector) = (&name, &selector);
+                rpc_client
+                    .call(&quit_token, |client| async move {
+                        client
+                            .set_cheatsheet_selector(
+                                context::current(),
+                                name.clone(),
+                                selector.clone(),
+                            )
+                            .await
+                    })
+                    .await
+            }
+            sync::Action::AddWmClassTags { wm_class, tags } => {
+                let (wm_class, tags) = (&wm_class, &tags.into_iter().collect::<HashSet<String>>());
+                rpc_client
+                    .call(&quit_token, |client| async move {
+                        client
+                            .add_wm_class_tags(context::current(), wm_class.clone(), tags.clone())
+                            .await
+                    })
+                    .await
+            }
+            sync::Action::RemoveWmClassTags { wm_class, tags } => {
+                let (wm_class, tags) = (&wm_class, &tags);
+                rpc_client
+                    .call(&quit_token, |client| async move {
+                        client
+                            .remove_wm_class_tags(
+                                context::current(),
+                                wm_class.clone(),
+                                tags.clone(),
+                            )
+                            .await
+                    })
+                    .await
+            }
+        };
+        match res {
+            Some(res) => res?,
+            None => return Ok(()),
+        }
+    }
+    println!("Sync finished.");
+    Ok(())
+}
+
+/// Prepares the image of a sheet in the manifest, returns `None` when cancelled.
+async fn prepare_sync_sheet(
+    info: &Info,
+    capabilities: &Capabilities,
+    quit_token: &CancellationToken,
+    sheet: &sync::SheetEntry,
+) -> anyhow::Result<Option<PreparedImage>> {
+    println!("Preparing cheatsheet '{}'..", sheet.name);
+    prepare_cheatsheet_image(
+        info,
+        capabilities,
+        quit_token,
+        sheet.source.clone(),
+        sheet.options,
+        sheet.split_pages,
+    )
+    .await
+    .with_context(|| format!("Prepare cheatsheet '{}'", sheet.name))
+}
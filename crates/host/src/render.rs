@@ -0,0 +1,319 @@
+//! Rasterization of Typst, PDF and SVG documents.
+
+use anyhow::{anyhow, Context};
+use comemo::Prehashed;
+use resvg::{tiny_skia, usvg};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use typst::diag::{FileError, FileResult};
+use typst::foundations::{Bytes, Datetime};
+use typst::syntax::{FileId, Source, VirtualPath};
+use typst::text::{Font, FontBook, FontInfo};
+use typst::{Library, World};
+
+/// Document formats that are rendered by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Document {
+    Typst,
+    Pdf,
+    Svg,
+}
+
+impl Document {
+    /// The document format of the file, determined by its extension.
+    ///
+    /// Returns `None` for files that are not documents, for example raster images.
+    pub(crate) fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "typ" => Some(Self::Typst),
+            "pdf" => Some(Self::Pdf),
+            "svg" => Some(Self::Svg),
+            _ => None,
+        }
+    }
+}
+
+/// Renders all pages of the document on white background, stacked vertically.
+///
+/// `pixel_per_pt` receives the width and height of the first page in points
+/// and returns the scale the pages are rendered with.
+pub(crate) fn render(
+    path: &Path,
+    document: Document,
+    pixel_per_pt: impl Fn(f32, f32) -> f32,
+) -> anyhow::Result<image::DynamicImage> {
+    let pages = match document {
+        Document::Typst => render_typst(path, pixel_per_pt)?,
+        Document::Pdf => render_pdf(path, pixel_per_pt)?,
+        Document::Svg => render_svg(path, pixel_per_pt)?,
+    };
+    stack_pages(pages)
+}
+
+fn stack_pages(pages: Vec<image::RgbaImage>) -> anyhow::Result<image::DynamicImage> {
+    if pages.len() == 1 {
+        return Ok(pages.into_iter().next().unwrap().into());
+    }
+    let width = pages
+        .iter()
+        .map(|page| page.width())
+        .max()
+        .ok_or_else(|| anyhow!("Document does not have any pages"))?;
+    let height = pages.iter().map(|page| page.height()).sum();
+    let mut stacked = image::RgbaImage::from_pixel(width, height, image::Rgba([0xff; 4]));
+    let mut y = 0;
+    for page in pages {
+        let x = (width - page.width()) / 2;
+        image::imageops::overlay(&mut stacked, &page, x as i64, y);
+        y += page.height() as i64;
+    }
+    Ok(stacked.into())
+}
+
+fn pixmap_to_image(pixmap: tiny_skia::Pixmap) -> anyhow::Result<image::RgbaImage> {
+    // The pixmap is opaque, so its premultiplied colors are the same as the straight ones
+    image::RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixmap.take())
+        .ok_or_else(|| anyhow!("Rendered pixmap has invalid dimensions"))
+}
+
+fn render_typst(
+    path: &Path,
+    pixel_per_pt: impl Fn(f32, f32) -> f32,
+) -> anyhow::Result<Vec<image::RgbaImage>> {
+    let world = TypstWorld::new(path)?;
+    let mut tracer = typst::eval::Tracer::new();
+    let document = typst::compile(&world, &mut tracer).map_err(|diagnostics| {
+        let messages = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+        anyhow!("Compiling typst document failed: {messages}")
+    })?;
+    let first_page = document
+        .pages
+        .first()
+        .ok_or_else(|| anyhow!("Typst document does not have any pages"))?;
+    let size = first_page.frame.size();
+    let scale = pixel_per_pt(size.x.to_pt() as f32, size.y.to_pt() as f32);
+
+    document
+        .pages
+        .iter()
+        .map(|page| {
+            let pixmap = typst_render::render(&page.frame, scale, typst::visualize::Color::WHITE);
+            pixmap_to_image(pixmap)
+        })
+        .collect()
+}
+
+fn render_pdf(
+    path: &Path,
+    pixel_per_pt: impl Fn(f32, f32) -> f32,
+) -> anyhow::Result<Vec<image::RgbaImage>> {
+    use pdfium_render::prelude::*;
+
+    let bindings = Pdfium::bind_to_system_library()
+        .context("Loading the pdfium library, make sure it is installed")?;
+    let pdfium = Pdfium::new(bindings);
+    let document = pdfium.load_pdf_from_file(path, None)?;
+    let pages = document.pages();
+    let first_page = pages.first()?;
+    let scale = pixel_per_pt(first_page.width().value, first_page.height().value);
+    let config = PdfRenderConfig::new().scale_page_by_factor(scale);
+
+    pages
+        .iter()
+        .map(|page| {
+            let bitmap = page.render_with_config(&config)?;
+            Ok(bitmap.as_image().into_rgba8())
+        })
+        .collect()
+}
+
+fn render_svg(
+    path: &Path,
+    pixel_per_pt: impl Fn(f32, f32) -> f32,
+) -> anyhow::Result<Vec<image::RgbaImage>> {
+    use usvg::{TreeParsing, TreePostProc};
+
+    let data = std::fs::read(path)?;
+    let options = usvg::Options {
+        resources_dir: path.parent().map(Path::to_path_buf),
+        ..Default::default()
+    };
+    let mut tree = usvg::Tree::from_data(&data, &options)?;
+    let mut fontdb = usvg::fontdb::Database::new();
+    fontdb.load_system_fonts();
+    tree.postprocess(usvg::PostProcessingSteps::default(), &fontdb);
+
+    // SVG user units are rendered as points
+    let scale = pixel_per_pt(tree.size.width(), tree.size.height());
+    let width = (tree.size.width() * scale).round().max(1.0) as u32;
+    let height = (tree.size.height() * scale).round().max(1.0) as u32;
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| anyhow!("Invalid SVG render size {width}x{height}"))?;
+    pixmap.fill(tiny_skia::Color::WHITE);
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    Ok(vec![pixmap_to_image(pixmap)?])
+}
+
+/// The environment a typst document is compiled in.
+///
+/// Files are resolved relative to the directory of the main source file.
+/// Packages are not supported.
+struct TypstWorld {
+    root: PathBuf,
+    main: Source,
+    library: Prehashed<Library>,
+    book: Prehashed<FontBook>,
+    fonts: Vec<FontSlot>,
+}
+
+/// A font that is loaded on first use.
+struct FontSlot {
+    source: FontSource,
+    index: u32,
+    font: OnceLock<Option<Font>>,
+}
+
+enum FontSource {
+    Embedded(&'static [u8]),
+    File(PathBuf),
+}
+
+impl FontSlot {
+    fn get(&self) -> Option<Font> {
+        self.font
+            .get_or_init(|| {
+                let data = match &self.source {
+                    FontSource::Embedded(data) => Bytes::from_static(data),
+                    FontSource::File(path) => Bytes::from(std::fs::read(path).ok()?),
+                };
+                Font::new(data, self.index)
+            })
+            .clone()
+    }
+}
+
+impl TypstWorld {
+    fn new(main_path: &Path) -> anyhow::Result<Self> {
+        let main_path = main_path.canonicalize()?;
+        let root = main_path
+            .parent()
+            .context("Typst source file does not have a parent directory")?
+            .to_path_buf();
+        let main_id = FileId::new(
+            None,
+            VirtualPath::within_root(&main_path, &root)
+                .context("Typst source file is not inside its root directory")?,
+        );
+        let text = std::fs::read_to_string(&main_path)?;
+        let (book, fonts) = Self::search_fonts();
+
+        Ok(Self {
+            root,
+            main: Source::new(main_id, text),
+            library: Prehashed::new(Library::default()),
+            book: Prehashed::new(book),
+            fonts,
+        })
+    }
+
+    /// Searches for system fonts and fonts embedded in typst.
+    fn search_fonts() -> (FontBook, Vec<FontSlot>) {
+        let mut book = FontBook::new();
+        let mut fonts = Vec::new();
+
+        let mut db = usvg::fontdb::Database::new();
+        db.load_system_fonts();
+        for face in db.faces() {
+            let path = match &face.source {
+                usvg::fontdb::Source::File(path) | usvg::fontdb::Source::SharedFile(path, _) => {
+                    path
+                }
+                usvg::fontdb::Source::Binary(_) => continue,
+            };
+            let info = db.with_face_data(face.id, FontInfo::new).flatten();
+            if let Some(info) = info {
+                book.push(info);
+                fonts.push(FontSlot {
+                    source: FontSource::File(path.clone()),
+                    index: face.index,
+                    font: OnceLock::new(),
+                });
+            }
+        }
+
+        for data in typst_assets::fonts() {
+            for (index, info) in FontInfo::iter(data).enumerate() {
+                book.push(info);
+                fonts.push(FontSlot {
+                    source: FontSource::Embedded(data),
+                    index: index as u32,
+                    font: OnceLock::new(),
+                });
+            }
+        }
+
+        (book, fonts)
+    }
+
+    fn path(&self, id: FileId) -> FileResult<PathBuf> {
+        if id.package().is_some() {
+            return Err(FileError::Other(Some("Packages are not supported".into())));
+        }
+        id.vpath()
+            .resolve(&self.root)
+            .ok_or(FileError::AccessDenied)
+    }
+}
+
+impl World for TypstWorld {
+    fn library(&self) -> &Prehashed<Library> {
+        &self.library
+    }
+
+    fn book(&self) -> &Prehashed<FontBook> {
+        &self.book
+    }
+
+    fn main(&self) -> Source {
+        self.main.clone()
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        if id == self.main.id() {
+            return Ok(self.main.clone());
+        }
+        let path = self.path(id)?;
+        let text = std::fs::read_to_string(&path).map_err(|e| FileError::from_io(e, &path))?;
+        Ok(Source::new(id, text))
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        let path = self.path(id)?;
+        std::fs::read(&path)
+            .map(Bytes::from)
+            .map_err(|e| FileError::from_io(e, &path))
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        self.fonts.get(index)?.get()
+    }
+
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        use chrono::Datelike;
+
+        let now = match offset {
+            Some(hours) => chrono::Utc::now().naive_utc() + chrono::Duration::hours(hours),
+            None => chrono::Local::now().naive_local(),
+        };
+        Datetime::from_ymd(now.year(), now.month() as u8, now.day() as u8)
+    }
+}
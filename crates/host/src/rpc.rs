@@ -0,0 +1,314 @@
+//! Connecting to the RPC server of the client application.
+
+use crate::credentials::CredentialStore;
+use anyhow::{anyhow, Context};
+use core::net::SocketAddr;
+use pb_cheatsheet_com::auth::{self, Credentials, DeviceId};
+use pb_cheatsheet_com::discovery;
+use pb_cheatsheet_com::transport::{self, WireFormat};
+use pb_cheatsheet_com::{Capabilities, ComError, WorldClient, PROTOCOL_VERSION};
+use std::cmp::Ordering;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use tarpc::client::{self, RpcError};
+use tarpc::context;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// A sleeping device might not answer at all, so connection attempts time out.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// How long to browse for the device when the address is discovered.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the RPC server of the client application is reached.
+#[derive(Debug, Clone)]
+pub(crate) enum ServerAddr {
+    Fixed(SocketAddr),
+    /// Discovered in the local network through mDNS.
+    ///
+    /// Without selecting a device by its name or id, there must only be one in the network.
+    Discover {
+        device: Option<String>,
+    },
+}
+
+impl ServerAddr {
+    pub(crate) async fn resolve(&self) -> anyhow::Result<SocketAddr> {
+        let device = match self {
+            ServerAddr::Fixed(addr) => return Ok(*addr),
+            ServerAddr::Discover { device } => device.as_deref(),
+        };
+        let mut devices = discovery::discover(DISCOVERY_TIMEOUT, device)
+            .await?
+            .into_iter()
+            .filter_map(|device| Some((device.socket_addr()?, device)))
+            .collect::<Vec<_>>();
+        let (addr, device) = match (devices.len(), device) {
+            (0, Some(device)) => {
+                return Err(anyhow!("Device '{device}' not found through mDNS"));
+            }
+            (0, None) => {
+                return Err(anyhow!(
+                    "No device found through mDNS, pass the RPC server address explicitly"
+                ));
+            }
+            (1, _) => devices.remove(0),
+            _ => {
+                let found = devices
+                    .iter()
+                    .map(|(addr, device)| format!("  {}", describe_device(device, *addr)))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                return Err(anyhow!(
+                    "Found several devices through mDNS, select one with '--device <name|id>':\n{found}"
+                ));
+            }
+        };
+        println!("Discovered device {}", describe_device(&device, addr));
+        Ok(addr)
+    }
+}
+
+/// Describes the device with its name, id and address, as listed by the `discover` command.
+pub(crate) fn describe_device(device: &discovery::Device, addr: impl std::fmt::Display) -> String {
+    match &device.id {
+        Some(id) => format!("'{}' (id: {id}) with address: '{addr}'", device.name),
+        None => format!("'{}' with address: '{addr}'", device.name),
+    }
+}
+
+/// The RPC server of the client application and how to connect to it.
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoint {
+    pub(crate) addr: SocketAddr,
+    pub(crate) wire_format: WireFormat,
+    /// Secure the connection with TLS, otherwise messages are sent unencrypted.
+    pub(crate) tls: bool,
+    /// Path of the [CredentialStore].
+    pub(crate) credentials: PathBuf,
+}
+
+/// Connects and negotiates the protocol, returning what the client application supports.
+pub(crate) async fn connect(endpoint: &Endpoint) -> anyhow::Result<(WorldClient, Capabilities)> {
+    // Loaded for every connection, so credentials of a new pairing are picked up
+    let store = CredentialStore::load(&endpoint.credentials)?;
+    let transport = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        transport::connect(
+            endpoint.addr,
+            endpoint.wire_format,
+            endpoint.tls,
+            |device_id| store.get(device_id),
+        ),
+    )
+    .await??;
+    let client = WorldClient::new(client::Config::default(), transport).spawn();
+    let capabilities = handshake(&client).await?;
+    Ok((client, capabilities))
+}
+
+/// Refuses client applications that speak a different protocol version, as they refuse the host.
+async fn handshake(client: &WorldClient) -> anyhow::Result<Capabilities> {
+    let res = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        client.handshake(context::current(), PROTOCOL_VERSION),
+    )
+    .await
+    .map_err(|_| anyhow!("Protocol handshake timed out"))?
+    .context("Protocol handshake failed, the client application might be outdated")?;
+    let capabilities = match res {
+        Ok(capabilities) => capabilities,
+        Err(ComError::UnsupportedProtocol { supported, .. }) => {
+            return Err(version_mismatch("Client application", supported));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    debug!("Client capabilities: {capabilities:?}");
+    if capabilities.protocol_version != PROTOCOL_VERSION {
+        // Usually refused by the client application already, see the error above
+        return Err(version_mismatch(
+            &format!("Client application {}", capabilities.build),
+            capabilities.protocol_version,
+        ));
+    }
+    Ok(capabilities)
+}
+
+fn version_mismatch(client: &str, version: u32) -> anyhow::Error {
+    match version.cmp(&PROTOCOL_VERSION) {
+        Ordering::Less => anyhow!(
+            "{client} speaks protocol version {version}, the host requires {PROTOCOL_VERSION}. Update the client application"
+        ),
+        _ => anyhow!(
+            "{client} speaks protocol version {version}, the host only supports {PROTOCOL_VERSION}. Update the host application"
+        ),
+    }
+}
+
+/// Pairs with the device, `read_code` returns the one-time code displayed on the device.
+///
+/// The resulting credentials are added to the [CredentialStore],
+/// pinning the certificate of the device when connected with TLS.
+pub(crate) async fn pair<F>(
+    endpoint: &Endpoint,
+    read_code: impl FnOnce() -> F,
+) -> anyhow::Result<DeviceId>
+where
+    F: Future<Output = anyhow::Result<String>>,
+{
+    let mut store = CredentialStore::load(&endpoint.credentials)?;
+    let (device_id, credentials): (DeviceId, Credentials) =
+        transport::pair(endpoint.addr, endpoint.tls, read_code).await?;
+    store.insert(&device_id, credentials);
+    store.save(&endpoint.credentials)?;
+    debug!("Paired with device '{}'", auth::hex(&device_id));
+    Ok(device_id)
+}
+
+/// RPC client that connects on first use and reconnects when the connection is lost.
+///
+/// Failed connection attempts and calls are retried with exponential backoff,
+/// until cancelled or [ReconnectingClient::with_max_failures] is reached.
+/// A discovered server address is discovered again for every connection attempt,
+/// so address changes of the device are picked up.
+pub(crate) struct ReconnectingClient {
+    server_addr: ServerAddr,
+    wire_format: WireFormat,
+    tls: bool,
+    credentials: PathBuf,
+    client: Option<WorldClient>,
+    /// Number of consecutive failures.
+    failures: u32,
+    max_failures: Option<u32>,
+}
+
+impl ReconnectingClient {
+    pub(crate) fn new(
+        server_addr: ServerAddr,
+        wire_format: WireFormat,
+        tls: bool,
+        credentials: PathBuf,
+    ) -> Self {
+        Self {
+            server_addr,
+            wire_format,
+            tls,
+            credentials,
+            client: None,
+            failures: 0,
+            max_failures: None,
+        }
+    }
+
+    /// Starts with an already connected client, reconnecting to the same address.
+    pub(crate) fn connected(endpoint: &Endpoint, client: WorldClient) -> Self {
+        Self {
+            client: Some(client),
+            ..Self::new(
+                ServerAddr::Fixed(endpoint.addr),
+                endpoint.wire_format,
+                endpoint.tls,
+                endpoint.credentials.clone(),
+            )
+        }
+    }
+
+    /// Gives up a call after the given number of consecutive failures.
+    pub(crate) fn with_max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = Some(max_failures);
+        self
+    }
+
+    /// Calls `f` with the connected client until it does not fail with a transport error.
+    ///
+    /// `f` is called again for every retry, so it can send the latest state.
+    /// Returns `None` when cancelled.
+    pub(crate) async fn call<T, F, Fut>(
+        &mut self,
+        quit_token: &CancellationToken,
+        mut f: F,
+    ) -> Option<anyhow::Result<T>>
+    where
+        F: FnMut(WorldClient) -> Fut,
+        Fut: Future<Output = Result<Result<T, ComError>, RpcError>>,
+    {
+        let mut last_err = None;
+        loop {
+            if let Some(max_failures) = self.max_failures.filter(|max| self.failures >= *max) {
+                self.failures = 0;
+                let err = last_err.unwrap_or_else(|| anyhow!("Connection lost"));
+                return Some(Err(
+                    err.context(format!("Giving up after {max_failures} failed attempts"))
+                ));
+            }
+            if self.failures > 0 {
+                let delay = self.backoff();
+                debug!("Retrying in {delay:?}");
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    _ = quit_token.cancelled() => return None,
+                }
+            }
+
+            let client = match &self.client {
+                Some(client) => client.clone(),
+                None => {
+                    let res = tokio::select! {
+                        res = self.connect() => res,
+                        _ = quit_token.cancelled() => return None,
+                    };
+                    match res {
+                        Ok((client, addr)) => {
+                            println!("Connected to RPC server '{addr}'");
+                            self.client = Some(client.clone());
+                            client
+                        }
+                        Err(e) => {
+                            warn!("Connecting to RPC server failed, Err: {e:?}");
+                            last_err = Some(e);
+                            self.failures += 1;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let res = tokio::select! {
+                res = f(client) => res,
+                _ = quit_token.cancelled() => return None,
+            };
+            match res {
+                Ok(res) => {
+                    self.failures = 0;
+                    return Some(res.map_err(Into::into));
+                }
+                Err(e) => {
+                    warn!("RPC call failed, reconnecting. Err: {e:?}");
+                    last_err = Some(e.into());
+                    self.client = None;
+                    self.failures += 1;
+                }
+            }
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<(WorldClient, SocketAddr)> {
+        let endpoint = Endpoint {
+            addr: self.server_addr.resolve().await?,
+            wire_format: self.wire_format,
+            tls: self.tls,
+            credentials: self.credentials.clone(),
+        };
+        let (client, _) = connect(&endpoint).await?;
+        Ok((client, endpoint.addr))
+    }
+
+    /// Doubles with every consecutive failure, up to a maximum.
+    fn backoff(&self) -> Duration {
+        let factor = 2_u32.saturating_pow(self.failures.saturating_sub(1));
+        BACKOFF_INITIAL.saturating_mul(factor).min(BACKOFF_MAX)
+    }
+}
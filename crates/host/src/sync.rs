@@ -0,0 +1,493 @@
+//! Synchronization of the cheatsheets on the device with a declarative manifest.
+//!
+//! The manifest is a TOML or YAML file listing the cheatsheets with their image sources and tags,
+//! and the tags of wm classes. For example in TOML:
+//!
+//! ```toml
+//! [[sheets]]
+//! name = "git"
+//! source = "cheatsheets/cheat-git.typ"
+//! tags = ["git"]
+//! priority = 10
+//! selector = "git & !gitk"
+//! rotate = 270
+//!
+//! [wm_classes]
+//! "org.gnome.Console" = ["git", "bash"]
+//! ```
+
+use crate::imageprocessing::PrepareOptions;
+use anyhow::{anyhow, Context};
+use pb_cheatsheet_com::{CheatsheetTags, Info, TagsEither};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub(crate) struct Manifest {
+    #[serde(default)]
+    pub(crate) sheets: Vec<SheetEntry>,
+    /// key: wm class, value: associated tags
+    #[serde(default)]
+    pub(crate) wm_classes: BTreeMap<String, BTreeSet<String>>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub(crate) struct SheetEntry {
+    pub(crate) name: String,
+    /// Path to the image or document, relative to the manifest.
+    pub(crate) source: PathBuf,
+    #[serde(default)]
+    pub(crate) tags: BTreeSet<String>,
+    /// See [pb_cheatsheet_com::CheatsheetTags::priority].
+    #[serde(default)]
+    pub(crate) priority: i32,
+    /// See [pb_cheatsheet_com::CheatsheetTags::selector].
+    #[serde(default)]
+    pub(crate) selector: Option<String>,
+    #[serde(flatten)]
+    pub(crate) options: PrepareOptions,
+    #[serde(default)]
+    pub(crate) split_pages: bool,
+}
+
+impl Manifest {
+    /// Loads the manifest, the format is determined by the file extension.
+    ///
+    /// Sheet sources are resolved relative to the directory of the manifest.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading manifest '{}'", path.display()))?;
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let mut manifest: Manifest = match extension {
+            "toml" => toml::from_str(&content)?,
+            "yaml" | "yml" => serde_yaml::from_str(&content)?,
+            _ => {
+                return Err(anyhow!(
+                    "Unknown manifest format '{extension}', expected 'toml', 'yaml' or 'yml'"
+                ))
+            }
+        };
+
+        let mut names = HashSet::new();
+        for sheet in manifest.sheets.iter() {
+            if !names.insert(sheet.name.as_str()) {
+                return Err(anyhow!(
+                    "Cheatsheet '{}' is listed more than once",
+                    sheet.name
+                ));
+            }
+        }
+        let base_path = path.parent().unwrap_or(Path::new(""));
+        for sheet in manifest.sheets.iter_mut() {
+            sheet.source = base_path.join(&sheet.source);
+        }
+        Ok(manifest)
+    }
+
+    /// Compares the manifest with the info of the device and returns the actions that bring the device in sync.
+    ///
+    /// `content_hashes` contains the content hashes of the prepared sheet images by sheet name.
+    /// Cheatsheets that are missing or have a different content hash on the device get uploaded,
+    /// or all cheatsheets when `reupload` is set.
+    /// Cheatsheets and wm classes that are not in the manifest get removed.
+    pub(crate) fn plan(
+        &self,
+        info: &Info,
+        content_hashes: &HashMap<String, String>,
+        reupload: bool,
+    ) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        let device_sheets = info
+            .cheatsheets
+            .iter()
+            .map(|sheet| (sheet.name.as_str(), sheet))
+            .collect::<BTreeMap<&str, &CheatsheetTags>>();
+        for sheet in self.sheets.iter() {
+            let Some(device_sheet) = device_sheets.get(sheet.name.as_str()) else {
+                actions.push(Action::UploadCheatsheet {
+                    sheet: sheet.clone(),
+                    reason: UploadReason::Missing,
+                });
+                if sheet.priority != 0 {
+                    actions.push(Action::SetCheatsheetPriority {
+                        name: sheet.name.clone(),
+                        priority: sheet.priority,
+                    });
+                }
+                if sheet.selector.is_some() {
+                    actions.push(Action::SetCheatsheetSelector {
+                        name: sheet.name.clone(),
+                        selector: sheet.selector.clone(),
+                    });
+                }
+                continue;
+            };
+            // Uploading keeps the priority and selector of the sheet on the device
+            if device_sheet.priority != sheet.priority {
+                actions.push(Action::SetCheatsheetPriority {
+                    name: sheet.name.clone(),
+                    priority: sheet.priority,
+                });
+            }
+            if device_sheet.selector != sheet.selector {
+                actions.push(Action::SetCheatsheetSelector {
+                    name: sheet.name.clone(),
+                    selector: sheet.selector.clone(),
+                });
+            }
+            let reason = if reupload {
+                Some(UploadReason::Forced)
+            } else if content_hashes.get(&sheet.name) != Some(&device_sheet.content_hash) {
+                Some(UploadReason::Changed)
+            } else {
+                None
+            };
+            match reason {
+                Some(reason) => actions.push(Action::UploadCheatsheet {
+                    sheet: sheet.clone(),
+                    reason,
+                }),
+                None => {
+                    let device_tags = device_sheet.tags.iter().cloned().collect();
+                    let (add, remove) = tags_diff(&device_tags, &sheet.tags);
+                    if !add.is_empty() {
+                        actions.push(Action::AddCheatsheetTags {
+                            name: sheet.name.clone(),
+                            tags: add,
+                        });
+                    }
+                    if !remove.is_empty() {
+                        actions.push(Action::RemoveCheatsheetTags {
+                            name: sheet.name.clone(),
+                            tags: remove,
+                        });
+                    }
+                }
+            }
+        }
+        for name in device_sheets.keys() {
+            if !self.sheets.iter().any(|sheet| sheet.name == *name) {
+                actions.push(Action::RemoveCheatsheet {
+                    name: name.to_string(),
+                });
+            }
+        }
+
+        let device_wm_classes = info
+            .wm_classes
+            .iter()
+            .map(|wm_class| {
+                let tags = wm_class.tags.iter().cloned().collect();
+                (wm_class.wm_class.as_str(), tags)
+            })
+            .collect::<BTreeMap<&str, BTreeSet<String>>>();
+        let no_tags = BTreeSet::new();
+        for (wm_class, tags) in self.wm_classes.iter() {
+            let device_tags = device_wm_classes.get(wm_class.as_str()).unwrap_or(&no_tags);
+            let (add, remove) = tags_diff(device_tags, tags);
+            if !add.is_empty() {
+                actions.push(Action::AddWmClassTags {
+                    wm_class: wm_class.clone(),
+                    tags: add,
+                });
+            }
+            if !remove.is_empty() {
+                actions.push(Action::RemoveWmClassTags {
+                    wm_class: wm_class.clone(),
+                    tags: TagsEither::Tags(remove.into_iter().collect()),
+                });
+            }
+        }
+        for wm_class in device_wm_classes.keys() {
+            if !self.wm_classes.contains_key(*wm_class) {
+                actions.push(Action::RemoveWmClassTags {
+                    wm_class: wm_class.to_string(),
+                    tags: TagsEither::All,
+                });
+            }
+        }
+
+        actions
+    }
+}
+
+/// Returns the tags that need to be added and removed to get from `current` to `target`.
+fn tags_diff(
+    current: &BTreeSet<String>,
+    target: &BTreeSet<String>,
+) -> (BTreeSet<String>, BTreeSet<String>) {
+    let add = target.difference(current).cloned().collect();
+    let remove = current.difference(target).cloned().collect();
+    (add, remove)
+}
+
+/// A change on the device to bring it in sync with the manifest.
+#[derive(Debug, Clone)]
+pub(crate) enum Action {
+    UploadCheatsheet {
+        sheet: SheetEntry,
+        reason: UploadReason,
+    },
+    RemoveCheatsheet {
+        name: String,
+    },
+    AddCheatsheetTags {
+        name: String,
+        tags: BTreeSet<String>,
+    },
+    RemoveCheatsheetTags {
+        name: String,
+        tags: BTreeSet<String>,
+    },
+    SetCheatsheetPriority {
+        name: String,
+        priority: i32,
+    },
+    SetCheatsheetSelector {
+        name: String,
+        selector: Option<String>,
+    },
+    AddWmClassTags {
+        wm_class: String,
+        tags: BTreeSet<String>,
+    },
+    RemoveWmClassTags {
+        wm_class: String,
+        tags: TagsEither,
+    },
+}
+
+/// Why a cheatsheet gets uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UploadReason {
+    /// The cheatsheet is not on the device.
+    Missing,
+    /// The cheatsheet on the device has different content.
+    Changed,
+    /// All cheatsheets are uploaded.
+    Forced,
+}
+
+impl Display for UploadReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadReason::Missing => write!(f, "new"),
+            UploadReason::Changed => write!(f, "changed"),
+            UploadReason::Forced => write!(f, "forced"),
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn tags_list<'a>(tags: impl IntoIterator<Item = &'a String>) -> String {
+            tags.into_iter()
+                .map(String::as_str)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        }
+
+        match self {
+            Action::UploadCheatsheet { sheet, reason } => write!(
+                f,
+                "upload cheatsheet '{}' ({reason}) from '{}' with tags [{}]",
+                sheet.name,
+                sheet.source.display(),
+                tags_list(&sheet.tags)
+            ),
+            Action::RemoveCheatsheet { name } => write!(f, "remove cheatsheet '{name}'"),
+            Action::AddCheatsheetTags { name, tags } => {
+                write!(f, "add tags [{}] to cheatsheet '{name}'", tags_list(tags))
+            }
+            Action::RemoveCheatsheetTags { name, tags } => write!(
+                f,
+                "remove tags [{}] from cheatsheet '{name}'",
+                tags_list(tags)
+            ),
+            Action::SetCheatsheetPriority { name, priority } => {
+                write!(f, "set priority of cheatsheet '{name}' to {priority}")
+            }
+            Action::SetCheatsheetSelector {
+                name,
+                selector: Some(selector),
+            } => write!(f, "set selector of cheatsheet '{name}' to '{selector}'"),
+            Action::SetCheatsheetSelector {
+                name,
+                selector: None,
+            } => write!(f, "remove selector of cheatsheet '{name}'"),
+            Action::AddWmClassTags { wm_class, tags } => {
+                write!(f, "add tags [{}] to wm class '{wm_class}'", tags_list(tags))
+            }
+            Action::RemoveWmClassTags {
+                wm_class,
+                tags: TagsEither::Tags(tags),
+            } => {
+                let tags = tags.iter().collect::<BTreeSet<&String>>();
+                write!(
+                    f,
+                    "remove tags [{}] from wm class '{wm_class}'",
+                    tags_list(tags)
+                )
+            }
+            Action::RemoveWmClassTags {
+                wm_class,
+                tags: TagsEither::All,
+            } => write!(f, "remove wm class '{wm_class}'"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pb_cheatsheet_com::WmClassTags;
+
+    fn sheet(name: &str, tags: &[&str]) -> SheetEntry {
+        SheetEntry {
+            name: name.to_string(),
+            source: PathBuf::from(format!("{name}.png")),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            priority: 0,
+            selector: None,
+            options: PrepareOptions::default(),
+            split_pages: false,
+        }
+    }
+
+    fn device_sheet(name: &str, tags: &[&str], content_hash: &str) -> CheatsheetTags {
+        CheatsheetTags {
+            name: name.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            content_hash: content_hash.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn hashes(hashes: &[(&str, &str)]) -> HashMap<String, String> {
+        hashes
+            .iter()
+            .map(|(name, hash)| (name.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    fn plan(manifest: &Manifest, info: &Info, hashes: &HashMap<String, String>) -> Vec<String> {
+        manifest
+            .plan(info, hashes, false)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn plan_diffs_sheets() {
+        let manifest = Manifest {
+            sheets: vec![
+                sheet("new", &["a"]),
+                sheet("changed", &["a"]),
+                sheet("retagged", &["a", "b"]),
+                sheet("identical", &["a"]),
+            ],
+            ..Default::default()
+        };
+        let info = Info {
+            cheatsheets: vec![
+                device_sheet("changed", &["a"], "1"),
+                device_sheet("retagged", &["a", "old"], "2"),
+                device_sheet("identical", &["a"], "3"),
+                device_sheet("stale", &["a"], "4"),
+            ],
+            ..Default::default()
+        };
+        let hashes = hashes(&[
+            ("new", "0"),
+            ("changed", "changed"),
+            ("retagged", "2"),
+            ("identical", "3"),
+        ]);
+        assert_eq!(
+            plan(&manifest, &info, &hashes),
+            [
+                "upload cheatsheet 'new' (new) from 'new.png' with tags [a]",
+                "upload cheatsheet 'changed' (changed) from 'changed.png' with tags [a]",
+                "add tags [b] to cheatsheet 'retagged'",
+                "remove tags [old] from cheatsheet 'retagged'",
+                "remove cheatsheet 'stale'",
+            ]
+        );
+
+        let forced = manifest.plan(&info, &hashes, true);
+        assert!(forced.iter().any(|action| matches!(
+            action,
+            Action::UploadCheatsheet {
+                sheet,
+                reason: UploadReason::Forced,
+            } if sheet.name == "identical"
+        )));
+    }
+
+    #[test]
+    fn plan_sets_priority_and_selector() {
+        let mut new = sheet("new", &[]);
+        new.priority = 10;
+        new.selector = Some("vim & !neovim".to_string());
+        let mut existing = sheet("existing", &[]);
+        existing.priority = -1;
+        let manifest = Manifest {
+            sheets: vec![new, existing],
+            ..Default::default()
+        };
+        let mut device_existing = device_sheet("existing", &[], "1");
+        device_existing.selector = Some("git".to_string());
+        let info = Info {
+            cheatsheets: vec![device_existing],
+            ..Default::default()
+        };
+        let hashes = hashes(&[("new", "0"), ("existing", "1")]);
+        assert_eq!(
+            plan(&manifest, &info, &hashes),
+            [
+                "upload cheatsheet 'new' (new) from 'new.png' with tags []",
+                "set priority of cheatsheet 'new' to 10",
+                "set selector of cheatsheet 'new' to 'vim & !neovim'",
+                "set priority of cheatsheet 'existing' to -1",
+                "remove selector of cheatsheet 'existing'",
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_diffs_wm_classes() {
+        let manifest = Manifest {
+            wm_classes: BTreeMap::from([
+                (
+                    "terminal".to_string(),
+                    BTreeSet::from(["bash".to_string(), "git".to_string()]),
+                ),
+                ("editor".to_string(), BTreeSet::from(["vim".to_string()])),
+            ]),
+            ..Default::default()
+        };
+        let wm_class = |wm_class: &str, tags: &[&str]| WmClassTags {
+            wm_class: wm_class.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+        let info = Info {
+            wm_classes: vec![
+                wm_class("terminal", &["bash", "vim"]),
+                wm_class("editor", &["vim"]),
+                wm_class("stale", &["a"]),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            plan(&manifest, &info, &HashMap::new()),
+            [
+                "add tags [git] to wm class 'terminal'",
+                "remove tags [vim] from wm class 'terminal'",
+                "remove wm class 'stale'",
+            ]
+        );
+    }
+}
@@ -1,34 +1,152 @@
-use embedded_graphics::image::{Image, ImageRaw};
+use crate::FileOp;
+use anyhow::Context;
+use embedded_graphics::image::{Image, ImageDrawable, ImageDrawableExt, ImageRaw};
+use embedded_graphics::primitives::Rectangle;
 use embedded_graphics::{pixelcolor, prelude::*};
-use pb_cheatsheet_com::{CheatsheetTags, WmClassTags};
-use std::collections::{HashMap, HashSet};
+use globset::{Glob, GlobMatcher};
+use pb_cheatsheet_com::{
+    CheatsheetTags, ComError, FocusedWindowInfo, ImageFormat, MatchRule, Pattern, WmClassTags,
+};
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::iter::Peekable;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::str::CharIndices;
+use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::fs;
-use tokio::io::AsyncReadExt;
-use tokio::sync::mpsc;
-use tracing::debug;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info};
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Default)]
 pub(crate) struct Cheatsheets {
-    /// Contains the cheatsheets.
+    /// Contains the metadata of the cheatsheets, their images are loaded on demand, see [Cheatsheets::image].
     ///
-    /// key: cheatsheet name, value: tuple containing metadata and cheatsheet
-    sheets: HashMap<String, (CheatsheetMetadata, Cheatsheet)>,
+    /// key: cheatsheet name, value: metadata
+    sheets: BTreeMap<String, CheatsheetMetadata>,
     /// Contains tags associated with focused window wm class
     ///
     /// key: wm-class, value: wm-class tag
-    wm_class_tags: HashMap<String, HashSet<String>>,
+    wm_class_tags: BTreeMap<String, HashSet<String>>,
+    /// Contains the match rules activating tags for focused windows
+    ///
+    /// key: rule name, value: rule with compiled patterns
+    match_rules: BTreeMap<String, CompiledMatchRule>,
+    /// The most recently used images
+    images: ImageCache,
+    /// What changed since the last save
+    dirty: Dirty,
+    /// Saves that were dispatched but not confirmed yet, oldest first
+    pending_saves: Vec<PendingSave>,
+}
+
+/// A dispatched save, its images are kept in memory until they are written.
+#[derive(Debug)]
+struct PendingSave {
+    /// The saved changes, dirty again when saving failed
+    changes: Dirty,
+    /// Whether the files were written, see [FileOp::Save]
+    written: oneshot::Receiver<bool>,
+}
+
+/// Tracks the changes to the cheatsheets, so only these are written when saving.
+#[derive(Debug, Clone, Default)]
+struct Dirty {
+    /// Blobs to write with their images, by content hash
+    blobs: BTreeMap<String, Arc<Cheatsheet>>,
+    /// Blobs no sheet refers to anymore, by content hash
+    removed_blobs: BTreeSet<String>,
+    /// The index containing the sheet metadata, wm class tags and match rules
+    index: bool,
+    /// Files of the layout before the library index, removed once the index is written
+    legacy_files: Vec<PathBuf>,
+}
+
+impl Dirty {
+    fn is_dirty(&self) -> bool {
+        !self.blobs.is_empty()
+            || !self.removed_blobs.is_empty()
+            || self.index
+            || !self.legacy_files.is_empty()
+    }
+
+    /// Marks the changes of a save that failed as dirty again, so they are saved with the next one.
+    ///
+    /// Blobs are only kept when a sheet still refers to them, as in [Cheatsheets::release_blob].
+    fn restore(&mut self, failed: Dirty, sheets: &BTreeMap<String, CheatsheetMetadata>) {
+        let referenced =
+            |content_hash: &str| sheets.values().any(|m| m.content_hash == content_hash);
+        for (content_hash, image) in failed.blobs {
+            if referenced(&content_hash) {
+                self.blobs.entry(content_hash).or_insert(image);
+            }
+        }
+        for content_hash in failed.removed_blobs {
+            if !referenced(&content_hash) && !self.blobs.contains_key(&content_hash) {
+                self.removed_blobs.insert(content_hash);
+            }
+        }
+        self.index = true;
+        self.legacy_files.extend(failed.legacy_files);
+    }
+}
+
+/// The number of images kept in memory, enough to page back and forth between sheets.
+const IMAGE_CACHE_CAPACITY: usize = 3;
+
+/// Keeps the most recently used images decompressed, by content hash.
+#[derive(Debug, Clone, Default)]
+struct ImageCache {
+    /// Least recently used first
+    entries: VecDeque<(String, Arc<Cheatsheet>)>,
+}
+
+impl ImageCache {
+    fn get(&mut self, content_hash: &str) -> Option<Arc<Cheatsheet>> {
+        let position = self
+            .entries
+            .iter()
+            .position(|(hash, _image)| hash == content_hash)?;
+        let entry = self.entries.remove(position)?;
+        let image = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(image)
+    }
+
+    fn insert(&mut self, content_hash: String, image: Arc<Cheatsheet>) {
+        self.remove(&content_hash);
+        if self.entries.len() >= IMAGE_CACHE_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((content_hash, image));
+    }
+
+    fn remove(&mut self, content_hash: &str) {
+        self.entries.retain(|(hash, _image)| hash != content_hash);
+    }
 }
 
 impl Cheatsheets {
     pub(crate) fn get_sheet_tags(&self) -> Vec<CheatsheetTags> {
         self.sheets
             .iter()
-            .map(|(name, (metadata, _))| {
-                let tags = metadata.tags.iter().cloned().collect();
+            .map(|(name, metadata)| {
+                let mut tags = metadata.tags.iter().cloned().collect::<Vec<String>>();
+                tags.sort_unstable();
                 CheatsheetTags {
                     name: name.to_owned(),
                     tags,
+                    priority: metadata.priority,
+                    selector: metadata.selector.as_ref().map(ToString::to_string),
+                    content_hash: metadata.content_hash.clone(),
+                    width: metadata.width,
+                    height: metadata.height,
+                    format: metadata.format,
+                    modified: metadata.modified,
                 }
             })
             .collect()
@@ -38,7 +156,8 @@ impl Cheatsheets {
         self.wm_class_tags
             .iter()
             .map(|(wm_class, tags)| {
-                let tags = tags.iter().cloned().collect();
+                let mut tags = tags.iter().cloned().collect::<Vec<String>>();
+                tags.sort_unstable();
                 WmClassTags {
                     wm_class: wm_class.to_owned(),
                     tags,
@@ -47,191 +166,607 @@ impl Cheatsheets {
             .collect()
     }
 
-    pub(crate) fn sheets_iter(
-        &self,
-    ) -> impl Iterator<Item = (&String, &(CheatsheetMetadata, Cheatsheet))> {
-        self.sheets.iter()
+    /// All sheets, sheets with a higher priority first, then by name.
+    pub(crate) fn sheets_iter(&self) -> impl Iterator<Item = (&String, &CheatsheetMetadata)> {
+        let mut sheets = self.sheets.iter().collect::<Vec<_>>();
+        // Stable, so sheets with the same priority stay ordered by name
+        sheets.sort_by_key(|(_name, metadata)| Reverse(metadata.priority));
+        sheets.into_iter()
     }
 
     #[allow(unused)]
     pub(crate) fn sheets_mut(
         &mut self,
-    ) -> impl Iterator<Item = (&String, &mut (CheatsheetMetadata, Cheatsheet))> {
+    ) -> impl Iterator<Item = (&String, &mut CheatsheetMetadata)> {
+        self.dirty.index = true;
         self.sheets.iter_mut()
     }
 
+    pub(crate) fn get_sheet(&self, name: &str) -> Option<&CheatsheetMetadata> {
+        self.sheets.get(name)
+    }
+
+    /// Inserts the sheet, its image is kept in memory until it is saved.
     pub(crate) fn insert_sheet(
         &mut self,
         sheet: Cheatsheet,
         name: String,
-        tags: HashSet<String>,
-    ) -> Option<(CheatsheetMetadata, Cheatsheet)> {
+        mut metadata: CheatsheetMetadata,
+    ) -> Option<CheatsheetMetadata> {
+        metadata.width = sheet.image.width;
+        metadata.height = sheet.image.height;
+        metadata.format = sheet.image.format;
+        let content_hash = metadata.content_hash.clone();
+        // Sheets with the same image share the blob
+        let shared = self
+            .sheets
+            .iter()
+            .any(|(n, m)| *n != name && m.content_hash == content_hash);
+        let previous = self.sheets.insert(name, metadata);
+        if let Some(previous_metadata) = &previous {
+            self.release_blob(&previous_metadata.content_hash);
+        }
+        self.dirty.removed_blobs.remove(&content_hash);
+        if !shared {
+            self.dirty.blobs.insert(content_hash, Arc::new(sheet));
+        }
+        self.dirty.index = true;
+        previous
+    }
+
+    pub(crate) fn remove_sheet(&mut self, name: &str) -> Option<CheatsheetMetadata> {
+        let removed = self.sheets.remove(name)?;
+        self.release_blob(&removed.content_hash);
+        self.dirty.index = true;
+        Some(removed)
+    }
+
+    /// Marks the blob for removal when no sheet refers to it anymore.
+    fn release_blob(&mut self, content_hash: &str) {
+        if self
+            .sheets
+            .values()
+            .any(|metadata| metadata.content_hash == content_hash)
+        {
+            return;
+        }
+        self.dirty.blobs.remove(content_hash);
+        self.images.remove(content_hash);
+        self.dirty.removed_blobs.insert(content_hash.to_string());
+    }
+
+    /// The decompressed image with the content hash of a sheet, so drawing it again doesn't decompress it.
+    ///
+    /// Images that were not written yet are kept in memory, the others are loaded from their blob.
+    /// Both are kept in a small cache of the most recently used images.
+    /// The blob is read blocking, on the thread drawing the image. That's at most once per drawn page
+    /// and drawing has to wait for the image anyway, so it is not loaded in the background.
+    pub(crate) fn image(
+        &mut self,
+        base_path: impl AsRef<Path>,
+        content_hash: &str,
+    ) -> anyhow::Result<Arc<Cheatsheet>> {
+        if let Some(image) = self.images.get(content_hash) {
+            return Ok(image);
+        }
+        let unsaved = std::iter::once(&self.dirty)
+            .chain(self.pending_saves.iter().map(|pending| &pending.changes))
+            .find_map(|changes| changes.blobs.get(content_hash));
+        let image = match unsaved {
+            Some(image) if !image.image.format.is_compressed() => image.clone(),
+            Some(image) => Arc::new(image.as_ref().clone().into_decompressed()?),
+            None => {
+                let blob_path = base_path
+                    .as_ref()
+                    .join(BLOBS_SUBFOLDER)
+                    .join(format!("{content_hash}.cs"));
+                debug!("Loading image from blob '{}'", blob_path.display());
+                let data = std::fs::read(&blob_path)
+                    .with_context(|| format!("Read blob '{}'", blob_path.display()))?;
+                let image = postcard::from_bytes::<Cheatsheet>(&data).context("Parse blob")?;
+                Arc::new(image.into_decompressed()?)
+            }
+        };
+        self.images.insert(content_hash.to_string(), image.clone());
+        Ok(image)
+    }
+
+    /// The number of pages of all sheets.
+    pub(crate) fn n_pages(&self, page_height: u32) -> usize {
         self.sheets
-            .insert(name, (CheatsheetMetadata { tags }, sheet))
+            .values()
+            .map(|metadata| metadata.n_pages(page_height))
+            .sum()
     }
 
-    pub(crate) fn remove_sheet(&mut self, name: &str) -> Option<(CheatsheetMetadata, Cheatsheet)> {
-        self.sheets.remove(name)
+    /// The number of pages of all sheets for the window.
+    pub(crate) fn window_n_pages(&self, window: &FocusedWindowInfo, page_height: u32) -> usize {
+        self.sheets_for_window(window)
+            .into_iter()
+            .map(|metadata| metadata.n_pages(page_height))
+            .sum()
     }
 
-    pub(crate) fn wm_class_n_sheets(&self, wm_class: &str) -> usize {
-        self.sheets_for_wm_class(wm_class).len()
+    pub(crate) fn add_sheet_tags(
+        &mut self,
+        name: &str,
+        tags: HashSet<String>,
+    ) -> Result<(), ComError> {
+        let Some(metadata) = self.sheets.get_mut(name) else {
+            return Err(ComError::CheatsheetNotFound {
+                name: name.to_string(),
+            });
+        };
+        metadata.tags.extend(tags);
+        self.dirty.index = true;
+        Ok(())
     }
 
-    pub(crate) fn add_sheet_tag(&mut self, name: &str, tag: String) -> anyhow::Result<bool> {
-        let Some((metadata, _sheet)) = self.sheets.get_mut(name) else {
-            return Err(anyhow::anyhow!("Cheatsheet with name '{name}' not found."));
+    /// Removes the tags from the cheatsheet.
+    ///
+    /// Nothing is removed if one of the tags is not associated with the cheatsheet.
+    pub(crate) fn remove_sheet_tags(
+        &mut self,
+        name: &str,
+        tags: &HashSet<String>,
+    ) -> Result<(), ComError> {
+        let Some(metadata) = self.sheets.get_mut(name) else {
+            return Err(ComError::CheatsheetNotFound {
+                name: name.to_string(),
+            });
         };
-        Ok(metadata.tags.insert(tag))
+        if let Some(tag) = tags.iter().find(|t| !metadata.tags.contains(*t)) {
+            return Err(ComError::CheatsheetTagNotFound {
+                name: name.to_string(),
+                tag: tag.clone(),
+            });
+        }
+        metadata.tags.retain(|t| !tags.contains(t));
+        self.dirty.index = true;
+        Ok(())
     }
 
-    pub(crate) fn remove_sheet_tag(&mut self, name: &str, tag: &str) -> anyhow::Result<bool> {
-        let Some((metadata, _sheet)) = self.sheets.get_mut(name) else {
-            return Err(anyhow::anyhow!("Cheatsheet with name '{name}' not found."));
+    pub(crate) fn set_sheet_priority(&mut self, name: &str, priority: i32) -> Result<(), ComError> {
+        let Some(metadata) = self.sheets.get_mut(name) else {
+            return Err(ComError::CheatsheetNotFound {
+                name: name.to_string(),
+            });
         };
-        Ok(metadata.tags.remove(tag))
+        metadata.priority = priority;
+        self.dirty.index = true;
+        Ok(())
     }
 
-    pub(crate) fn clear_sheet_tags(&mut self, name: &str) -> anyhow::Result<()> {
-        let Some((metadata, _sheet)) = self.sheets.get_mut(name) else {
-            return Err(anyhow::anyhow!(
-                "Clearing cheatsheet '{name}' tags failed, not found."
-            ));
+    pub(crate) fn set_sheet_selector(
+        &mut self,
+        name: &str,
+        selector: Option<TagSelector>,
+    ) -> Result<(), ComError> {
+        let Some(metadata) = self.sheets.get_mut(name) else {
+            return Err(ComError::CheatsheetNotFound {
+                name: name.to_string(),
+            });
+        };
+        metadata.selector = selector;
+        self.dirty.index = true;
+        Ok(())
+    }
+
+    pub(crate) fn clear_sheet_tags(&mut self, name: &str) -> Result<(), ComError> {
+        let Some(metadata) = self.sheets.get_mut(name) else {
+            return Err(ComError::CheatsheetNotFound {
+                name: name.to_string(),
+            });
         };
         metadata.tags.clear();
+        self.dirty.index = true;
         Ok(())
     }
 
-    pub(crate) fn add_wm_class_tag(&mut self, wm_class: &str, tag: String) -> bool {
-        if let Some(tags) = self.wm_class_tags.get_mut(wm_class) {
-            tags.insert(tag)
-        } else {
-            let mut tags = HashSet::new();
-            tags.insert(tag);
-            self.wm_class_tags.insert(wm_class.to_string(), tags);
-            false
-        }
+    pub(crate) fn add_wm_class_tags(&mut self, wm_class: &str, tags: HashSet<String>) {
+        self.wm_class_tags
+            .entry(wm_class.to_string())
+            .or_default()
+            .extend(tags);
+        self.dirty.index = true;
     }
 
-    pub(crate) fn remove_wm_class_tag(
+    /// Removes the tags from the wm class. The wm class is removed when no tags are left.
+    ///
+    /// Nothing is removed if one of the tags is not associated with the wm class.
+    pub(crate) fn remove_wm_class_tags(
         &mut self,
         wm_class: &str,
-        tag: &str,
-    ) -> anyhow::Result<bool> {
-        let tags = self.wm_class_tags.get_mut(wm_class).ok_or_else(|| {
-            anyhow::anyhow!("Removing tag from wm class '{wm_class}' failed, not found.")
-        })?;
-        let removed = tags.remove(tag);
-        if tags.is_empty() {
+        tags: &HashSet<String>,
+    ) -> Result<(), ComError> {
+        let wm_class_tags =
+            self.wm_class_tags
+                .get_mut(wm_class)
+                .ok_or_else(|| ComError::WmClassNotFound {
+                    wm_class: wm_class.to_string(),
+                })?;
+        if let Some(tag) = tags.iter().find(|t| !wm_class_tags.contains(*t)) {
+            return Err(ComError::WmClassTagNotFound {
+                wm_class: wm_class.to_string(),
+                tag: tag.clone(),
+            });
+        }
+        wm_class_tags.retain(|t| !tags.contains(t));
+        if wm_class_tags.is_empty() {
             self.wm_class_tags.remove(wm_class);
         }
-        Ok(removed)
+        self.dirty.index = true;
+        Ok(())
     }
 
     #[allow(unused)]
-    pub(crate) fn clear_wm_class_tags(&mut self, wm_class: &str) -> anyhow::Result<()> {
-        let tags = self.wm_class_tags.get_mut(wm_class).ok_or_else(|| {
-            anyhow::anyhow!("Clearing wm class '{wm_class}' tags failed, not found.")
-        })?;
+    pub(crate) fn clear_wm_class_tags(&mut self, wm_class: &str) -> Result<(), ComError> {
+        let tags =
+            self.wm_class_tags
+                .get_mut(wm_class)
+                .ok_or_else(|| ComError::WmClassNotFound {
+                    wm_class: wm_class.to_string(),
+                })?;
         tags.clear();
+        self.dirty.index = true;
         Ok(())
     }
 
-    pub(crate) fn remove_wm_class(&mut self, wm_class: &str) -> anyhow::Result<HashSet<String>> {
-        self.wm_class_tags
-            .remove(wm_class)
-            .ok_or_else(|| anyhow::anyhow!("Couldn't remove wm class, not found."))
+    pub(crate) fn remove_wm_class(&mut self, wm_class: &str) -> Result<HashSet<String>, ComError> {
+        let tags =
+            self.wm_class_tags
+                .remove(wm_class)
+                .ok_or_else(|| ComError::WmClassNotFound {
+                    wm_class: wm_class.to_string(),
+                })?;
+        self.dirty.index = true;
+        Ok(tags)
+    }
+
+    pub(crate) fn get_match_rules(&self) -> Vec<MatchRule> {
+        self.match_rules
+            .values()
+            .map(|compiled| compiled.rule.clone())
+            .collect()
+    }
+
+    /// Adds the match rule or replaces the rule with the same name.
+    pub(crate) fn set_match_rule(&mut self, rule: MatchRule) -> Result<(), ComError> {
+        let compiled = CompiledMatchRule::new(rule)?;
+        self.match_rules
+            .insert(compiled.rule.name.clone(), compiled);
+        self.dirty.index = true;
+        Ok(())
+    }
+
+    pub(crate) fn remove_match_rule(&mut self, name: &str) -> Result<MatchRule, ComError> {
+        let compiled =
+            self.match_rules
+                .remove(name)
+                .ok_or_else(|| ComError::MatchRuleNotFound {
+                    name: name.to_string(),
+                })?;
+        self.dirty.index = true;
+        Ok(compiled.rule)
+    }
+
+    /// The tags activated for the window by its wm class and the matching rules.
+    ///
+    /// value: the highest priority the tag is activated with, wm class tags have priority 0
+    pub(crate) fn window_tags(&self, window: &FocusedWindowInfo) -> HashMap<&str, i32> {
+        let wm_class_tags = self
+            .wm_class_tags
+            .get(&window.wm_class)
+            .into_iter()
+            .flatten()
+            .map(|tag| (tag.as_str(), 0));
+        let rule_tags = self
+            .match_rules
+            .values()
+            .filter(|compiled| compiled.matches(window))
+            .flat_map(|compiled| {
+                let priority = compiled.rule.priority;
+                compiled
+                    .rule
+                    .tags
+                    .iter()
+                    .map(move |tag| (tag.as_str(), priority))
+            });
+
+        let mut tags = HashMap::new();
+        for (tag, priority) in wm_class_tags.chain(rule_tags) {
+            tags.entry(tag)
+                .and_modify(|p: &mut i32| *p = (*p).max(priority))
+                .or_insert(priority);
+        }
+        tags
     }
 
     #[allow(unused)]
     pub(crate) fn sheets_for_tag<'i>(
         &'i self,
         tag: &'i str,
-    ) -> impl Iterator<Item = (&'i CheatsheetMetadata, &'i Cheatsheet)> {
+    ) -> impl Iterator<Item = &'i CheatsheetMetadata> {
         self.sheets
-            .iter()
-            .filter_map(move |(_name, (metadata, sheet))| {
-                if metadata.tags.contains(tag) {
-                    Some((metadata, sheet))
-                } else {
-                    None
-                }
-            })
+            .values()
+            .filter(move |metadata| metadata.tags.contains(tag))
     }
 
-    pub(crate) fn sheets_for_wm_class<'i>(
-        &'i self,
-        wm_class: &'i str,
-    ) -> Vec<(&'i CheatsheetMetadata, &'i Cheatsheet)> {
-        let Some(wm_class_tags) = self.wm_class_tags.get(wm_class).map(|i| i.iter()) else {
-            return Vec::new();
-        };
+    /// The sheets matching the tags activated for the window.
+    ///
+    /// Sheets with a selector match when it evaluates to true for the tags,
+    /// the others when they have one of the tags.
+    /// Sheets sharing more tags with the window come first, then sheets activated
+    /// with a higher rule priority, then sheets with a higher priority, then by name.
+    pub(crate) fn sheets_for_window(&self, window: &FocusedWindowInfo) -> Vec<&CheatsheetMetadata> {
+        let window_tags = self.window_tags(window);
+        let is_active = |tag: &str| window_tags.contains_key(tag);
 
-        let found_sheets = self
+        let mut found_sheets = self
             .sheets
             .iter()
-            .filter_map(move |(_, (metadata, sheet))| {
-                if metadata
+            .filter_map(|(name, metadata)| {
+                let matches = match &metadata.selector {
+                    Some(selector) => selector.matches(&is_active),
+                    None => metadata.tags.iter().any(|tag| is_active(tag)),
+                };
+                if !matches {
+                    return None;
+                }
+                let shared = metadata
                     .tags
                     .iter()
-                    .any(|tag| wm_class_tags.clone().any(|t| t == tag))
-                {
-                    Some((metadata, sheet))
-                } else {
-                    None
+                    .map(String::as_str)
+                    .chain(metadata.selector.iter().flat_map(TagSelector::tags))
+                    .collect::<HashSet<&str>>()
+                    .into_iter()
+                    .filter_map(|tag| window_tags.get(tag))
+                    .collect::<Vec<&i32>>();
+                // Selectors like `!neovim` match without any shared tag
+                let rule_priority = shared.iter().map(|p| **p).max().unwrap_or_default();
+                let score = (
+                    Reverse(shared.len()),
+                    Reverse(rule_priority),
+                    Reverse(metadata.priority),
+                    name,
+                );
+                Some((score, metadata))
+            })
+            .collect::<Vec<_>>();
+        found_sheets.sort_unstable_by_key(|(score, _metadata)| *score);
+        found_sheets
+            .into_iter()
+            .map(|(_, metadata)| metadata)
+            .collect()
+    }
+
+    /// Whether there are changes that were not saved yet.
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty.is_dirty()
+    }
+
+    /// Handles the dispatched saves that finished, see [Cheatsheets::dispatch_save].
+    ///
+    /// The written images are loaded from their blobs from now on, the changes of failed saves are dirty again.
+    pub(crate) fn poll_saves(&mut self) {
+        for mut pending in std::mem::take(&mut self.pending_saves) {
+            match pending.written.try_recv() {
+                Err(oneshot::error::TryRecvError::Empty) => self.pending_saves.push(pending),
+                Ok(true) => {}
+                Ok(false) | Err(oneshot::error::TryRecvError::Closed) => {
+                    error!("Saving cheatsheets failed, retrying with the next save");
+                    self.dirty.restore(pending.changes, &self.sheets);
                 }
-            });
-        // TODO: implement a tag-priority sorting
-        found_sheets.collect()
+            }
+        }
     }
 
-    pub(crate) fn dispatch_save_all(
-        &self,
+    /// Dispatches saving the changed blobs and the index and deleting the blobs no sheet refers to anymore.
+    ///
+    /// Blobs are written before the index referring to them, and removed after it,
+    /// all in one [FileOp::Save], so nothing is removed when writing failed
+    /// and the stored index never refers to a missing blob.
+    /// Unchanged blobs are not rewritten. The changes are marked as saved once they are dispatched,
+    /// the images are kept in memory until [Cheatsheets::poll_saves] sees them written.
+    pub(crate) fn dispatch_save(
+        &mut self,
         base_path: impl AsRef<Path>,
-        file_save_tx: mpsc::UnboundedSender<(PathBuf, Vec<u8>)>,
+        file_op_tx: mpsc::UnboundedSender<FileOp>,
     ) -> anyhow::Result<()> {
         let base_path = base_path.as_ref();
+        let blobs_path = base_path.join(BLOBS_SUBFOLDER);
 
-        for (name, (metadata, image)) in self.sheets.iter() {
-            let cheatsheet_path = base_path.join(format!("{name}.cs"));
-            let cheatsheet_data: Vec<u8> = postcard::to_stdvec(image)?;
-            file_save_tx.send((cheatsheet_path, cheatsheet_data))?;
-
-            let metadata_path = base_path.join(format!("{name}-metadata.json"));
-            let metadata_data = serde_json::to_vec(metadata)?;
-            file_save_tx.send((metadata_path, metadata_data))?;
+        let mut writes = Vec::new();
+        for (content_hash, image) in &self.dirty.blobs {
+            writes.push((
+                blobs_path.join(format!("{content_hash}.cs")),
+                postcard::to_stdvec(image.as_ref())?,
+            ));
         }
+        if self.dirty.index {
+            let index = LibraryIndex {
+                version: LIBRARY_VERSION,
+                sheets: self
+                    .sheets
+                    .iter()
+                    .map(|(name, metadata)| (name.clone(), metadata.clone()))
+                    .collect(),
+                wm_class_tags: self.wm_class_tags.clone(),
+                match_rules: self.get_match_rules(),
+            };
+            writes.push((
+                base_path.join(LIBRARY_INDEX_FILE_NAME),
+                serde_json::to_vec(&index)?,
+            ));
+        }
+        let removes = self
+            .dirty
+            .removed_blobs
+            .iter()
+            .map(|content_hash| blobs_path.join(format!("{content_hash}.cs")))
+            .chain(self.dirty.legacy_files.iter().cloned())
+            .collect();
 
-        let wm_class_tags_path = base_path.join("wm_class_tags.json");
-        let wm_class_tags_data = serde_json::to_vec(&self.wm_class_tags)?;
-        file_save_tx.send((wm_class_tags_path, wm_class_tags_data))?;
+        let (written_tx, written_rx) = oneshot::channel();
+        file_op_tx.send(FileOp::Save {
+            writes,
+            removes,
+            written: written_tx,
+        })?;
 
+        self.pending_saves.push(PendingSave {
+            changes: std::mem::take(&mut self.dirty),
+            written: written_rx,
+        });
         Ok(())
     }
 
-    pub(crate) fn dispatch_save_metadata(
-        &self,
-        base_path: impl AsRef<Path>,
-        file_save_tx: mpsc::UnboundedSender<(PathBuf, Vec<u8>)>,
-    ) -> anyhow::Result<()> {
+    /// Loads the library index, the images of its sheets are loaded on demand.
+    ///
+    /// Files that can't be loaded, e.g. because they were truncated by a power loss,
+    /// are moved to the quarantine folder and skipped instead of failing the entire library.
+    /// A library stored in the layout before the index was introduced gets migrated.
+    /// When the index is missing or had to be quarantined, the sheets are recovered from the blobs,
+    /// see [Cheatsheets::recover_from_blobs].
+    pub(crate) async fn load_from_path(base_path: impl AsRef<Path>) -> anyhow::Result<Self> {
         let base_path = base_path.as_ref();
+        let blobs_path = base_path.join(BLOBS_SUBFOLDER);
+        let quarantine_path = base_path.join(QUARANTINE_SUBFOLDER);
 
-        for (name, (metadata, _)) in self.sheets.iter() {
-            let metadata_path = base_path.join(format!("{name}-metadata.json"));
-            let metadata_data = serde_json::to_vec(metadata)?;
-            file_save_tx.send((metadata_path, metadata_data))?;
+        remove_tmp_files(base_path).await?;
+        if blobs_path.exists() {
+            remove_tmp_files(&blobs_path).await?;
         }
+        let legacy_files = list_legacy_files(base_path)?;
 
-        let wm_class_tags_path = base_path.join("wm_class_tags.json");
-        let wm_class_tags_data = serde_json::to_vec(&self.wm_class_tags)?;
-        file_save_tx.send((wm_class_tags_path, wm_class_tags_data))?;
+        let Some(index) =
+            load_index(&base_path.join(LIBRARY_INDEX_FILE_NAME), &quarantine_path).await?
+        else {
+            if legacy_files.is_empty() {
+                return Self::recover_from_blobs(base_path).await;
+            }
+            info!(
+                "Migrating cheatsheets in '{}' to library version {LIBRARY_VERSION}",
+                base_path.display()
+            );
+            let mut cheatsheets = Self::load_legacy(base_path).await?;
+            // Listed again, the ones that failed to load were moved to quarantine
+            cheatsheets.dirty.legacy_files = list_legacy_files(base_path)?;
+            cheatsheets.dirty.index = true;
+            return Ok(cheatsheets);
+        };
 
-        Ok(())
+        let mut cheatsheets = Self {
+            wm_class_tags: index.wm_class_tags,
+            match_rules: compile_match_rules(index.match_rules),
+            // Left over when the migration was interrupted
+            dirty: Dirty {
+                legacy_files,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        for (name, mut metadata) in index.sheets {
+            let blob_path = blobs_path.join(format!("{}.cs", metadata.content_hash));
+            if !blob_path.exists() {
+                error!(
+                    "Blob '{}' of cheatsheet '{name}' is missing, removing it from the index",
+                    blob_path.display()
+                );
+                cheatsheets.dirty.index = true;
+                continue;
+            }
+            // Indexes written before the image size was stored get it on the next save
+            if metadata.height == 0 {
+                debug!(
+                    "Loading size of cheatsheet '{name}' from blob '{}'",
+                    blob_path.display()
+                );
+                match load_blob(&blob_path).await {
+                    Ok(sheet) => {
+                        metadata.width = sheet.image.width;
+                        metadata.height = sheet.image.height;
+                        metadata.format = sheet.image.format;
+                        cheatsheets.dirty.index = true;
+                    }
+                    Err(e) => {
+                        error!("Loading cheatsheet '{name}' failed, moving its blob to quarantine and removing it from the index. Err: {e:?}");
+                        quarantine(&quarantine_path, &[&blob_path]).await;
+                        cheatsheets.dirty.index = true;
+                        continue;
+                    }
+                }
+            }
+            cheatsheets.sheets.insert(name, metadata);
+        }
+
+        // Blobs are written before the index, so they are orphaned when saving was interrupted.
+        // Only swept with a loaded index, without one the blobs are all that's left of the library.
+        for (content_hash, blob_path) in list_blobs(&blobs_path)? {
+            if !cheatsheets
+                .sheets
+                .values()
+                .any(|metadata| metadata.content_hash == content_hash)
+            {
+                debug!("Removing orphaned blob '{}'", blob_path.display());
+                cheatsheets.dirty.removed_blobs.insert(content_hash);
+            }
+        }
+        Ok(cheatsheets)
     }
 
-    pub(crate) async fn load_from_path(base_path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let base_path = base_path.as_ref();
-        let mut sheets = HashMap::new();
+    /// Recovers the sheets of a library whose index is missing or was quarantined from its blobs.
+    ///
+    /// The names, tags and the rest of the metadata were only stored in the index,
+    /// so each blob becomes a sheet named after its content hash, without tags.
+    /// They are kept until they are tagged, replaced or removed, instead of being swept as orphans.
+    async fn recover_from_blobs(base_path: &Path) -> anyhow::Result<Self> {
+        let blobs_path = base_path.join(BLOBS_SUBFOLDER);
+        let quarantine_path = base_path.join(QUARANTINE_SUBFOLDER);
+        let mut cheatsheets = Self::default();
+
+        for (content_hash, blob_path) in list_blobs(&blobs_path)? {
+            let sheet = match load_blob(&blob_path).await {
+                Ok(sheet) => sheet,
+                Err(e) => {
+                    error!(
+                        "Loading blob '{}' failed, moving it to quarantine. Err: {e:?}",
+                        blob_path.display()
+                    );
+                    quarantine(&quarantine_path, &[&blob_path]).await;
+                    continue;
+                }
+            };
+            let name = format!("{RECOVERED_SHEET_PREFIX}{content_hash}");
+            info!(
+                "Recovered cheatsheet '{name}' from blob '{}' without an index",
+                blob_path.display()
+            );
+            let modified = fs::metadata(&blob_path).await?.modified().ok();
+            cheatsheets.sheets.insert(
+                name,
+                CheatsheetMetadata {
+                    content_hash,
+                    modified,
+                    width: sheet.image.width,
+                    height: sheet.image.height,
+                    format: sheet.image.format,
+                    ..Default::default()
+                },
+            );
+            cheatsheets.dirty.index = true;
+        }
+        Ok(cheatsheets)
+    }
+
+    /// Loads the cheatsheets stored in the layout before the library index was introduced,
+    /// each sheet in a `<name>.cs` file with a sibling `<name>-metadata.json`,
+    /// next to `wm_class_tags.json` and `match_rules.json`.
+    ///
+    /// The images are kept in memory until they are saved as blobs.
+    async fn load_legacy(base_path: &Path) -> anyhow::Result<Self> {
+        let quarantine_path = base_path.join(QUARANTINE_SUBFOLDER);
+        let mut cheatsheets = Self::default();
 
         for entry in base_path.read_dir()? {
             let entry_path = entry?.path();
@@ -239,62 +774,681 @@ impl Cheatsheets {
                 continue;
             }
             debug!("Loading cheatsheet from file '{}'", entry_path.display());
-            let basename = entry_path
-                .file_stem()
-                .ok_or_else(|| {
-                    anyhow::anyhow!(
-                        "Cheatsheet file '{}' does not have file stem",
-                        entry_path.display()
-                    )
-                })?
-                .to_str()
-                .ok_or_else(|| {
-                    anyhow::anyhow!(
-                        "Cheatsheet file '{}' stem not valid UTF-8",
-                        entry_path.display()
-                    )
-                })?;
+            let Some(basename) = entry_path.file_stem().and_then(|stem| stem.to_str()) else {
+                error!(
+                    "Cheatsheet file '{}' stem not valid UTF-8, skipping it",
+                    entry_path.display()
+                );
+                continue;
+            };
+            let metadata_path = base_path.join(format!("{basename}-metadata.json"));
+
+            match load_sheet(&entry_path, &metadata_path).await {
+                Ok((metadata, sheet)) => {
+                    cheatsheets.insert_sheet(sheet, basename.to_string(), metadata);
+                }
+                Err(e) => {
+                    error!("Loading cheatsheet '{basename}' failed, moving it to quarantine. Err: {e:?}");
+                    quarantine(&quarantine_path, &[&entry_path, &metadata_path]).await;
+                }
+            }
+        }
 
-            let mut cheatsheet_data: Vec<u8> = Vec::new();
-            fs::File::open(&entry_path)
+        cheatsheets.wm_class_tags =
+            load_json(&base_path.join("wm_class_tags.json"), &quarantine_path)
                 .await?
-                .read_to_end(&mut cheatsheet_data)
-                .await?;
-            let cheatsheet: Cheatsheet = postcard::from_bytes(&cheatsheet_data)?;
+                .unwrap_or_default();
 
-            let mut metadata_data: Vec<u8> = Vec::new();
-            fs::File::open(base_path.join(format!("{basename}-metadata.json")))
+        // Stored since match rules were introduced
+        let match_rules: Vec<MatchRule> =
+            load_json(&base_path.join("match_rules.json"), &quarantine_path)
                 .await?
-                .read_to_end(&mut metadata_data)
-                .await?;
-            let metadata: CheatsheetMetadata = serde_json::from_slice(&metadata_data)?;
+                .unwrap_or_default();
+        cheatsheets.match_rules = compile_match_rules(match_rules);
+        Ok(cheatsheets)
+    }
+}
+
+/// The version of the library index written by this client, see [LibraryIndex].
+const LIBRARY_VERSION: u32 = 1;
+/// The file name of the library index inside the cheatsheets folder.
+const LIBRARY_INDEX_FILE_NAME: &str = "library.json";
+/// The folder inside the cheatsheets folder the sheet images are stored in, named by their content hash.
+const BLOBS_SUBFOLDER: &str = "blobs";
+
+/// Sheets recovered without an index are named by this prefix and their content hash.
+const RECOVERED_SHEET_PREFIX: &str = "recovered-";
 
-            sheets.insert(basename.to_string(), (metadata, cheatsheet));
+/// The index of the stored library.
+///
+/// Sheet names are only stored inside the index, so they never end up in file paths.
+/// The images are stored separately as blobs named by the content hash of the sheet,
+/// so they can be written and read individually.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct LibraryIndex {
+    /// See [LIBRARY_VERSION], bumped on incompatible changes, older versions get migrated when loading.
+    version: u32,
+    sheets: BTreeMap<String, CheatsheetMetadata>,
+    #[serde(default)]
+    wm_class_tags: BTreeMap<String, HashSet<String>>,
+    #[serde(default)]
+    match_rules: Vec<MatchRule>,
+}
+
+/// Only the version of the index, read first to decide how to parse the rest.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct LibraryIndexVersion {
+    version: u32,
+}
+
+/// Loads the library index, `None` when it does not exist (yet).
+///
+/// An index that can't be parsed is moved to quarantine and `None` is returned as well.
+/// Fails for an index written by a newer client, instead of losing what it stores.
+async fn load_index(path: &Path, quarantine_path: &Path) -> anyhow::Result<Option<LibraryIndex>> {
+    let data = match fs::read(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Read '{}'", path.display())),
+    };
+    let parsed = serde_json::from_slice::<LibraryIndexVersion>(&data).and_then(|version| {
+        if version.version > LIBRARY_VERSION {
+            return Ok(Err(version.version));
+        }
+        // Migrations of older index versions go here
+        serde_json::from_slice::<LibraryIndex>(&data).map(Ok)
+    });
+    match parsed {
+        Ok(Ok(index)) => Ok(Some(index)),
+        Ok(Err(version)) => Err(anyhow::anyhow!(
+            "Library '{}' has version {version}, this client only supports up to version {LIBRARY_VERSION}. Update the client application",
+            path.display()
+        )),
+        Err(e) => {
+            error!(
+                "Parsing '{}' failed, moving it to quarantine. Err: {e:?}",
+                path.display()
+            );
+            quarantine(quarantine_path, &[path]).await;
+            Ok(None)
         }
+    }
+}
 
-        let mut wm_class_tags_data = Vec::new();
-        fs::File::open(base_path.join("wm_class_tags.json"))
-            .await?
-            .read_to_end(&mut wm_class_tags_data)
-            .await?;
-        let wm_class_tags = serde_json::from_slice(&wm_class_tags_data)?;
-        Ok(Self {
-            sheets,
-            wm_class_tags,
+async fn load_blob(blob_path: &Path) -> anyhow::Result<Cheatsheet> {
+    let data = fs::read(blob_path).await.context("Read blob")?;
+    postcard::from_bytes(&data).context("Parse blob")
+}
+
+/// Removes temporary files of interrupted saves.
+async fn remove_tmp_files(dir: &Path) -> anyhow::Result<()> {
+    for entry in dir.read_dir()? {
+        let entry_path = entry?.path();
+        if !entry_path.extension().map(|e| e == "tmp").unwrap_or(false) {
+            continue;
+        }
+        debug!(
+            "Removing temporary file '{}' of an interrupted save",
+            entry_path.display()
+        );
+        if let Err(e) = fs::remove_file(&entry_path).await {
+            error!(
+                "Removing temporary file '{}' failed, Err: {e:?}",
+                entry_path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The blobs in the folder with their content hash, empty when the folder does not exist (yet).
+fn list_blobs(blobs_path: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut blobs = Vec::new();
+    if !blobs_path.exists() {
+        return Ok(blobs);
+    }
+    for entry in blobs_path.read_dir()? {
+        let entry_path = entry?.path();
+        if !entry_path.extension().map(|e| e == "cs").unwrap_or(false) {
+            continue;
+        }
+        let Some(content_hash) = entry_path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        blobs.push((content_hash.to_string(), entry_path));
+    }
+    Ok(blobs)
+}
+
+/// The files of the layout before the library index was introduced, see [Cheatsheets::load_legacy].
+fn list_legacy_files(base_path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in base_path.read_dir()? {
+        let entry_path = entry?.path();
+        let is_legacy = match entry_path.file_name().and_then(|name| name.to_str()) {
+            Some("wm_class_tags.json" | "match_rules.json") => true,
+            Some(name) => name.ends_with(".cs") || name.ends_with("-metadata.json"),
+            None => false,
+        };
+        if is_legacy && entry_path.is_file() {
+            files.push(entry_path);
+        }
+    }
+    Ok(files)
+}
+
+fn compile_match_rules(match_rules: Vec<MatchRule>) -> BTreeMap<String, CompiledMatchRule> {
+    match_rules
+        .into_iter()
+        .filter_map(|rule| {
+            let name = rule.name.clone();
+            CompiledMatchRule::new(rule)
+                .inspect_err(|e| error!("Loading match rule '{name}' failed, Err: {e}"))
+                .ok()
         })
+        .map(|compiled| (compiled.rule.name.clone(), compiled))
+        .collect()
+}
+
+/// The folder inside the cheatsheets folder files that failed to load are moved to.
+pub(crate) const QUARANTINE_SUBFOLDER: &str = "quarantine";
+
+async fn load_sheet(
+    cheatsheet_path: &Path,
+    metadata_path: &Path,
+) -> anyhow::Result<(CheatsheetMetadata, Cheatsheet)> {
+    let cheatsheet_data = fs::read(cheatsheet_path).await.context("Read cheatsheet")?;
+    let cheatsheet: Cheatsheet =
+        postcard::from_bytes(&cheatsheet_data).context("Parse cheatsheet")?;
+    // Sheets stored before compression was introduced get compressed on the next save
+    let cheatsheet = Cheatsheet::compressed(cheatsheet.image)?;
+
+    let metadata_data = fs::read(metadata_path).await.context("Read metadata")?;
+    let mut metadata: CheatsheetMetadata =
+        serde_json::from_slice(&metadata_data).context("Parse metadata")?;
+    // Sheets stored before content hashes were introduced get them on the next save
+    if metadata.content_hash.is_empty() {
+        metadata.content_hash =
+            pb_cheatsheet_com::content_hash(&cheatsheet.image, &metadata.page_breaks)?;
+    }
+    if metadata.modified.is_none() {
+        metadata.modified = fs::metadata(cheatsheet_path).await?.modified().ok();
+    }
+    Ok((metadata, cheatsheet))
+}
+
+/// Loads a JSON file, `None` when it does not exist (yet).
+///
+/// A file that can't be parsed is moved to quarantine and `None` is returned as well.
+async fn load_json<T: DeserializeOwned>(
+    path: &Path,
+    quarantine_path: &Path,
+) -> anyhow::Result<Option<T>> {
+    let data = match fs::read(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Read '{}'", path.display())),
+    };
+    match serde_json::from_slice(&data) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => {
+            error!(
+                "Parsing '{}' failed, moving it to quarantine. Err: {e:?}",
+                path.display()
+            );
+            quarantine(quarantine_path, &[path]).await;
+            Ok(None)
+        }
+    }
+}
+
+/// Moves the files to the quarantine folder, so they are not loaded again but can still be recovered.
+///
+/// The files get the time they were quarantined appended, so earlier quarantined files are kept.
+pub(crate) async fn quarantine(quarantine_path: &Path, files: &[&Path]) {
+    if let Err(e) = fs::create_dir_all(quarantine_path).await {
+        error!(
+            "Creating quarantine folder '{}' failed, Err: {e:?}",
+            quarantine_path.display()
+        );
+        return;
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for file in files {
+        let Some(file_name) = file.file_name() else {
+            continue;
+        };
+        let mut quarantined_name = file_name.to_os_string();
+        quarantined_name.push(format!(".{timestamp}"));
+        let quarantined_path = quarantine_path.join(quarantined_name);
+        match fs::rename(file, &quarantined_path).await {
+            Ok(()) => info!(
+                "Moved '{}' to quarantine '{}'",
+                file.display(),
+                quarantined_path.display()
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!(
+                "Moving '{}' to quarantine failed, Err: {e:?}",
+                file.display()
+            ),
+        }
     }
 }
 
 #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub(crate) struct CheatsheetMetadata {
     pub(crate) tags: HashSet<String>,
+    /// Rows at which the image is split into pages.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) page_breaks: Vec<u32>,
+    /// See [pb_cheatsheet_com::content_hash], also names the blob the image is stored in.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) content_hash: String,
+    /// When the image was last uploaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) modified: Option<SystemTime>,
+    /// Sheets with a higher priority are shown first, see [Cheatsheets::sheets_for_window].
+    #[serde(default)]
+    pub(crate) priority: i32,
+    /// When set, the sheet is shown for windows the selector matches
+    /// instead of windows sharing one of its tags.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) selector: Option<TagSelector>,
+    /// Width of the image, stored so the image doesn't have to be loaded to list the sheet.
+    #[serde(default)]
+    pub(crate) width: u32,
+    /// Height of the image, stored so the image doesn't have to be loaded to page through it.
+    #[serde(default)]
+    pub(crate) height: u32,
+    #[serde(default)]
+    pub(crate) format: ImageFormat,
+}
+
+impl CheatsheetMetadata {
+    /// The rows of the pages of the image, see [Cheatsheet::pages].
+    pub(crate) fn page_rows(&self, page_height: u32) -> impl Iterator<Item = Range<u32>> {
+        page_rows(self.height, &self.page_breaks, page_height)
+    }
+
+    pub(crate) fn n_pages(&self, page_height: u32) -> usize {
+        self.page_rows(page_height).count()
+    }
 }
 
+/// A match rule with its patterns compiled.
+#[derive(Debug, Clone)]
+struct CompiledMatchRule {
+    rule: MatchRule,
+    wm_class: Option<Matcher>,
+    wm_class_instance: Option<Matcher>,
+    title: Option<Matcher>,
+}
+
+impl CompiledMatchRule {
+    fn new(rule: MatchRule) -> Result<Self, ComError> {
+        Ok(Self {
+            wm_class: rule.wm_class.as_ref().map(Matcher::new).transpose()?,
+            wm_class_instance: rule
+                .wm_class_instance
+                .as_ref()
+                .map(Matcher::new)
+                .transpose()?,
+            title: rule.title.as_ref().map(Matcher::new).transpose()?,
+            rule,
+        })
+    }
+
+    /// Whether all patterns of the rule match the window.
+    fn matches(&self, window: &FocusedWindowInfo) -> bool {
+        [
+            (&self.wm_class, &window.wm_class),
+            (&self.wm_class_instance, &window.wm_class_instance),
+            (&self.title, &window.title),
+        ]
+        .into_iter()
+        .all(|(matcher, value)| match matcher {
+            Some(matcher) => matcher.is_match(value),
+            None => true,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn new(pattern: &Pattern) -> Result<Self, ComError> {
+        match pattern {
+            Pattern::Glob(glob) => Glob::new(glob)
+                .map(|glob| Self::Glob(glob.compile_matcher()))
+                .map_err(|e| ComError::InvalidPattern {
+                    pattern: glob.clone(),
+                    reason: e.kind().to_string(),
+                }),
+            Pattern::Regex(regex) => {
+                Regex::new(regex)
+                    .map(Self::Regex)
+                    .map_err(|e| ComError::InvalidPattern {
+                        pattern: regex.clone(),
+                        reason: e.to_string(),
+                    })
+            }
+        }
+    }
+
+    fn is_match(&self, value: &str) -> bool {
+        match self {
+            Self::Glob(glob) => glob.is_match(value),
+            Self::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+/// Boolean expression over the tags activated for the focused window,
+/// e.g. `vim & !neovim` or `(git | gitk) & terminal`.
+///
+/// `!` binds tighter than `&`, which binds tighter than `|`.
+/// Selectors can be nested up to [SelectorParser::MAX_DEPTH] levels with `!` and parentheses.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct TagSelector {
+    /// The selector as it was set, reported back unchanged so hosts can compare it.
+    source: String,
+    expr: TagExpr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    /// Chains of `&` are flattened, so only nesting adds depth.
+    And(Vec<TagExpr>),
+    Or(Vec<TagExpr>),
+}
+
+impl TagSelector {
+    pub(crate) fn parse(selector: &str) -> Result<Self, ComError> {
+        let mut parser = SelectorParser {
+            chars: selector.char_indices().peekable(),
+            depth: 0,
+        };
+        let parsed = parser.or().and_then(|expr| match parser.peek() {
+            Some((pos, c)) => Err(format!("Unexpected '{c}' at position {pos}")),
+            None => Ok(expr),
+        });
+        match parsed {
+            Ok(expr) => Ok(Self {
+                source: selector.to_string(),
+                expr,
+            }),
+            Err(reason) => Err(ComError::InvalidSelector {
+                selector: selector.to_string(),
+                reason,
+            }),
+        }
+    }
+
+    /// Evaluates the selector, `is_active` tells whether a tag is activated for the window.
+    pub(crate) fn matches(&self, is_active: &impl Fn(&str) -> bool) -> bool {
+        self.expr.matches(is_active)
+    }
+
+    /// The tags the selector refers to.
+    pub(crate) fn tags(&self) -> Vec<&str> {
+        let mut tags = Vec::new();
+        self.expr.collect_tags(&mut tags);
+        tags
+    }
+}
+
+impl TagExpr {
+    fn matches(&self, is_active: &impl Fn(&str) -> bool) -> bool {
+        match self {
+            Self::Tag(tag) => is_active(tag),
+            Self::Not(expr) => !expr.matches(is_active),
+            Self::And(exprs) => exprs.iter().all(|expr| expr.matches(is_active)),
+            Self::Or(exprs) => exprs.iter().any(|expr| expr.matches(is_active)),
+        }
+    }
+
+    fn collect_tags<'e>(&'e self, tags: &mut Vec<&'e str>) {
+        match self {
+            Self::Tag(tag) => tags.push(tag),
+            Self::Not(expr) => expr.collect_tags(tags),
+            Self::And(exprs) | Self::Or(exprs) => {
+                for expr in exprs {
+                    expr.collect_tags(tags);
+                }
+            }
+        }
+    }
+}
+
+impl Display for TagSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl TryFrom<String> for TagSelector {
+    type Error = ComError;
+
+    fn try_from(selector: String) -> Result<Self, Self::Error> {
+        Self::parse(&selector)
+    }
+}
+
+impl From<TagSelector> for String {
+    fn from(selector: TagSelector) -> Self {
+        selector.source
+    }
+}
+
+/// Recursive descent parser of [TagSelector], one method per precedence level.
+///
+/// Errors describe the problem and its position in the selector.
+struct SelectorParser<'s> {
+    chars: Peekable<CharIndices<'s>>,
+    /// Levels of `!` and parentheses the parser is in.
+    depth: usize,
+}
+
+impl SelectorParser<'_> {
+    const OPERATORS: &'static [char] = &['&', '|', '!', '(', ')'];
+    /// Deeper selectors are rejected, parsing and evaluating them recurses per level.
+    const MAX_DEPTH: usize = 64;
+
+    /// The next character that is not whitespace, without consuming it.
+    fn peek(&mut self) -> Option<(usize, char)> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    /// Parses one level deeper, the operator at `pos` opened the level.
+    fn nested(
+        &mut self,
+        pos: usize,
+        parse: impl FnOnce(&mut Self) -> Result<TagExpr, String>,
+    ) -> Result<TagExpr, String> {
+        if self.depth >= Self::MAX_DEPTH {
+            return Err(format!(
+                "Nested deeper than {} levels at position {pos}",
+                Self::MAX_DEPTH
+            ));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn or(&mut self) -> Result<TagExpr, String> {
+        let mut exprs = vec![self.and()?];
+        while let Some((_, '|')) = self.peek() {
+            self.chars.next();
+            exprs.push(self.and()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => TagExpr::Or(exprs),
+        })
+    }
+
+    fn and(&mut self) -> Result<TagExpr, String> {
+        let mut exprs = vec![self.not()?];
+        while let Some((_, '&')) = self.peek() {
+            self.chars.next();
+            exprs.push(self.not()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => TagExpr::And(exprs),
+        })
+    }
+
+    fn not(&mut self) -> Result<TagExpr, String> {
+        if let Some((pos, '!')) = self.peek() {
+            self.chars.next();
+            return self.nested(pos, |parser| Ok(TagExpr::Not(Box::new(parser.not()?))));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<TagExpr, String> {
+        match self.peek() {
+            Some((pos, '(')) => {
+                self.chars.next();
+                let expr = self.nested(pos, Self::or)?;
+                match self.peek() {
+                    Some((_, ')')) => {
+                        self.chars.next();
+                        Ok(expr)
+                    }
+                    _ => Err(format!("Unclosed '(' at position {pos}")),
+                }
+            }
+            Some((pos, c)) if Self::OPERATORS.contains(&c) => {
+                Err(format!("Expected tag at position {pos}, found '{c}'"))
+            }
+            Some(_) => {
+                let mut tag = String::new();
+                while let Some((_, c)) = self
+                    .chars
+                    .next_if(|(_, c)| !c.is_whitespace() && !Self::OPERATORS.contains(c))
+                {
+                    tag.push(c);
+                }
+                Ok(TagExpr::Tag(tag))
+            }
+            None => Err("Expected tag at the end".to_string()),
+        }
+    }
+}
+
+/// The image formats cheatsheets can be drawn in.
+pub(crate) const SUPPORTED_IMAGE_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Gray8,
+    ImageFormat::Gray8Deflate,
+    ImageFormat::Gray1,
+    ImageFormat::Gray4,
+];
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub(crate) struct Cheatsheet {
     pub(crate) image: pb_cheatsheet_com::CheatsheetImage,
 }
 
+impl Cheatsheet {
+    /// Creates a cheatsheet that stores the image compressed.
+    pub(crate) fn compressed(image: pb_cheatsheet_com::CheatsheetImage) -> anyhow::Result<Self> {
+        Ok(Self {
+            image: image.compressed()?,
+        })
+    }
+
+    /// Decompresses the image, see [pb_cheatsheet_com::CheatsheetImage::decompressed].
+    pub(crate) fn into_decompressed(self) -> anyhow::Result<Self> {
+        if !self.image.format.is_compressed() {
+            return Ok(self);
+        }
+        Ok(Self {
+            image: self.image.decompressed()?.into_owned(),
+        })
+    }
+
+    /// The pages of the sheet, see [page_rows].
+    pub(crate) fn pages(
+        &self,
+        page_breaks: &[u32],
+        page_height: u32,
+    ) -> impl Iterator<Item = CheatsheetPage<'_>> {
+        page_rows(self.image.height, page_breaks, page_height).map(move |rows| self.page(rows))
+    }
+
+    /// The page of the sheet with the rows, see [CheatsheetMetadata::page_rows].
+    pub(crate) fn page(&self, rows: Range<u32>) -> CheatsheetPage<'_> {
+        CheatsheetPage { sheet: self, rows }
+    }
+
+    pub(crate) fn n_pages(&self, page_breaks: &[u32], page_height: u32) -> usize {
+        self.pages(page_breaks, page_height).count()
+    }
+}
+
+/// The rows of the pages of an image with the height.
+///
+/// The image is split at the page breaks, or when there are none
+/// and the image is taller than the page height, into slices of the page height.
+fn page_rows(
+    height: u32,
+    page_breaks: &[u32],
+    page_height: u32,
+) -> impl Iterator<Item = Range<u32>> {
+    let mut breaks = page_breaks
+        .iter()
+        .copied()
+        .filter(|row| *row > 0 && *row < height)
+        .collect::<Vec<u32>>();
+    if breaks.is_empty() && page_height > 0 {
+        breaks = (1..height.div_ceil(page_height))
+            .map(|page| page * page_height)
+            .collect();
+    }
+    breaks.sort_unstable();
+    breaks.dedup();
+
+    let starts = std::iter::once(0).chain(breaks.clone());
+    let ends = breaks.into_iter().chain(std::iter::once(height));
+    starts.zip(ends).map(|(start, end)| start..end)
+}
+
+/// Finds the item with the sheet that contains the page and the rows of the page,
+/// counting the pages of all sheets of the items in order.
+pub(crate) fn find_page<'s, T>(
+    items: impl IntoIterator<Item = T>,
+    metadata: impl Fn(&T) -> &'s CheatsheetMetadata,
+    mut page: usize,
+    page_height: u32,
+) -> Option<(T, Range<u32>)> {
+    for item in items {
+        let metadata = metadata(&item);
+        let n_pages = metadata.n_pages(page_height);
+        if page < n_pages {
+            let rows = metadata.page_rows(page_height).nth(page)?;
+            return Some((item, rows));
+        }
+        page -= n_pages;
+    }
+    None
+}
+
 impl embedded_graphics::Drawable for Cheatsheet {
     type Color = pixelcolor::Gray8;
 
@@ -304,24 +1458,91 @@ impl embedded_graphics::Drawable for Cheatsheet {
     where
         D: DrawTarget<Color = Self::Color>,
     {
-        let target_center = target.bounding_box().center();
-        match (self.image.format, self.image.byte_order) {
+        let page = CheatsheetPage {
+            sheet: self,
+            rows: 0..self.image.height,
+        };
+        page.draw(target)
+    }
+}
+
+/// A page of a cheatsheet, spanning a range of rows of its image.
+///
+/// Pages that fit on the screen are drawn centered,
+/// slices of taller images are drawn from the top of the screen.
+#[derive(Debug, Clone)]
+pub(crate) struct CheatsheetPage<'s> {
+    sheet: &'s Cheatsheet,
+    rows: Range<u32>,
+}
+
+impl embedded_graphics::Drawable for CheatsheetPage<'_> {
+    type Color = pixelcolor::Gray8;
+
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        let rows = self.rows.clone();
+        // Borrowed for the images of the image cache, see [Cheatsheets::image]
+        let image = match self.sheet.image.decompressed() {
+            Ok(image) => image,
+            Err(e) => {
+                error!("Decompressing cheatsheet image failed, Err: {e:?}");
+                return Ok(());
+            }
+        };
+        match (image.format, image.byte_order) {
             (pb_cheatsheet_com::ImageFormat::Gray8, pb_cheatsheet_com::ByteOrder::LittleEndian) => {
-                let raw_image = convert_image_to_eg_bw_le(&self.image);
-                let image = Image::with_center(&raw_image, target_center);
-                image.draw(target)?;
+                let raw_image = convert_image_to_eg_bw_le(&image);
+                draw_image_page(&raw_image, rows.clone(), target)?;
             }
             (pb_cheatsheet_com::ImageFormat::Gray8, pb_cheatsheet_com::ByteOrder::BigEndian) => {
-                let raw_image = convert_image_to_eg_bw_be(&self.image);
-                let image = Image::with_center(&raw_image, target_center);
-                image.draw(target)?;
+                let raw_image = convert_image_to_eg_bw_be(&image);
+                draw_image_page(&raw_image, rows.clone(), target)?;
+            }
+            (pb_cheatsheet_com::ImageFormat::Gray1, _) => {
+                let raw_image = convert_image_to_eg_binary(&image);
+                draw_image_page(&raw_image, rows.clone(), &mut target.color_converted())?;
+            }
+            (pb_cheatsheet_com::ImageFormat::Gray4, _) => {
+                let raw_image = convert_image_to_eg_gray4(&image);
+                draw_image_page(&raw_image, rows.clone(), &mut target.color_converted())?;
+            }
+            (format, byte_order) => {
+                error!(
+                    "Drawing cheatsheet image with format '{format:?}' and byte order '{byte_order:?}' is not supported"
+                );
             }
-            _ => unimplemented!(),
         }
         Ok(())
     }
 }
 
+fn draw_image_page<I, D>(image: &I, rows: Range<u32>, target: &mut D) -> Result<(), D::Error>
+where
+    I: ImageDrawable,
+    D: DrawTarget<Color = I::Color>,
+{
+    let target_bounding_box = target.bounding_box();
+    let image_size = image.bounding_box().size;
+    if image_size.height <= target_bounding_box.size.height && rows == (0..image_size.height) {
+        Image::with_center(image, target_bounding_box.center()).draw(target)?;
+        return Ok(());
+    }
+    let page_area = Rectangle::new(
+        Point::new(0, rows.start as i32),
+        Size::new(image_size.width, rows.len() as u32),
+    );
+    let top_left = Point::new(
+        target_bounding_box.center().x - image_size.width as i32 / 2,
+        target_bounding_box.top_left.y,
+    );
+    Image::new(&image.sub_image(&page_area), top_left).draw(target)
+}
+
 fn convert_image_to_eg_bw_le(
     image: &pb_cheatsheet_com::CheatsheetImage,
 ) -> ImageRaw<'_, pixelcolor::Gray8, pixelcolor::raw::LittleEndian> {
@@ -333,3 +1554,433 @@ fn convert_image_to_eg_bw_be(
 ) -> ImageRaw<'_, pixelcolor::Gray8, pixelcolor::raw::BigEndian> {
     embedded_graphics::image::ImageRaw::new(&image.data, image.width)
 }
+
+fn convert_image_to_eg_binary(
+    image: &pb_cheatsheet_com::CheatsheetImage,
+) -> ImageRaw<'_, pixelcolor::BinaryColor> {
+    embedded_graphics::image::ImageRaw::new(&image.data, image.width)
+}
+
+fn convert_image_to_eg_gray4(
+    image: &pb_cheatsheet_com::CheatsheetImage,
+) -> ImageRaw<'_, pixelcolor::Gray4> {
+    embedded_graphics::image::ImageRaw::new(&image.data, image.width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(tag: &str) -> TagExpr {
+        TagExpr::Tag(tag.to_string())
+    }
+
+    fn not(expr: TagExpr) -> TagExpr {
+        TagExpr::Not(Box::new(expr))
+    }
+
+    fn test_sheet() -> Cheatsheet {
+        Cheatsheet {
+            image: pb_cheatsheet_com::CheatsheetImage {
+                format: ImageFormat::Gray8,
+                byte_order: pb_cheatsheet_com::ByteOrder::LittleEndian,
+                width: 2,
+                height: 3,
+                data: vec![0; 6],
+            },
+        }
+    }
+
+    fn window(wm_class: &str, wm_class_instance: &str, title: &str) -> FocusedWindowInfo {
+        FocusedWindowInfo {
+            title: title.to_string(),
+            wm_class: wm_class.to_string(),
+            wm_class_instance: wm_class_instance.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn rule(name: &str, priority: i32, tags: &[&str]) -> MatchRule {
+        MatchRule {
+            name: name.to_string(),
+            wm_class: None,
+            wm_class_instance: None,
+            title: None,
+            priority,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    fn glob(pattern: &str) -> Option<Pattern> {
+        Some(Pattern::Glob(pattern.to_string()))
+    }
+
+    fn regex(pattern: &str) -> Option<Pattern> {
+        Some(Pattern::Regex(pattern.to_string()))
+    }
+
+    fn parse(selector: &str) -> TagExpr {
+        TagSelector::parse(selector).unwrap().expr
+    }
+
+    fn parse_err(selector: &str) -> String {
+        match TagSelector::parse(selector) {
+            Err(ComError::InvalidSelector { reason, .. }) => reason,
+            res => panic!("Expected invalid selector for '{selector}', got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn selector_precedence() {
+        assert_eq!(
+            parse("a | b & !c"),
+            TagExpr::Or(vec![tag("a"), TagExpr::And(vec![tag("b"), not(tag("c"))])])
+        );
+        assert_eq!(
+            parse("!a & b | c"),
+            TagExpr::Or(vec![TagExpr::And(vec![not(tag("a")), tag("b")]), tag("c")])
+        );
+        assert_eq!(
+            parse("a & b & c"),
+            TagExpr::And(vec![tag("a"), tag("b"), tag("c")])
+        );
+    }
+
+    #[test]
+    fn selector_parentheses() {
+        assert_eq!(
+            parse("(a | b) & c"),
+            TagExpr::And(vec![TagExpr::Or(vec![tag("a"), tag("b")]), tag("c")])
+        );
+        assert_eq!(
+            parse("!(a & b)"),
+            not(TagExpr::And(vec![tag("a"), tag("b")]))
+        );
+        assert_eq!(parse(" ( ( vim ) ) "), tag("vim"));
+    }
+
+    #[test]
+    fn selector_matches() {
+        let selector = TagSelector::parse("vim & !neovim | (git & terminal)").unwrap();
+        let active = |tags: &'static [&'static str]| move |tag: &str| tags.contains(&tag);
+        assert!(selector.matches(&active(&["vim"])));
+        assert!(!selector.matches(&active(&["vim", "neovim"])));
+        assert!(selector.matches(&active(&["neovim", "git", "terminal"])));
+        assert!(!selector.matches(&active(&["git"])));
+        assert_eq!(selector.tags(), vec!["vim", "neovim", "git", "terminal"]);
+        assert_eq!(selector.to_string(), "vim & !neovim | (git & terminal)");
+    }
+
+    #[test]
+    fn selector_errors() {
+        assert_eq!(parse_err(""), "Expected tag at the end");
+        assert_eq!(parse_err("a &"), "Expected tag at the end");
+        assert_eq!(parse_err("& a"), "Expected tag at position 0, found '&'");
+        assert_eq!(parse_err("(a | b"), "Unclosed '(' at position 0");
+        assert_eq!(parse_err("a)"), "Unexpected ')' at position 1");
+        assert_eq!(parse_err("a b"), "Unexpected 'b' at position 2");
+    }
+
+    #[test]
+    fn selector_depth_limit() {
+        let max = SelectorParser::MAX_DEPTH;
+        assert_eq!(parse(&format!("{}a", "!".repeat(max))), {
+            (0..max).fold(tag("a"), |expr, _| not(expr))
+        });
+        let nested = format!("{}a{}", "(".repeat(max), ")".repeat(max));
+        assert_eq!(parse(&nested), tag("a"));
+
+        assert_eq!(
+            parse_err(&format!("{}a", "!".repeat(max + 1))),
+            format!("Nested deeper than {max} levels at position {max}")
+        );
+        assert!(parse_err(&"!".repeat(100_000)).starts_with("Nested deeper"));
+        assert!(parse_err(&"(".repeat(100_000)).starts_with("Nested deeper"));
+        // Chains don't nest
+        let chain = vec!["a"; 100_000].join(" & ");
+        assert!(TagSelector::parse(&chain).is_ok());
+    }
+
+    #[tokio::test]
+    async fn recovers_sheets_when_index_is_corrupt() {
+        let dir =
+            std::env::temp_dir().join(format!("pb-cheatsheet-recover-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        let blobs_path = dir.join(BLOBS_SUBFOLDER);
+        fs::create_dir_all(&blobs_path).await.unwrap();
+        fs::write(
+            blobs_path.join("abc.cs"),
+            postcard::to_stdvec(&test_sheet()).unwrap(),
+        )
+        .await
+        .unwrap();
+        fs::write(dir.join(LIBRARY_INDEX_FILE_NAME), b"{\"version\":1,\"shee")
+            .await
+            .unwrap();
+
+        let cheatsheets = Cheatsheets::load_from_path(&dir).await.unwrap();
+        let metadata = &cheatsheets.sheets["recovered-abc"];
+        assert_eq!(metadata.content_hash, "abc");
+        assert_eq!((metadata.width, metadata.height), (2, 3));
+        assert!(cheatsheets.dirty.index);
+        assert!(cheatsheets.dirty.removed_blobs.is_empty());
+        assert!(!dir.join(LIBRARY_INDEX_FILE_NAME).exists());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn images_pinned_until_written() {
+        let base_path = Path::new("/nonexistent");
+        let mut cheatsheets = Cheatsheets::default();
+        let metadata = CheatsheetMetadata {
+            content_hash: "abc".to_string(),
+            ..Default::default()
+        };
+        cheatsheets.insert_sheet(test_sheet(), "a".to_string(), metadata);
+        let (file_op_tx, mut file_op_rx) = mpsc::unbounded_channel();
+        let mut dispatch_save = |cheatsheets: &mut Cheatsheets| {
+            cheatsheets
+                .dispatch_save(base_path, file_op_tx.clone())
+                .unwrap();
+            let Ok(FileOp::Save { written, .. }) = file_op_rx.try_recv() else {
+                panic!("Expected a dispatched save");
+            };
+            written
+        };
+
+        let written = dispatch_save(&mut cheatsheets);
+        assert!(!cheatsheets.is_dirty());
+        cheatsheets.poll_saves();
+        assert!(cheatsheets.image(base_path, "abc").is_ok());
+
+        written.send(false).unwrap();
+        cheatsheets.poll_saves();
+        assert!(cheatsheets.dirty.blobs.contains_key("abc"));
+        assert!(cheatsheets.dirty.index);
+        assert!(cheatsheets.image(base_path, "abc").is_ok());
+
+        let written = dispatch_save(&mut cheatsheets);
+        written.send(true).unwrap();
+        cheatsheets.poll_saves();
+        assert!(!cheatsheets.is_dirty());
+        assert!(cheatsheets.pending_saves.is_empty());
+        // Still cached from drawing it before
+        assert!(cheatsheets.image(base_path, "abc").is_ok());
+    }
+
+    #[test]
+    fn images_cached_decompressed() {
+        let base_path = Path::new("/nonexistent");
+        let mut cheatsheets = Cheatsheets::default();
+        let sheet = Cheatsheet::compressed(test_sheet().image).unwrap();
+        assert!(sheet.image.format.is_compressed());
+        let metadata = CheatsheetMetadata {
+            content_hash: "abc".to_string(),
+            ..Default::default()
+        };
+        cheatsheets.insert_sheet(sheet, "a".to_string(), metadata);
+
+        let image = cheatsheets.image(base_path, "abc").unwrap();
+        assert_eq!(image.image.format, ImageFormat::Gray8);
+        assert_eq!(image.image.data, test_sheet().image.data);
+        // The unsaved blob stays compressed for writing it
+        assert!(cheatsheets.dirty.blobs["abc"].image.format.is_compressed());
+        assert!(Arc::ptr_eq(
+            &image,
+            &cheatsheets.image(base_path, "abc").unwrap()
+        ));
+    }
+
+    #[test]
+    fn glob_patterns_match_entire_string() {
+        let compiled = CompiledMatchRule::new(MatchRule {
+            wm_class: glob("firefox"),
+            title: glob("*GitHub*"),
+            ..rule("github", 0, &["git"])
+        })
+        .unwrap();
+        assert!(compiled.matches(&window("firefox", "Navigator", "Pull requests · GitHub")));
+        assert!(!compiled.matches(&window("firefox-esr", "Navigator", "GitHub")));
+        assert!(!compiled.matches(&window("firefox", "Navigator", "GitLab")));
+
+        let compiled = CompiledMatchRule::new(MatchRule {
+            wm_class_instance: glob("term?"),
+            ..rule("instance", 0, &["shell"])
+        })
+        .unwrap();
+        assert!(compiled.matches(&window("kitty", "term1", "")));
+        assert!(!compiled.matches(&window("kitty", "term12", "")));
+    }
+
+    #[test]
+    fn regex_patterns_match_anywhere() {
+        let compiled = CompiledMatchRule::new(MatchRule {
+            wm_class: regex("^(kitty|foot)$"),
+            title: regex(r"\bn?vim\b"),
+            ..rule("vim", 10, &["vim"])
+        })
+        .unwrap();
+        assert!(compiled.matches(&window("kitty", "kitty", "nvim src/main.rs")));
+        assert!(compiled.matches(&window("foot", "foot", "~ - vim")));
+        assert!(!compiled.matches(&window("foot", "foot", "vimdiff a b")));
+        assert!(!compiled.matches(&window("alacritty", "alacritty", "vim")));
+
+        assert!(matches!(
+            CompiledMatchRule::new(MatchRule {
+                title: regex("(unclosed"),
+                ..rule("invalid", 0, &[])
+            }),
+            Err(ComError::InvalidPattern { .. })
+        ));
+    }
+
+    #[test]
+    fn window_tags_take_highest_priority() {
+        let mut cheatsheets = Cheatsheets::default();
+        cheatsheets.add_wm_class_tags("kitty", HashSet::from(["shell".to_string()]));
+        for rule in [
+            MatchRule {
+                title: regex("vim"),
+                ..rule("vim", 10, &["vim", "shell"])
+            },
+            MatchRule {
+                title: glob("*git*"),
+                ..rule("git", 5, &["git", "vim"])
+            },
+            MatchRule {
+                wm_class: glob("firefox"),
+                ..rule("browser", 20, &["web"])
+            },
+        ] {
+            cheatsheets.set_match_rule(rule).unwrap();
+        }
+
+        let tags = cheatsheets.window_tags(&window("kitty", "kitty", "nvim .git/config"));
+        assert_eq!(
+            tags,
+            HashMap::from([("shell", 10), ("vim", 10), ("git", 5)])
+        );
+        let tags = cheatsheets.window_tags(&window("kitty", "kitty", "bash"));
+        assert_eq!(tags, HashMap::from([("shell", 0)]));
+    }
+
+    #[test]
+    fn sheets_for_window_ordering() {
+        let mut cheatsheets = Cheatsheets::default();
+        cheatsheets.add_wm_class_tags(
+            "kitty",
+            HashSet::from(["shell".to_string(), "git".to_string()]),
+        );
+        cheatsheets
+            .set_match_rule(MatchRule {
+                title: glob("*vim*"),
+                ..rule("vim", 10, &["vim"])
+            })
+            .unwrap();
+        let sheets: [(&str, &[&str], i32, Option<&str>); 8] = [
+            ("shell-b", &["shell"], 0, None),
+            ("git-a", &["git"], 0, None),
+            ("selected", &[], 0, Some("!web")),
+            ("web", &["web"], 100, None),
+            ("vim-selected", &[], 0, Some("vim & !neovim")),
+            ("shell-high", &["shell"], 5, None),
+            ("vim", &["vim"], 0, None),
+            ("both", &["shell", "git"], 0, None),
+        ];
+        for (name, tags, priority, selector) in sheets {
+            let metadata = CheatsheetMetadata {
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                // Identifies the sheet in the returned metadata
+                content_hash: name.to_string(),
+                priority,
+                selector: selector.map(|selector| TagSelector::parse(selector).unwrap()),
+                ..Default::default()
+            };
+            cheatsheets.insert_sheet(test_sheet(), name.to_string(), metadata);
+        }
+
+        let order = |window: &FocusedWindowInfo| {
+            cheatsheets
+                .sheets_for_window(window)
+                .into_iter()
+                .map(|metadata| metadata.content_hash.as_str())
+                .collect::<Vec<&str>>()
+        };
+        // Shared tags, then rule priority, then sheet priority, then name
+        assert_eq!(
+            order(&window("kitty", "kitty", "nvim")),
+            [
+                "both",
+                "vim",
+                "vim-selected",
+                "shell-high",
+                "git-a",
+                "shell-b",
+                "selected"
+            ]
+        );
+        assert_eq!(
+            order(&window("kitty", "kitty", "bash")),
+            ["both", "shell-high", "git-a", "shell-b", "selected"]
+        );
+    }
+
+    #[tokio::test]
+    async fn migrates_legacy_layout() {
+        let dir =
+            std::env::temp_dir().join(format!("pb-cheatsheet-migrate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        let legacy_sheet = postcard::to_stdvec(&test_sheet()).unwrap();
+        fs::write(dir.join("git.cs"), legacy_sheet).await.unwrap();
+        fs::write(dir.join("git-metadata.json"), r#"{"tags":["git"]}"#)
+            .await
+            .unwrap();
+        fs::write(dir.join("broken.cs"), b"\xff").await.unwrap();
+        fs::write(dir.join("broken-metadata.json"), r#"{"tags":[]}"#)
+            .await
+            .unwrap();
+        fs::write(dir.join("wm_class_tags.json"), r#"{"kitty":["git"]}"#)
+            .await
+            .unwrap();
+
+        let mut cheatsheets = Cheatsheets::load_from_path(&dir).await.unwrap();
+        assert_eq!(cheatsheets.sheets.keys().collect::<Vec<_>>(), ["git"]);
+        assert!(cheatsheets.dirty.index);
+        assert_eq!(cheatsheets.dirty.legacy_files.len(), 3);
+        assert_eq!(
+            std::fs::read_dir(dir.join(QUARANTINE_SUBFOLDER))
+                .unwrap()
+                .count(),
+            2
+        );
+
+        let (file_op_tx, mut file_op_rx) = mpsc::unbounded_channel();
+        cheatsheets.dispatch_save(&dir, file_op_tx).unwrap();
+        let Ok(FileOp::Save {
+            writes, removes, ..
+        }) = file_op_rx.try_recv()
+        else {
+            panic!("Expected a dispatched save");
+        };
+        assert!(crate::save_files(&writes, &removes).await);
+
+        let mut migrated = Cheatsheets::load_from_path(&dir).await.unwrap();
+        assert!(!migrated.is_dirty());
+        let metadata = migrated.sheets["git"].clone();
+        assert_eq!(metadata.tags, HashSet::from(["git".to_string()]));
+        assert_eq!(metadata.format, ImageFormat::Gray8Deflate);
+        assert_eq!(
+            migrated.wm_class_tags["kitty"],
+            HashSet::from(["git".to_string()])
+        );
+        for legacy_file in ["git.cs", "git-metadata.json", "wm_class_tags.json"] {
+            assert!(!dir.join(legacy_file).exists());
+        }
+        let image = migrated.image(&dir, &metadata.content_hash).unwrap();
+        assert_eq!(image.image.data, test_sheet().image.data);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
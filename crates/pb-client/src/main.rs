@@ -1,8 +1,10 @@
 pub(crate) mod cheatsheets;
+pub(crate) mod pairing;
+pub(crate) mod upload;
 pub(crate) mod wifi;
 
 use anyhow::Context;
-use cheatsheets::{Cheatsheet, Cheatsheets};
+use cheatsheets::{Cheatsheet, CheatsheetMetadata, Cheatsheets, TagSelector};
 use core::convert::Infallible;
 use core::fmt::Display;
 use core::net::{Ipv4Addr, SocketAddr};
@@ -13,21 +15,30 @@ use embedded_graphics::pixelcolor::{self, Gray8};
 use embedded_graphics::prelude::*;
 use embedded_graphics::primitives::{PrimitiveStyle, Rectangle, StyledDrawable};
 use embedded_graphics::text::Text;
-use futures::{future, prelude::*};
+use futures::{future, prelude::*, stream};
 use inkview::bindings::Inkview;
 use inkview_eg::InkviewDisplay;
+use pairing::Pairings;
+use pb_cheatsheet_com::auth::{self, PairingStore};
+use pb_cheatsheet_com::discovery::Advertisement;
+use pb_cheatsheet_com::tls;
+use pb_cheatsheet_com::transport;
 use pb_cheatsheet_com::{
-    CheatsheetImage, FocusedWindowInfo, Info, ScreenOrientation, TagsEither, World, RPC_PORT,
+    BuildInfo, Capabilities, CheatsheetImage, ComError, Feature, FocusedWindowInfo, Info,
+    MatchRule, ScreenOrientation, TagsEither, UploadHeader, UploadSession, UploadTarget, World,
+    PROTOCOL_VERSION, RPC_PORT,
 };
 use std::cell::OnceCell;
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
-use std::time::Instant;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 use tarpc::context::Context as TarpcContext;
 use tarpc::server::{self, Channel};
-use tarpc::tokio_serde::formats::Json;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
@@ -36,52 +47,125 @@ use tracing::level_filters::LevelFilter;
 use tracing::{debug, error, info, warn};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::fmt::writer::MakeWriterExt;
+use upload::Uploads;
 
 const CLIENT_DATA_DIR: &str = "/mnt/ext1/applications/pb-cheatsheet-data";
 const CHEATSHEETS_SUBFOLDER: &str = "cheatsheets";
 const LOG_FILE_NAME: &str = "pb-cheatsheet.log";
+const PAIRINGS_FILE_NAME: &str = "pairings.json";
+const TLS_CERT_FILE_NAME: &str = "tls-cert.der";
+const TLS_KEY_FILE_NAME: &str = "tls-key.der";
+
+type ReplyTx<T = ()> = oneshot::Sender<Result<T, ComError>>;
 
 #[derive(Debug)]
 enum Msg {
     InkviewEvent(inkview::Event),
     FocusedWindow(FocusedWindowInfo),
-    GetInfo(oneshot::Sender<Info>),
+    /// The one-time code to display while a host is pairing, `None` when pairing ended.
+    PairingCode(Option<String>),
+    GetInfo(ReplyTx<Info>),
     UploadCheatsheet {
         image: CheatsheetImage,
         name: String,
         tags: HashSet<String>,
+        page_breaks: Vec<u32>,
+        reply_tx: ReplyTx,
     },
     RemoveCheatsheet {
         name: String,
+        reply_tx: ReplyTx,
     },
     UploadScreenshot {
         screenshot: CheatsheetImage,
         name: Option<String>,
+        reply_tx: ReplyTx,
+    },
+    ClearScreenshot {
+        reply_tx: ReplyTx,
     },
-    ClearScreenshot,
     AddCheatsheetTags {
         name: String,
         tags: HashSet<String>,
+        reply_tx: ReplyTx,
     },
     RemoveCheatsheetTags {
         name: String,
         either: TagsEither,
+        reply_tx: ReplyTx,
+    },
+    SetCheatsheetPriority {
+        name: String,
+        priority: i32,
+        reply_tx: ReplyTx,
+    },
+    SetCheatsheetSelector {
+        name: String,
+        selector: Option<String>,
+        reply_tx: ReplyTx,
     },
     AddWmClassTags {
         wm_class: String,
         tags: HashSet<String>,
+        reply_tx: ReplyTx,
     },
     RemoveWmClassTags {
         wm_class: String,
         either: TagsEither,
+        reply_tx: ReplyTx,
+    },
+    GetMatchRules(ReplyTx<Vec<MatchRule>>),
+    SetMatchRule {
+        rule: MatchRule,
+        reply_tx: ReplyTx,
+    },
+    RemoveMatchRule {
+        name: String,
+        reply_tx: ReplyTx,
     },
 }
 
+impl Msg {
+    /// The reply sender of messages changing the cheatsheet library, otherwise the message itself.
+    #[allow(clippy::result_large_err)]
+    fn into_library_change(self) -> Result<ReplyTx, Self> {
+        match self {
+            Msg::UploadCheatsheet { reply_tx, .. }
+            | Msg::RemoveCheatsheet { reply_tx, .. }
+            | Msg::AddCheatsheetTags { reply_tx, .. }
+            | Msg::RemoveCheatsheetTags { reply_tx, .. }
+            | Msg::SetCheatsheetPriority { reply_tx, .. }
+            | Msg::SetCheatsheetSelector { reply_tx, .. }
+            | Msg::AddWmClassTags { reply_tx, .. }
+            | Msg::RemoveWmClassTags { reply_tx, .. }
+            | Msg::SetMatchRule { reply_tx, .. }
+            | Msg::RemoveMatchRule { reply_tx, .. } => Ok(reply_tx),
+            msg => Err(msg),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct TarpcServer {
-    #[allow(unused)]
     peer_addr: SocketAddr,
     msg_tx: mpsc::UnboundedSender<Msg>,
+    /// Shared between all channels.
+    uploads: Arc<Mutex<Uploads>>,
+}
+
+impl TarpcServer {
+    /// Sends a message to the message handler task and awaits its reply.
+    async fn request<T>(&self, msg: impl FnOnce(ReplyTx<T>) -> Msg) -> Result<T, ComError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if self.msg_tx.send(msg(reply_tx)).is_err() {
+            error!("Sending received RPC request from handler failed, receiving half closed");
+            return Err(ComError::Unavailable);
+        }
+        reply_rx.await.unwrap_or_else(|_| {
+            error!("Receiving reply to RPC request failed, sender half dropped");
+            Err(ComError::Unavailable)
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
@@ -124,6 +208,20 @@ impl UiMode {
     }
 }
 
+/// Operations on the files in the data directory, executed in order by the file operation task.
+#[derive(Debug)]
+enum FileOp {
+    /// Atomically replaces the files with their data in order, then removes the files that exist.
+    ///
+    /// Stops at the first failed write, so files are only removed when the ones superseding them were written.
+    /// Whether all files were written is sent back.
+    Save {
+        writes: Vec<(PathBuf, Vec<u8>)>,
+        removes: Vec<PathBuf>,
+        written: oneshot::Sender<bool>,
+    },
+}
+
 #[derive(Debug, Default)]
 struct UiState {
     pub mode: UiMode,
@@ -132,11 +230,16 @@ struct UiState {
     pub screen_height: u32,
     pub screen_orientation: ScreenOrientation,
     pub cheatsheets: Cheatsheets,
+    /// Why loading the cheatsheets failed. The library is then read-only and never saved,
+    /// so it does not overwrite what is stored.
+    pub library_error: Option<String>,
     /// Current pages for wm class
     pub current_page: HashMap<String, usize>,
     pub manual_mode_current_page: usize,
     pub screenshot: Option<(Cheatsheet, Option<String>)>,
+    pub screenshot_current_page: usize,
     pub show_stats: bool,
+    pub pairing_code: Option<String>,
     pub button_prev_pressed_time: Option<Instant>,
     pub button_next_pressed_time: Option<Instant>,
 }
@@ -153,15 +256,37 @@ impl UiState {
             screen_height: 0,
             screen_orientation: ScreenOrientation::Portrait0Deg,
             cheatsheets,
+            library_error: None,
             current_page: HashMap::default(),
             screenshot: None,
+            screenshot_current_page: 0,
             show_stats: false,
+            pairing_code: None,
             button_prev_pressed_time: None,
             button_next_pressed_time: None,
             manual_mode_current_page: 0,
         })
     }
 
+    /// Keeps the current pages in range after cheatsheets were removed.
+    pub fn clamp_current_pages(&mut self) {
+        let last_page = self
+            .cheatsheets
+            .n_pages(self.screen_height)
+            .saturating_sub(1);
+        self.manual_mode_current_page = self.manual_mode_current_page.min(last_page);
+        let last_window_page = self
+            .cheatsheets
+            .window_n_pages(&self.focused_window_info, self.screen_height)
+            .saturating_sub(1);
+        if let Some(page) = self
+            .current_page
+            .get_mut(&self.focused_window_info.wm_class)
+        {
+            *page = (*page).min(last_window_page);
+        }
+    }
+
     pub fn update(&mut self, _iv: &'static Inkview, display: &InkviewDisplay) {
         self.screen_width = display.iv_screen_ref().width() as u32;
         self.screen_height = display.iv_screen_ref().height() as u32;
@@ -205,7 +330,16 @@ impl UiState {
                     false
                 }
             }
-            UiMode::Screenshot => false,
+            UiMode::Screenshot => {
+                let current_page = self.screenshot_current_page;
+                let prev_page = self.screenshot_current_page.saturating_sub(1);
+                if prev_page != current_page {
+                    self.screenshot_current_page = prev_page;
+                    true
+                } else {
+                    false
+                }
+            }
         }
     }
 
@@ -215,7 +349,7 @@ impl UiState {
     pub fn next_page(&mut self) -> bool {
         match self.mode {
             UiMode::Manual => {
-                let pages = self.cheatsheets.sheets_iter().count();
+                let pages = self.cheatsheets.n_pages(self.screen_height);
                 let current_page = self.manual_mode_current_page;
                 let next_page = self
                     .manual_mode_current_page
@@ -229,9 +363,9 @@ impl UiState {
                 }
             }
             UiMode::AutomaticWmClass => {
-                let n_sheets = self
+                let n_pages = self
                     .cheatsheets
-                    .wm_class_n_sheets(&self.focused_window_info.wm_class);
+                    .window_n_pages(&self.focused_window_info, self.screen_height);
                 let current_page = if let Some(p) = self
                     .current_page
                     .get_mut(&self.focused_window_info.wm_class)
@@ -246,7 +380,7 @@ impl UiState {
                 };
                 let next_page = current_page
                     .saturating_add(1)
-                    .min(n_sheets.saturating_sub(1));
+                    .min(n_pages.saturating_sub(1));
                 if next_page != *current_page {
                     *current_page = next_page;
                     true
@@ -254,7 +388,24 @@ impl UiState {
                     false
                 }
             }
-            UiMode::Screenshot => false,
+            UiMode::Screenshot => {
+                let pages = self
+                    .screenshot
+                    .as_ref()
+                    .map(|(screenshot, _)| screenshot.n_pages(&[], self.screen_height))
+                    .unwrap_or(1);
+                let current_page = self.screenshot_current_page;
+                let next_page = self
+                    .screenshot_current_page
+                    .saturating_add(1)
+                    .min(pages.saturating_sub(1));
+                if next_page != current_page {
+                    self.screenshot_current_page = next_page;
+                    true
+                } else {
+                    false
+                }
+            }
         }
     }
 
@@ -271,6 +422,7 @@ impl UiState {
         const FILL_WHITE: PrimitiveStyle<Gray8> = PrimitiveStyle::with_fill(Gray8::new(0xff));
         let display_bounding_box = display.bounding_box();
         let display_center = display_bounding_box.center();
+        let page_height = display_bounding_box.size.height;
 
         fn draw_ui_info(
             display: &mut impl DrawTarget<Color = pixelcolor::Gray8, Error = Infallible>,
@@ -301,12 +453,26 @@ impl UiState {
         match self.mode {
             UiMode::Manual => {
                 let current_page = self.manual_mode_current_page;
-                if let Some((name, (_metadata, sheet))) =
-                    self.cheatsheets.sheets_iter().nth(current_page)
-                {
-                    sheet.draw(display)?;
+                let found = cheatsheets::find_page(
+                    self.cheatsheets.sheets_iter(),
+                    |(_name, metadata)| *metadata,
+                    current_page,
+                    page_height,
+                )
+                .map(|((name, metadata), rows)| {
+                    (name.clone(), metadata.content_hash.clone(), rows)
+                });
+                if let Some((name, content_hash, rows)) = found {
+                    match self.cheatsheets.image(
+                        PathBuf::from(CLIENT_DATA_DIR).join(CHEATSHEETS_SUBFOLDER),
+                        &content_hash,
+                    ) {
+                        Ok(sheet) => sheet.page(rows).draw(display)?,
+                        Err(e) => error!("Loading image of cheatsheet '{name}' failed, Err: {e:?}"),
+                    }
 
-                    let cheatsheet_name_text = Text::new(name, Point::new(10, 30), TEXT_STYLE_HUGE);
+                    let cheatsheet_name_text =
+                        Text::new(&name, Point::new(10, 30), TEXT_STYLE_HUGE);
                     let cheatsheet_name_text_boundings_box = cheatsheet_name_text.bounding_box();
                     cheatsheet_name_text_boundings_box
                         .into_styled(FILL_WHITE)
@@ -328,16 +494,29 @@ impl UiState {
                             .insert(self.focused_window_info.wm_class.clone(), page);
                         page
                     };
-                if let Some((_metadata, sheet)) = self
-                    .cheatsheets
-                    .sheets_for_wm_class(&self.focused_window_info.wm_class)
-                    .into_iter()
-                    .nth(current_page)
-                {
-                    sheet.draw(display)?;
+                let found = cheatsheets::find_page(
+                    self.cheatsheets
+                        .sheets_for_window(&self.focused_window_info),
+                    |metadata| *metadata,
+                    current_page,
+                    page_height,
+                )
+                .map(|(metadata, rows)| (metadata.content_hash.clone(), rows));
+                if let Some((content_hash, rows)) = found {
+                    match self.cheatsheets.image(
+                        PathBuf::from(CLIENT_DATA_DIR).join(CHEATSHEETS_SUBFOLDER),
+                        &content_hash,
+                    ) {
+                        Ok(sheet) => sheet.page(rows).draw(display)?,
+                        Err(e) => error!("Loading cheatsheet image failed, Err: {e:?}"),
+                    }
                 } else {
+                    let placeholder = match self.library_error {
+                        Some(_) => "LIBRARY UNAVAILABLE",
+                        None => "NO CHEATSHEET FOUND",
+                    };
                     let placeholder_text = Text::with_alignment(
-                        "NO CHEATSHEET FOUND",
+                        placeholder,
                         display_center,
                         TEXT_STYLE_HUGE,
                         embedded_graphics::text::Alignment::Center,
@@ -358,7 +537,12 @@ impl UiState {
             }
             UiMode::Screenshot => {
                 if let Some((screenshot, _name)) = self.screenshot.as_ref() {
-                    screenshot.draw(display)?;
+                    if let Some(page) = screenshot
+                        .pages(&[], page_height)
+                        .nth(self.screenshot_current_page)
+                    {
+                        page.draw(display)?;
+                    }
                     // TODO: draw name
                 } else {
                     let placeholder_text = Text::with_alignment(
@@ -380,7 +564,7 @@ impl UiState {
                     placeholder_text.draw(display)?;
                 }
 
-                draw_ui_info(display, self.mode, 0)?;
+                draw_ui_info(display, self.mode, self.screenshot_current_page)?;
             }
         }
 
@@ -393,18 +577,26 @@ impl UiState {
     orientation:        {}
 
 ### Focused Window Info ###
+    title:              {}
     wm_class:           {}
     wm_class_instance:  {}
     pid:                {}
     focus:              {}
+    tags:               {}
 ",
                 self.screen_width,
                 self.screen_height,
                 self.screen_orientation,
+                self.focused_window_info.title,
                 self.focused_window_info.wm_class,
                 self.focused_window_info.wm_class_instance,
                 self.focused_window_info.pid,
                 self.focused_window_info.focus,
+                self.cheatsheets
+                    .window_tags(&self.focused_window_info)
+                    .into_keys()
+                    .collect::<Vec<&str>>()
+                    .join(", "),
             );
             let stats_text = Text::new(&stats, Point::new(10, 40), TEXT_STYLE_NORMAL);
             let stats_text_bounding_box = stats_text.bounding_box();
@@ -412,118 +604,245 @@ impl UiState {
             stats_text.draw(display)?;
         }
 
+        if let Some(code) = &self.pairing_code {
+            let display_center = display.bounding_box().center();
+            let pairing = format!("PAIRING CODE\n\n{code}");
+            let pairing_text = Text::with_alignment(
+                &pairing,
+                display_center,
+                TEXT_STYLE_HUGE,
+                embedded_graphics::text::Alignment::Center,
+            );
+            let pairing_text_bounding_box = Rectangle::new(
+                pairing_text.bounding_box().top_left - Point::new(20, 20),
+                pairing_text.bounding_box().size + Size::new(40, 40),
+            );
+            pairing_text_bounding_box
+                .into_styled(FILL_WHITE)
+                .draw(display)?;
+            pairing_text_bounding_box
+                .into_styled(STROKE_THIN_BLACK)
+                .draw(display)?;
+            pairing_text.draw(display)?;
+        }
+
         Ok(())
     }
 }
 
 impl World for TarpcServer {
-    async fn focused_window(self, _: TarpcContext, info: FocusedWindowInfo) {
+    async fn handshake(
+        self,
+        _: TarpcContext,
+        protocol_version: u32,
+    ) -> Result<Capabilities, ComError> {
+        if protocol_version != PROTOCOL_VERSION {
+            warn!(
+                "Refusing host '{}' speaking protocol version {protocol_version}, expected {PROTOCOL_VERSION}",
+                self.peer_addr
+            );
+            return Err(ComError::UnsupportedProtocol {
+                version: protocol_version,
+                supported: PROTOCOL_VERSION,
+            });
+        }
+        Ok(capabilities())
+    }
+
+    async fn focused_window(
+        self,
+        _: TarpcContext,
+        info: FocusedWindowInfo,
+    ) -> Result<(), ComError> {
         if self.msg_tx.send(Msg::FocusedWindow(info)).is_err() {
             error!(
                 "Sending received RPC focused window info from handler failed, receiving half closed"
             );
+            return Err(ComError::Unavailable);
         }
+        Ok(())
     }
 
-    async fn get_info(self, _: TarpcContext) -> Info {
-        let (tx, rx) = oneshot::channel();
-        if self.msg_tx.send(Msg::GetInfo(tx)).is_err() {
-            error!(
-                "Sending received RPC get info sender from handler failed, receiving half closed"
-            );
+    async fn get_info(self, _: TarpcContext) -> Result<Info, ComError> {
+        self.request(Msg::GetInfo).await
+    }
+
+    async fn begin_upload(
+        self,
+        _: TarpcContext,
+        target: UploadTarget,
+        header: UploadHeader,
+    ) -> Result<UploadSession, ComError> {
+        if !cheatsheets::SUPPORTED_IMAGE_FORMATS.contains(&header.format) {
+            return Err(ComError::UnsupportedImageFormat {
+                format: header.format,
+            });
         }
-        let Ok(info) = rx.await else {
-            error!("Receiving request info failed, sender half dropped");
-            return Info::default();
-        };
-        info
+        self.uploads
+            .lock()
+            .unwrap()
+            .begin(target, header)
+            .inspect_err(|e| error!("Beginning upload failed, Err: {e}"))
     }
 
-    async fn upload_cheatsheet(
+    async fn upload_chunk(
+        self,
+        _: TarpcContext,
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+        checksum: u32,
+    ) -> Result<u64, ComError> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .chunk(id, offset, data, checksum)
+            .inspect_err(|e| error!("Receiving upload chunk failed, Err: {e}"))
+    }
+
+    async fn commit_upload(self, _: TarpcContext, id: u64) -> Result<(), ComError> {
+        let committed = self.uploads.lock().unwrap().commit(id);
+        let received = committed.inspect_err(|e| error!("Committing upload failed, Err: {e}"))?;
+        // Not holding the lock of the uploads, nor blocking the runtime while validating
+        let (target, image) = tokio::task::spawn_blocking(move || received.validate())
+            .await
+            .map_err(|e| {
+                error!("Validating upload panicked, Err: {e:?}");
+                ComError::Unavailable
+            })?
+            .inspect_err(|e| error!("Committing upload failed, Err: {e}"))?;
+        match target {
+            UploadTarget::Cheatsheet {
+                name,
+                tags,
+                page_breaks,
+            } => {
+                self.request(|reply_tx| Msg::UploadCheatsheet {
+                    image,
+                    name,
+                    tags,
+                    page_breaks,
+                    reply_tx,
+                })
+                .await
+            }
+            UploadTarget::Screenshot { name } => {
+                self.request(|reply_tx| Msg::UploadScreenshot {
+                    screenshot: image,
+                    name,
+                    reply_tx,
+                })
+                .await
+            }
+        }
+    }
+
+    async fn remove_cheatsheet(self, _: TarpcContext, name: String) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::RemoveCheatsheet { name, reply_tx })
+            .await
+    }
+
+    async fn clear_screenshot(self, _: TarpcContext) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::ClearScreenshot { reply_tx })
+            .await
+    }
+
+    async fn add_cheatsheet_tags(
         self,
         _: TarpcContext,
-        image: CheatsheetImage,
         name: String,
         tags: HashSet<String>,
-    ) {
-        if self
-            .msg_tx
-            .send(Msg::UploadCheatsheet { image, name, tags })
-            .is_err()
-        {
-            error!(
-                "Sending received RPC cheatsheet image from handler failed, receiving half closed"
-            );
-        }
+    ) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::AddCheatsheetTags {
+            name,
+            tags,
+            reply_tx,
+        })
+        .await
     }
 
-    async fn remove_cheatsheet(self, _: TarpcContext, name: String) {
-        if self.msg_tx.send(Msg::RemoveCheatsheet { name }).is_err() {
-            error!("Sending remove cheatsheet message failed, receiving half closed");
-        }
+    async fn remove_cheatsheet_tags(
+        self,
+        _: TarpcContext,
+        name: String,
+        either: TagsEither,
+    ) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::RemoveCheatsheetTags {
+            name,
+            either,
+            reply_tx,
+        })
+        .await
     }
 
-    async fn upload_screenshot(
+    async fn set_cheatsheet_priority(
         self,
         _: TarpcContext,
-        screenshot: CheatsheetImage,
-        name: Option<String>,
-    ) {
-        if self
-            .msg_tx
-            .send(Msg::UploadScreenshot { screenshot, name })
-            .is_err()
-        {
-            error!(
-                "Sending received RPC cheatsheet image from handler failed, receiving half closed"
-            );
-        }
+        name: String,
+        priority: i32,
+    ) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::SetCheatsheetPriority {
+            name,
+            priority,
+            reply_tx,
+        })
+        .await
     }
 
-    async fn clear_screenshot(self, _: TarpcContext) {
-        if self.msg_tx.send(Msg::ClearScreenshot).is_err() {
-            error!("Sending received RPC screenshot from handler failed, receiving half closed");
-        }
+    async fn set_cheatsheet_selector(
+        self,
+        _: TarpcContext,
+        name: String,
+        selector: Option<String>,
+    ) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::SetCheatsheetSelector {
+            name,
+            selector,
+            reply_tx,
+        })
+        .await
     }
 
-    async fn add_cheatsheet_tags(self, _: TarpcContext, name: String, tags: HashSet<String>) {
-        if self
-            .msg_tx
-            .send(Msg::AddCheatsheetTags { name, tags })
-            .is_err()
-        {
-            error!("Sending add cheatsheet tags message failed, receiving half closed");
-        }
+    async fn add_wm_class_tags(
+        self,
+        _: TarpcContext,
+        wm_class: String,
+        tags: HashSet<String>,
+    ) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::AddWmClassTags {
+            wm_class,
+            tags,
+            reply_tx,
+        })
+        .await
     }
 
-    async fn remove_cheatsheet_tags(self, _: TarpcContext, name: String, either: TagsEither) {
-        if self
-            .msg_tx
-            .send(Msg::RemoveCheatsheetTags { name, either })
-            .is_err()
-        {
-            error!("Sending remove cheatsheet tags message failed, receiving half closed");
-        }
+    async fn remove_wm_class_tags(
+        self,
+        _: TarpcContext,
+        wm_class: String,
+        either: TagsEither,
+    ) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::RemoveWmClassTags {
+            wm_class,
+            either,
+            reply_tx,
+        })
+        .await
     }
 
-    async fn add_wm_class_tags(self, _: TarpcContext, wm_class: String, tags: HashSet<String>) {
-        if self
-            .msg_tx
-            .send(Msg::AddWmClassTags { wm_class, tags })
-            .is_err()
-        {
-            error!("Sending add wm class tags message failed, receiving half closed");
-        }
+    async fn get_match_rules(self, _: TarpcContext) -> Result<Vec<MatchRule>, ComError> {
+        self.request(Msg::GetMatchRules).await
     }
 
-    async fn remove_wm_class_tags(self, _: TarpcContext, wm_class: String, either: TagsEither) {
-        if self
-            .msg_tx
-            .send(Msg::RemoveWmClassTags { wm_class, either })
-            .is_err()
-        {
-            error!("Sending add wm class tags message failed, receiving half closed");
-        }
+    async fn set_match_rule(self, _: TarpcContext, rule: MatchRule) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::SetMatchRule { rule, reply_tx })
+            .await
+    }
+
+    async fn remove_match_rule(self, _: TarpcContext, name: String) -> Result<(), ComError> {
+        self.request(|reply_tx| Msg::RemoveMatchRule { name, reply_tx })
+            .await
     }
 }
 
@@ -540,21 +859,26 @@ async fn main() -> anyhow::Result<()> {
     // The exit cleanup token is used to block the main loop while cleanup tasks are running.
     let exit_cleanup_token = tokio_util::sync::CancellationToken::new();
     let (msg_tx, msg_rx) = mpsc::unbounded_channel::<Msg>();
-    let (file_save_tx, file_save_rx) = mpsc::unbounded_channel::<(PathBuf, Vec<u8>)>();
+    let (file_op_tx, file_op_rx) = mpsc::unbounded_channel::<FileOp>();
 
-    // File save task
-    let file_save_task = tokio::spawn(async move {
-        if let Err(err) = spawn_file_save_task(file_save_rx).await {
-            error!("File save task failed: {err:?}");
+    // File operation task
+    let file_op_task = tokio::spawn(async move {
+        if let Err(err) = spawn_file_op_task(file_op_rx).await {
+            error!("File operation task failed: {err:?}");
         }
     });
 
     // RPC server task
     let quit_token_c = quit_token.clone();
     let msg_tx_c = msg_tx.clone();
+    let device_name = device_model(iv);
     tokio::spawn(async move {
         tokio::select! {
-            _ = spawn_rpc_server(msg_tx_c) => {}
+            res = spawn_rpc_server(msg_tx_c, device_name) => {
+                if let Err(e) = res {
+                    error!("RPC server failed, Err: {e:?}");
+                }
+            }
             _ = quit_token_c.cancelled() => {}
         }
     });
@@ -565,8 +889,8 @@ async fn main() -> anyhow::Result<()> {
         spawn_msg_handler_task(
             iv,
             msg_rx,
-            file_save_tx,
-            file_save_task,
+            file_op_tx,
+            file_op_task,
             logfile_guard,
             quit_token,
             exit_cleanup_token_c,
@@ -633,57 +957,182 @@ fn setup_tracing() -> anyhow::Result<tracing_appender::non_blocking::WorkerGuard
     Ok(guard)
 }
 
-async fn spawn_file_save_task(
-    mut file_save_rx: UnboundedReceiver<(PathBuf, Vec<u8>)>,
-) -> anyhow::Result<()> {
-    while let Some((file_path, data)) = file_save_rx.recv().await {
-        debug!("Saving file with path '{}'", file_path.display());
-        if let Err(err) = async {
-            fs::create_dir_all(file_path.parent().ok_or_else(|| {
-                anyhow::anyhow!("File '{}' does not have parent", file_path.display())
-            })?)
-            .await?;
-            let mut file = fs::File::create(&file_path).await?;
-            file.write_all(&data).await?;
-            file.flush().await?;
-            Result::<(), anyhow::Error>::Ok(())
+async fn spawn_file_op_task(mut file_op_rx: UnboundedReceiver<FileOp>) -> anyhow::Result<()> {
+    while let Some(op) = file_op_rx.recv().await {
+        match op {
+            FileOp::Save {
+                writes,
+                removes,
+                written,
+            } => {
+                // The receiver is gone when the library was dropped on exit
+                let _ = written.send(save_files(&writes, &removes).await);
+            }
         }
-        .await
-        {
+    }
+    debug!("File operation task finished, sender closed");
+    Ok(())
+}
+
+/// Writes the files in order and then removes the files, see [FileOp::Save].
+///
+/// Returns whether all files were written.
+async fn save_files(writes: &[(PathBuf, Vec<u8>)], removes: &[PathBuf]) -> bool {
+    for (path, data) in writes {
+        debug!("Saving file with path '{}'", path.display());
+        if let Err(err) = write_file_atomic(path, data).await {
             error!(
-                "Saving file '{}' failed, Err: {err:?}'",
-                file_path.display()
+                "Saving file '{}' failed, skipping the remaining file operations, Err: {err:?}",
+                path.display()
             );
+            return false;
         }
     }
-    debug!("File save task finished, sender closed");
+    for path in removes {
+        debug!("Removing file with path '{}'", path.display());
+        if let Err(err) = remove_file_durable(path).await {
+            error!("Removing file '{}' failed, Err: {err:?}", path.display());
+        }
+    }
+    true
+}
+
+/// Replaces the file atomically, so after a power loss it is either the old or the new file.
+///
+/// The data is written and synced to a temporary file next to it first,
+/// which is then renamed. The temporary files of interrupted saves are removed when loading.
+async fn write_file_atomic(file_path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let dir = file_path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("File '{}' does not have parent", file_path.display()))?;
+    let file_name = file_path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("File '{}' does not have a name", file_path.display()))?;
+    fs::create_dir_all(dir).await?;
+
+    let mut tmp_name = file_name.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = dir.join(tmp_name);
+    let mut file = fs::File::create(&tmp_path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp_path, file_path).await?;
+    // Persist the rename itself
+    fs::File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+async fn remove_file_durable(file_path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(file_path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    if let Some(dir) = file_path.parent() {
+        fs::File::open(dir).await?.sync_all().await?;
+    }
     Ok(())
 }
 
-async fn spawn_rpc_server(msg_tx: UnboundedSender<Msg>) -> anyhow::Result<()> {
+/// What this build of the client application supports, reported in the handshake.
+fn capabilities() -> Capabilities {
+    let sdk = if cfg!(feature = "sdk-6-10") {
+        Some("6.10")
+    } else if cfg!(feature = "sdk-6-8") {
+        Some("6.8")
+    } else if cfg!(feature = "sdk-6-5") {
+        Some("6.5")
+    } else if cfg!(feature = "sdk-5-19") {
+        Some("5.19")
+    } else {
+        None
+    };
+    Capabilities {
+        protocol_version: PROTOCOL_VERSION,
+        image_formats: cheatsheets::SUPPORTED_IMAGE_FORMATS.to_vec(),
+        features: vec![
+            Feature::PageBreaks,
+            Feature::ContentHash,
+            Feature::MatchRules,
+        ],
+        build: BuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            sdk: sdk.map(str::to_string),
+            debug: cfg!(debug_assertions),
+        },
+    }
+}
+
+/// The device model, used as name the device is discovered with.
+fn device_model(iv: &Inkview) -> String {
+    let model = unsafe { iv.GetDeviceModel() };
+    if model.is_null() {
+        return "PocketBook".to_string();
+    }
+    unsafe { CStr::from_ptr(model) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+async fn spawn_rpc_server(msg_tx: UnboundedSender<Msg>, device_name: String) -> anyhow::Result<()> {
+    let pairings = Arc::new(
+        Pairings::load(
+            PathBuf::from(CLIENT_DATA_DIR).join(PAIRINGS_FILE_NAME),
+            msg_tx.clone(),
+        )
+        .await?,
+    );
+    let identity = pairing::load_identity(
+        &PathBuf::from(CLIENT_DATA_DIR).join(TLS_CERT_FILE_NAME),
+        &PathBuf::from(CLIENT_DATA_DIR).join(TLS_KEY_FILE_NAME),
+    )?;
+    let acceptor = tls::Acceptor::new(&identity)?;
     let server_addr = (Ipv4Addr::new(0, 0, 0, 0), RPC_PORT);
-    let mut listener = tarpc::serde_transport::tcp::listen(&server_addr, Json::default).await?;
+    let listener = TcpListener::bind(&server_addr).await?;
     info!("Started RPC server with listening address: '{server_addr:?}'");
-    listener.config_mut().max_frame_length(usize::MAX);
-    listener
-        // Ignore accept errors.
-        .filter_map(|r| future::ready(r.ok()))
-        .map(server::BaseChannel::with_defaults)
-        // serve is generated by the service attribute. It takes as input any type implementing
-        // the generated World trait.
-        .map(|channel| {
-            let server = TarpcServer {
-                peer_addr: channel.transport().peer_addr().unwrap(),
-                msg_tx: msg_tx.clone(),
-            };
-            channel.execute(server.serve()).for_each(|fut| async {
-                tokio::spawn(fut);
-            })
+    // Advertised as long as the server is running
+    let device_id = auth::hex(&pairings.device_id());
+    let _advertisement = Advertisement::new(&device_name, &device_id, RPC_PORT)
+        .inspect_err(|e| warn!("Advertising RPC server through mDNS failed, Err: {e:?}"))
+        .ok();
+    let uploads = Arc::new(Mutex::new(Uploads::default()));
+    stream::unfold(listener, |listener| async move {
+        Some((listener.accept().await, listener))
+    })
+    // Ignore accept errors.
+    .filter_map(|r| future::ready(r.ok()))
+    .map(|(stream, peer_addr)| {
+        let pairings = Arc::clone(&pairings);
+        let acceptor = acceptor.clone();
+        async move {
+            // Only authenticated hosts get a channel, paired hosts have to connect again
+            transport::accept(stream, pairings.as_ref(), &acceptor)
+                .await
+                .inspect_err(|e| warn!("Rejected connection from '{peer_addr}', Err: {e:?}"))
+                .ok()
+                .flatten()
+        }
+    })
+    .buffer_unordered(10)
+    .filter_map(future::ready)
+    .map(server::BaseChannel::with_defaults)
+    // serve is generated by the service attribute. It takes as input any type implementing
+    // the generated World trait.
+    .map(|channel| {
+        let server = TarpcServer {
+            peer_addr: channel.transport().get_ref().peer_addr().unwrap(),
+            msg_tx: msg_tx.clone(),
+            uploads: Arc::clone(&uploads),
+        };
+        channel.execute(server.serve()).for_each(|fut| async {
+            tokio::spawn(fut);
         })
-        // Max 10 channels.
-        .buffer_unordered(10)
-        .for_each(|_| async {})
-        .await;
+    })
+    // Max 10 channels.
+    .buffer_unordered(10)
+    .for_each(|_| async {})
+    .await;
     Ok(())
 }
 
@@ -712,30 +1161,45 @@ async fn spawn_signal_handler_task(quit_token: CancellationToken) -> anyhow::Res
 fn spawn_msg_handler_task(
     iv: &'static inkview::bindings::Inkview,
     mut msg_rx: UnboundedReceiver<Msg>,
-    file_save_tx: UnboundedSender<(PathBuf, Vec<u8>)>,
-    file_save_task: JoinHandle<()>,
+    file_op_tx: UnboundedSender<FileOp>,
+    file_op_task: JoinHandle<()>,
     logfile_guard: WorkerGuard,
     quit_token: CancellationToken,
     exit_cleanup_token: CancellationToken,
 ) {
     let mut ui_state = tokio::runtime::Handle::current().block_on(async move {
-        UiState::with_loaded_cheatsheets()
-            .await
-            .inspect_err(|e| error!("Display state image loading failed, Err: {e:?}"))
-            .unwrap_or_default()
+        match UiState::with_loaded_cheatsheets().await {
+            Ok(ui_state) => ui_state,
+            Err(e) => {
+                error!("Loading cheatsheets failed, refusing changes to them. Err: {e:?}");
+                UiState {
+                    library_error: Some(format!("{e:#}")),
+                    ..Default::default()
+                }
+            }
+        }
     });
     let mut display: OnceCell<InkviewDisplay> = OnceCell::new();
 
     loop {
         let mut repaint = false;
-        let mut save_cheatsheets = false;
-        let mut save_metadata = false;
 
         let msg = msg_rx.blocking_recv();
         debug!("Handling received message:\n{msg:?}");
         let Some(msg) = msg else {
             continue;
         };
+        let msg = match &ui_state.library_error {
+            Some(reason) => match msg.into_library_change() {
+                Ok(reply_tx) => {
+                    let reason = reason.clone();
+                    send_reply(reply_tx, Err(ComError::LibraryUnavailable { reason }));
+                    continue;
+                }
+                Err(msg) => msg,
+            },
+            None => msg,
+        };
 
         match msg {
             Msg::InkviewEvent(event) => {
@@ -820,49 +1284,92 @@ fn spawn_msg_handler_task(
                 }
             }
             Msg::FocusedWindow(info) => {
-                if info.wm_class != ui_state.focused_window_info.wm_class
+                let tags_changed = ui_state.cheatsheets.window_tags(&info)
+                    != ui_state
+                        .cheatsheets
+                        .window_tags(&ui_state.focused_window_info);
+                if info.wm_class == ui_state.focused_window_info.wm_class && tags_changed {
+                    // Different sheets within the same wm class, e.g. another browser tab
+                    ui_state.current_page.insert(info.wm_class.clone(), 0);
+                }
+                if (info.wm_class != ui_state.focused_window_info.wm_class || tags_changed)
                     && ui_state.mode == UiMode::AutomaticWmClass
                 {
                     repaint = true;
                 }
                 ui_state.focused_window_info = info;
             }
-            Msg::GetInfo(tx) => {
-                if let Some(display) = display.get() {
+            Msg::PairingCode(code) => {
+                ui_state.pairing_code = code;
+                repaint = true;
+            }
+            Msg::GetInfo(reply_tx) => {
+                let res = if let Some(display) = display.get() {
                     let size = display.size();
                     let orientation = display.iv_screen_ref().orientation();
                     let cheatsheets = ui_state.cheatsheets.get_sheet_tags();
                     let wm_classes = ui_state.cheatsheets.get_wm_classes_tags();
-                    if tx
-                        .send(Info {
-                            screen_width: size.width,
-                            screen_height: size.height,
-                            screen_orientation: screen_orientation_iv_to_com(orientation),
-                            cheatsheets,
-                            wm_classes,
-                        })
-                        .is_err()
-                    {
-                        error!(
-                            "Sending screen info answer over channel failed, receiver half dropped"
-                        );
-                    }
+                    Ok(Info {
+                        screen_width: size.width,
+                        screen_height: size.height,
+                        screen_dpi: display.iv_screen_ref().dpi(),
+                        screen_orientation: screen_orientation_iv_to_com(orientation),
+                        cheatsheets,
+                        wm_classes,
+                    })
                 } else {
                     warn!("Display not initialized yet when trying to retrieve its dimensions");
-                }
+                    Err(ComError::DisplayNotInitialized)
+                };
+                send_reply(reply_tx, res);
             }
-            Msg::UploadCheatsheet { image, name, tags } => {
-                ui_state
+            Msg::UploadCheatsheet {
+                image,
+                name,
+                tags,
+                page_breaks,
+                reply_tx,
+            } => {
+                let content_hash = match pb_cheatsheet_com::content_hash(&image, &page_breaks) {
+                    Ok(content_hash) => content_hash,
+                    Err(e) => {
+                        error!("Hashing uploaded cheatsheet '{name}' failed, Err: {e:?}");
+                        send_reply(reply_tx, Err(ComError::InvalidImage));
+                        continue;
+                    }
+                };
+                let sheet = match Cheatsheet::compressed(image) {
+                    Ok(sheet) => sheet,
+                    Err(e) => {
+                        error!("Compressing uploaded cheatsheet '{name}' failed, Err: {e:?}");
+                        send_reply(reply_tx, Err(ComError::InvalidImage));
+                        continue;
+                    }
+                };
+                // Uploading a new image of a sheet keeps its place and selector
+                let (priority, selector) = ui_state
                     .cheatsheets
-                    .insert_sheet(Cheatsheet { image }, name, tags);
-                let n_sheets = ui_state
+                    .get_sheet(&name)
+                    .map(|metadata| (metadata.priority, metadata.selector.clone()))
+                    .unwrap_or_default();
+                let metadata = CheatsheetMetadata {
+                    tags,
+                    page_breaks,
+                    content_hash,
+                    modified: Some(SystemTime::now()),
+                    priority,
+                    selector,
+                    ..Default::default()
+                };
+                ui_state.cheatsheets.insert_sheet(sheet, name, metadata);
+                let n_pages = ui_state
                     .cheatsheets
-                    .wm_class_n_sheets(&ui_state.focused_window_info.wm_class);
+                    .window_n_pages(&ui_state.focused_window_info, ui_state.screen_height);
                 if let Some(page) = ui_state
                     .current_page
                     .get_mut(&ui_state.focused_window_info.wm_class)
                 {
-                    *page = page.saturating_add(1).min(n_sheets.saturating_sub(1));
+                    *page = page.saturating_add(1).min(n_pages.saturating_sub(1));
                 } else {
                     ui_state
                         .current_page
@@ -870,77 +1377,127 @@ fn spawn_msg_handler_task(
                 };
 
                 repaint = true;
-                save_cheatsheets = true;
+                send_reply(reply_tx, Ok(()));
             }
-            Msg::RemoveCheatsheet { name } => {
-                ui_state.cheatsheets.remove_sheet(&name);
+            Msg::RemoveCheatsheet { name, reply_tx } => {
+                let res = match ui_state.cheatsheets.remove_sheet(&name) {
+                    Some(_) => Ok(()),
+                    None => Err(ComError::CheatsheetNotFound { name }),
+                };
+                if res.is_ok() {
+                    ui_state.clamp_current_pages();
+                    repaint = true;
+                }
+                send_reply(reply_tx, res);
             }
-            Msg::UploadScreenshot { screenshot, name } => {
+            Msg::UploadScreenshot {
+                screenshot,
+                name,
+                reply_tx,
+            } => {
                 ui_state.screenshot = Some((Cheatsheet { image: screenshot }, name));
+                ui_state.screenshot_current_page = 0;
                 ui_state.mode = UiMode::Screenshot;
                 repaint = true;
+                send_reply(reply_tx, Ok(()));
             }
-            Msg::ClearScreenshot => {
+            Msg::ClearScreenshot { reply_tx } => {
                 ui_state.screenshot.take();
                 repaint = true;
+                send_reply(reply_tx, Ok(()));
             }
-            Msg::AddCheatsheetTags { name, tags } => {
-                for tag in tags {
-                    if let Err(e) = ui_state.cheatsheets.add_sheet_tag(&name, tag) {
-                        error!("Failed to add tag to cheatsheet '{name}', Err: {e:?}");
-                    }
+            Msg::AddCheatsheetTags {
+                name,
+                tags,
+                reply_tx,
+            } => {
+                let res = ui_state.cheatsheets.add_sheet_tags(&name, tags);
+                if res.is_ok() {
+                    repaint = true;
                 }
-                repaint = true;
-                save_metadata = true;
+                send_reply(reply_tx, res);
             }
-            Msg::RemoveCheatsheetTags { name, either } => {
-                match either {
-                    TagsEither::Tags(tags) => {
-                        for tag in tags {
-                            if let Err(e) = ui_state.cheatsheets.remove_sheet_tag(&name, &tag) {
-                                error!(
-                                        "Failed to remove tag '{tag}' from cheatsheet '{name}', Err: {e:?}"
-                                    );
-                            }
-                        }
-                    }
-                    TagsEither::All => {
-                        if let Err(e) = ui_state.cheatsheets.clear_sheet_tags(&name) {
-                            error!("Failed to remove clear cheatsheet '{name}' tags, Err: {e:?}");
-                        }
-                    }
+            Msg::RemoveCheatsheetTags {
+                name,
+                either,
+                reply_tx,
+            } => {
+                let res = match either {
+                    TagsEither::Tags(tags) => ui_state.cheatsheets.remove_sheet_tags(&name, &tags),
+                    TagsEither::All => ui_state.cheatsheets.clear_sheet_tags(&name),
                 };
-                repaint = true;
-                save_metadata = true;
+                if res.is_ok() {
+                    repaint = true;
+                }
+                send_reply(reply_tx, res);
             }
-            Msg::AddWmClassTags { wm_class, tags } => {
-                for tag in tags {
-                    ui_state.cheatsheets.add_wm_class_tag(&wm_class, tag);
+            Msg::SetCheatsheetPriority {
+                name,
+                priority,
+                reply_tx,
+            } => {
+                let res = ui_state.cheatsheets.set_sheet_priority(&name, priority);
+                if res.is_ok() {
+                    repaint = true;
                 }
+                send_reply(reply_tx, res);
+            }
+            Msg::SetCheatsheetSelector {
+                name,
+                selector,
+                reply_tx,
+            } => {
+                let res = selector
+                    .as_deref()
+                    .map(TagSelector::parse)
+                    .transpose()
+                    .and_then(|selector| ui_state.cheatsheets.set_sheet_selector(&name, selector));
+                if res.is_ok() {
+                    repaint = true;
+                }
+                send_reply(reply_tx, res);
+            }
+            Msg::AddWmClassTags {
+                wm_class,
+                tags,
+                reply_tx,
+            } => {
+                ui_state.cheatsheets.add_wm_class_tags(&wm_class, tags);
                 repaint = true;
-                save_metadata = true;
+                send_reply(reply_tx, Ok(()));
             }
-            Msg::RemoveWmClassTags { wm_class, either } => {
-                match either {
+            Msg::RemoveWmClassTags {
+                wm_class,
+                either,
+                reply_tx,
+            } => {
+                let res = match either {
                     TagsEither::Tags(tags) => {
-                        for tag in tags {
-                            if let Err(e) =
-                                ui_state.cheatsheets.remove_wm_class_tag(&wm_class, &tag)
-                            {
-                                error!(
-                                        "Failed to remove tag '{tag}' from wm class '{wm_class}', Err: {e:?}"
-                                    );
-                            }
-                        }
-                    }
-                    TagsEither::All => {
-                        if let Err(e) = ui_state.cheatsheets.remove_wm_class(&wm_class) {
-                            error!("Failed to remove clear wm class '{wm_class}' tags, Err: {e:?}");
-                        }
+                        ui_state.cheatsheets.remove_wm_class_tags(&wm_class, &tags)
                     }
+                    TagsEither::All => ui_state.cheatsheets.remove_wm_class(&wm_class).map(|_| ()),
                 };
-                repaint = true;
-                save_metadata = true;
+                if res.is_ok() {
+                    repaint = true;
+                }
+                send_reply(reply_tx, res);
+            }
+            Msg::GetMatchRules(reply_tx) => {
+                send_reply(reply_tx, Ok(ui_state.cheatsheets.get_match_rules()));
+            }
+            Msg::SetMatchRule { rule, reply_tx } => {
+                let res = ui_state.cheatsheets.set_match_rule(rule);
+                if res.is_ok() {
+                    repaint = true;
+                }
+                send_reply(reply_tx, res);
+            }
+            Msg::RemoveMatchRule { name, reply_tx } => {
+                let res = ui_state.cheatsheets.remove_match_rule(&name).map(|_| ());
+                if res.is_ok() {
+                    repaint = true;
+                }
+                send_reply(reply_tx, res);
             }
         }
 
@@ -957,50 +1514,56 @@ fn spawn_msg_handler_task(
             display.flush();
         }
 
-        if save_cheatsheets {
-            debug!("Saving cheatsheets and metadata");
+        ui_state.cheatsheets.poll_saves();
+        if ui_state.library_error.is_none() && ui_state.cheatsheets.is_dirty() {
+            debug!("Saving changed cheatsheets and metadata");
 
-            if let Err(e) = ui_state.cheatsheets.dispatch_save_all(
+            if let Err(e) = ui_state.cheatsheets.dispatch_save(
                 PathBuf::from(CLIENT_DATA_DIR).join(CHEATSHEETS_SUBFOLDER),
-                file_save_tx.clone(),
+                file_op_tx.clone(),
             ) {
                 error!("Failed to dispatch saving cheatsheets, Err: {e:?}");
             };
-        } else if save_metadata {
-            debug!("Saving metadata");
-
-            if let Err(e) = ui_state.cheatsheets.dispatch_save_metadata(
-                PathBuf::from(CLIENT_DATA_DIR).join(CHEATSHEETS_SUBFOLDER),
-                file_save_tx.clone(),
-            ) {
-                error!("Failed to dispatch saving metadata, Err: {e:?}");
-            };
         }
 
         if quit_token.is_cancelled() {
-            debug!("Quitting! Saving cheatsheets and metadata");
-
-            if let Err(e) = ui_state.cheatsheets.dispatch_save_all(
-                PathBuf::from(CLIENT_DATA_DIR).join(CHEATSHEETS_SUBFOLDER),
-                file_save_tx,
-            ) {
-                error!("Failed to dispatch saving images on exit, Err: {e:?}");
-            };
+            if ui_state.library_error.is_none() {
+                debug!("Quitting! Saving changed cheatsheets and metadata");
+
+                if let Err(e) = ui_state.cheatsheets.dispatch_save(
+                    PathBuf::from(CLIENT_DATA_DIR).join(CHEATSHEETS_SUBFOLDER),
+                    file_op_tx.clone(),
+                ) {
+                    error!("Failed to dispatch saving cheatsheets on exit, Err: {e:?}");
+                };
+            }
 
             break;
         }
     }
 
     info!("Exiting..");
+    // The file operation task finishes once the sender is closed
+    drop(file_op_tx);
     tokio::runtime::Handle::current().block_on(async move {
-        if let Err(e) = file_save_task.await {
-            error!("File save task failed, Err: {e:?}");
+        if let Err(e) = file_op_task.await {
+            error!("File operation task failed, Err: {e:?}");
         }
         drop(logfile_guard);
     });
     exit_cleanup_token.cancel();
 }
 
+/// Sends the result of a handled message back to the RPC handler.
+fn send_reply<T>(reply_tx: ReplyTx<T>, res: Result<T, ComError>) {
+    if let Err(e) = &res {
+        error!("Handling message failed, Err: {e}");
+    }
+    if reply_tx.send(res).is_err() {
+        error!("Sending reply over channel failed, receiver half dropped");
+    }
+}
+
 fn screen_orientation_iv_to_com(
     orientation: inkview::screen::ScreenOrientation,
 ) -> pb_cheatsheet_com::ScreenOrientation {
@@ -1019,3 +1582,44 @@ fn screen_orientation_iv_to_com(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn save_files_skips_removes_after_failed_write() {
+        let dir = std::env::temp_dir().join(format!("pb-cheatsheet-save-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        let legacy = dir.join("legacy.cs");
+        fs::write(&legacy, b"legacy").await.unwrap();
+        // A file can't be the parent of another file, so writing it fails
+        let unwritable = legacy.join("blob.cs");
+
+        let written = save_files(
+            &[
+                (dir.join("first.cs"), b"first".to_vec()),
+                (unwritable, b"blob".to_vec()),
+                (dir.join("library.json"), b"{}".to_vec()),
+            ],
+            std::slice::from_ref(&legacy),
+        )
+        .await;
+        assert!(!written);
+        assert!(dir.join("first.cs").exists());
+        assert!(!dir.join("library.json").exists());
+        assert!(legacy.exists());
+
+        let written = save_files(
+            &[(dir.join("library.json"), b"{}".to_vec())],
+            std::slice::from_ref(&legacy),
+        )
+        .await;
+        assert!(written);
+        assert!(dir.join("library.json").exists());
+        assert!(!legacy.exists());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
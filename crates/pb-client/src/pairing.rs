@@ -0,0 +1,153 @@
+use crate::cheatsheets::{quarantine, QUARANTINE_SUBFOLDER};
+use crate::{write_file_atomic, Msg};
+use anyhow::Context;
+use pb_cheatsheet_com::auth::{self, DeviceId, KeyId, PairingStore, Secret};
+use pb_cheatsheet_com::tls::Identity;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{error, info};
+
+/// The device id and the keys of the paired hosts, persisted in the data directory.
+#[derive(Debug)]
+pub(crate) struct Pairings {
+    path: PathBuf,
+    data: Mutex<PairingsData>,
+    /// Held while saving, so a later state never gets overwritten by an earlier one.
+    saving: tokio::sync::Mutex<()>,
+    msg_tx: UnboundedSender<Msg>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PairingsData {
+    #[serde(with = "auth::hex_bytes")]
+    device_id: DeviceId,
+    /// Paired hosts by the hex encoded key id.
+    hosts: BTreeMap<String, PairedHost>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PairedHost {
+    #[serde(with = "auth::hex_bytes")]
+    secret: Secret,
+    /// Unix timestamp of the pairing.
+    paired: u64,
+}
+
+impl Pairings {
+    /// Loads the pairings, a new device id is generated on first start.
+    ///
+    /// Pairings that can't be parsed are moved to quarantine and the device starts unpaired.
+    pub(crate) async fn load(path: PathBuf, msg_tx: UnboundedSender<Msg>) -> anyhow::Result<Self> {
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    error!(
+                        "Parsing pairings '{}' failed, moving them to quarantine. Err: {e:?}",
+                        path.display()
+                    );
+                    if let Some(dir) = path.parent() {
+                        quarantine(&dir.join(QUARANTINE_SUBFOLDER), &[&path]).await;
+                    }
+                    None
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("Read pairings '{}'", path.display())),
+        };
+        let data = match data {
+            Some(data) => data,
+            None => {
+                let data = PairingsData {
+                    device_id: auth::random(),
+                    hosts: BTreeMap::new(),
+                };
+                save(&path, &data).await?;
+                info!("Generated device id '{}'", auth::hex(&data.device_id));
+                data
+            }
+        };
+        Ok(Self {
+            path,
+            data: Mutex::new(data),
+            saving: tokio::sync::Mutex::new(()),
+            msg_tx,
+        })
+    }
+}
+
+impl PairingStore for Pairings {
+    fn device_id(&self) -> DeviceId {
+        self.data.lock().unwrap().device_id
+    }
+
+    fn secret(&self, key_id: &KeyId) -> Option<Secret> {
+        self.data
+            .lock()
+            .unwrap()
+            .hosts
+            .get(&auth::hex(key_id))
+            .map(|host| host.secret)
+    }
+
+    async fn insert(&self, key_id: KeyId, secret: Secret) -> anyhow::Result<()> {
+        let _saving = self.saving.lock().await;
+        let data = {
+            let mut data = self.data.lock().unwrap();
+            let paired = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            data.hosts
+                .insert(auth::hex(&key_id), PairedHost { secret, paired });
+            data.clone()
+        };
+        save(&self.path, &data).await
+    }
+
+    fn show_code(&self, code: Option<&str>) {
+        if self
+            .msg_tx
+            .send(Msg::PairingCode(code.map(str::to_string)))
+            .is_err()
+        {
+            error!("Sending pairing code failed, receiving half closed");
+        }
+    }
+}
+
+/// Loads the TLS certificate and private key of the device, they are generated on first start.
+pub(crate) fn load_identity(cert_path: &Path, key_path: &Path) -> anyhow::Result<Identity> {
+    match (std::fs::read(cert_path), std::fs::read(key_path)) {
+        (Ok(cert_der), Ok(key_der)) => Ok(Identity::from_der(cert_der, key_der)),
+        (Err(e), _) | (_, Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            let identity = Identity::generate()?;
+            std::fs::write(cert_path, identity.cert_der())
+                .with_context(|| format!("Write certificate '{}'", cert_path.display()))?;
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options
+                .open(key_path)
+                .and_then(|mut file| file.write_all(identity.key_der()))
+                .with_context(|| format!("Write private key '{}'", key_path.display()))?;
+            info!(
+                "Generated TLS certificate with fingerprint '{}'",
+                auth::hex(&identity.fingerprint())
+            );
+            Ok(identity)
+        }
+        (Err(e), _) | (_, Err(e)) => Err(e).context("Read TLS certificate and private key"),
+    }
+}
+
+async fn save(path: &Path, data: &PairingsData) -> anyhow::Result<()> {
+    write_file_atomic(path, &serde_json::to_vec_pretty(data)?)
+        .await
+        .with_context(|| format!("Write pairings '{}'", path.display()))
+}
@@ -0,0 +1,229 @@
+use pb_cheatsheet_com::{CheatsheetImage, ComError, UploadHeader, UploadSession, UploadTarget};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+
+/// Chunked uploads that are in progress.
+///
+/// Sessions are not bound to the RPC channel they were started on,
+/// so an upload can be resumed after the host reconnected.
+#[derive(Debug, Default)]
+pub(crate) struct Uploads {
+    next_id: u64,
+    sessions: HashMap<u64, Upload>,
+}
+
+#[derive(Debug)]
+struct Upload {
+    target: UploadTarget,
+    header: UploadHeader,
+    data: Vec<u8>,
+    last_activity: Instant,
+}
+
+impl Uploads {
+    /// Sessions that did not receive any data for this duration get discarded.
+    const SESSION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+    /// Sessions kept at the same time, the least recently active one is discarded for a new one.
+    const MAX_SESSIONS: usize = 2;
+    /// Maximum length of the uploaded data, well above a compressed image of a fit-width sheet.
+    const MAX_LEN: u64 = 32 * 1024 * 1024;
+    /// Maximum length of the image data after decompressing it.
+    const MAX_IMAGE_LEN: u64 = 64 * 1024 * 1024;
+
+    /// Begins a new upload session.
+    ///
+    /// If an upload with the same target and header is already in progress,
+    /// its session is returned instead so that the upload can be resumed.
+    pub(crate) fn begin(
+        &mut self,
+        target: UploadTarget,
+        header: UploadHeader,
+    ) -> Result<UploadSession, ComError> {
+        self.remove_expired();
+
+        if header.len > Self::MAX_LEN {
+            return Err(ComError::UploadTooLarge {
+                len: header.len,
+                max: Self::MAX_LEN,
+            });
+        }
+        let image_len = header.format.data_len(header.width, header.height).ok_or(
+            ComError::UnsupportedImageFormat {
+                format: header.format,
+            },
+        )?;
+        if image_len > Self::MAX_IMAGE_LEN {
+            return Err(ComError::UploadTooLarge {
+                len: image_len,
+                max: Self::MAX_IMAGE_LEN,
+            });
+        }
+        if !header.format.is_compressed() && header.len != image_len {
+            return Err(ComError::InvalidImage);
+        }
+
+        if let Some((id, upload)) = self
+            .sessions
+            .iter_mut()
+            .find(|(_, u)| u.target == target && u.header == header)
+        {
+            upload.last_activity = Instant::now();
+            debug!("Resuming upload session '{id}'");
+            return Ok(UploadSession {
+                id: *id,
+                offset: upload.data.len() as u64,
+            });
+        }
+
+        while self.sessions.len() >= Self::MAX_SESSIONS {
+            let Some(oldest) = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, upload)| upload.last_activity)
+                .map(|(id, _)| *id)
+            else {
+                break;
+            };
+            debug!("Discarding least recently active upload session '{oldest}'");
+            self.sessions.remove(&oldest);
+        }
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.sessions.insert(
+            id,
+            Upload {
+                target,
+                header,
+                data: Vec::new(),
+                last_activity: Instant::now(),
+            },
+        );
+        debug!("Began upload session '{id}'");
+        Ok(UploadSession { id, offset: 0 })
+    }
+
+    /// Appends a chunk to the upload session.
+    ///
+    /// Returns the number of bytes received so far.
+    /// The session is discarded when the chunk exceeds the announced length or is corrupted,
+    /// a chunk not starting where the received data ends can be sent again.
+    pub(crate) fn chunk(
+        &mut self,
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+        checksum: u32,
+    ) -> Result<u64, ComError> {
+        let upload = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(ComError::UploadSessionNotFound { id })?;
+        let received = upload.data.len() as u64;
+        let len = data.len() as u64;
+
+        if offset != received {
+            return Err(ComError::UploadOffsetMismatch {
+                expected: received,
+                offset,
+            });
+        }
+        if received + len > upload.header.len {
+            let expected = upload.header.len;
+            self.sessions.remove(&id);
+            return Err(ComError::UploadLengthMismatch {
+                expected,
+                received: received + len,
+            });
+        }
+        if pb_cheatsheet_com::checksum(&data) != checksum {
+            self.sessions.remove(&id);
+            return Err(ComError::ChecksumMismatch { offset, len });
+        }
+
+        upload.data.extend_from_slice(&data);
+        upload.last_activity = Instant::now();
+        Ok(upload.data.len() as u64)
+    }
+
+    /// Finishes the upload session and returns the received upload, see [ReceivedUpload::validate].
+    ///
+    /// The session is discarded, also when the received data is incomplete.
+    pub(crate) fn commit(&mut self, id: u64) -> Result<ReceivedUpload, ComError> {
+        let Upload {
+            target,
+            header,
+            data,
+            ..
+        } = self
+            .sessions
+            .remove(&id)
+            .ok_or(ComError::UploadSessionNotFound { id })?;
+        let received = data.len() as u64;
+        if received != header.len {
+            return Err(ComError::UploadLengthMismatch {
+                expected: header.len,
+                received,
+            });
+        }
+        Ok(ReceivedUpload {
+            id,
+            target,
+            header,
+            data,
+        })
+    }
+
+    fn remove_expired(&mut self) {
+        self.sessions.retain(|id, upload| {
+            let expired = upload.last_activity.elapsed() > Self::SESSION_TIMEOUT;
+            if expired {
+                debug!("Discarding expired upload session '{id}'");
+            }
+            !expired
+        });
+    }
+}
+
+/// The complete data of a committed upload session, not validated yet.
+#[derive(Debug)]
+pub(crate) struct ReceivedUpload {
+    id: u64,
+    target: UploadTarget,
+    header: UploadHeader,
+    data: Vec<u8>,
+}
+
+impl ReceivedUpload {
+    /// Returns the received image, when the data is not corrupted and matches the size and format of the image.
+    ///
+    /// Decompresses the image data, so it blocks for a while with large images.
+    pub(crate) fn validate(self) -> Result<(UploadTarget, CheatsheetImage), ComError> {
+        let Self {
+            id,
+            target,
+            header,
+            data,
+        } = self;
+        if pb_cheatsheet_com::checksum(&data) != header.checksum {
+            return Err(ComError::ChecksumMismatch {
+                offset: 0,
+                len: header.len,
+            });
+        }
+        let image = CheatsheetImage {
+            format: header.format,
+            byte_order: header.byte_order,
+            width: header.width,
+            height: header.height,
+            data,
+        };
+        if let Err(e) = image.validate() {
+            error!("Uploaded image of session '{id}' is invalid, Err: {e:?}");
+            return Err(ComError::InvalidImage);
+        }
+        debug!("Committed upload session '{id}'");
+        Ok((target, image))
+    }
+}
@@ -1,11 +1,57 @@
 use core::net::{Ipv4Addr, SocketAddr};
-use futures::{future, prelude::*};
-use pb_cheatsheet_com::{CheatsheetImage, FocusedWindowInfo, Info, TagsEither, World, RPC_PORT};
-use std::collections::HashSet;
+use futures::{future, prelude::*, stream};
+use pb_cheatsheet_com::auth::{self, DeviceId, KeyId, PairingStore, Secret};
+use pb_cheatsheet_com::discovery::Advertisement;
+use pb_cheatsheet_com::tls::{self, Identity};
+use pb_cheatsheet_com::transport;
+use pb_cheatsheet_com::{
+    BuildInfo, Capabilities, ComError, Feature, FocusedWindowInfo, ImageFormat, Info, MatchRule,
+    TagsEither, UploadHeader, UploadSession, UploadTarget, World, PROTOCOL_VERSION, RPC_PORT,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use tarpc::context::Context;
 use tarpc::server::incoming::Incoming;
 use tarpc::server::{self, Channel};
-use tarpc::tokio_serde::formats::Json;
+use tokio::net::TcpListener;
+
+/// Keeps paired hosts in memory, so they have to pair again after a restart.
+#[derive(Debug)]
+struct MemoryPairingStore {
+    device_id: DeviceId,
+    secrets: Mutex<HashMap<KeyId, Secret>>,
+}
+
+impl MemoryPairingStore {
+    fn new() -> Self {
+        Self {
+            device_id: auth::random(),
+            secrets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl PairingStore for MemoryPairingStore {
+    fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    fn secret(&self, key_id: &KeyId) -> Option<Secret> {
+        self.secrets.lock().unwrap().get(key_id).copied()
+    }
+
+    async fn insert(&self, key_id: KeyId, secret: Secret) -> anyhow::Result<()> {
+        self.secrets.lock().unwrap().insert(key_id, secret);
+        Ok(())
+    }
+
+    fn show_code(&self, code: Option<&str>) {
+        match code {
+            Some(code) => println!("Pairing code: {code}"),
+            None => println!("Pairing ended"),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 struct TarpcServer {
@@ -14,105 +60,237 @@ struct TarpcServer {
 }
 
 impl pb_cheatsheet_com::World for TarpcServer {
-    async fn focused_window(self, _: Context, info: FocusedWindowInfo) {
+    async fn handshake(self, _: Context, protocol_version: u32) -> Result<Capabilities, ComError> {
+        println!("Received handshake");
+        println!("{protocol_version:#?}");
+        if protocol_version != PROTOCOL_VERSION {
+            return Err(ComError::UnsupportedProtocol {
+                version: protocol_version,
+                supported: PROTOCOL_VERSION,
+            });
+        }
+        Ok(Capabilities {
+            protocol_version: PROTOCOL_VERSION,
+            image_formats: vec![
+                ImageFormat::Gray8,
+                ImageFormat::Gray8Deflate,
+                ImageFormat::Gray1,
+                ImageFormat::Gray4,
+            ],
+            features: vec![
+                Feature::PageBreaks,
+                Feature::ContentHash,
+                Feature::MatchRules,
+            ],
+            build: BuildInfo {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                sdk: None,
+                debug: cfg!(debug_assertions),
+            },
+        })
+    }
+
+    async fn focused_window(self, _: Context, info: FocusedWindowInfo) -> Result<(), ComError> {
         println!("Received focused window");
         println!("{info:#?}");
+        Ok(())
     }
 
-    async fn get_info(self, _: Context) -> Info {
+    async fn get_info(self, _: Context) -> Result<Info, ComError> {
         println!("Received get info request");
-        Info {
+        Ok(Info {
             screen_width: 1920,
             screen_height: 1080,
+            screen_dpi: 96,
             screen_orientation: pb_cheatsheet_com::ScreenOrientation::default(),
             cheatsheets: Vec::new(),
             wm_classes: Vec::new(),
-        }
+        })
     }
 
-    async fn upload_cheatsheet(
+    async fn begin_upload(
         self,
         _: Context,
-        image: CheatsheetImage,
-        name: String,
-        tags: HashSet<String>,
-    ) {
-        println!("Received upload cheatsheet");
-        println!("{image:#?}");
-        println!("{name:#?}");
-        println!("{tags:#?}");
+        target: UploadTarget,
+        header: UploadHeader,
+    ) -> Result<UploadSession, ComError> {
+        println!("Received begin upload");
+        println!("{target:#?}");
+        println!("{header:#?}");
+        Ok(UploadSession { id: 0, offset: 0 })
     }
 
-    async fn remove_cheatsheet(self, _: Context, name: String) {
-        println!("Received remove cheatsheet");
-        println!("{name:#?}");
-    }
-
-    async fn upload_screenshot(
+    async fn upload_chunk(
         self,
         _: Context,
-        screenshot: CheatsheetImage,
-        name: Option<String>,
-    ) {
-        println!("Received upload screenshot");
-        println!("{screenshot:#?}");
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+        checksum: u32,
+    ) -> Result<u64, ComError> {
+        println!("Received upload chunk");
+        println!("{id:#?}");
+        println!("{offset:#?}");
+        println!("{checksum:#?}");
+        Ok(offset + data.len() as u64)
+    }
+
+    async fn commit_upload(self, _: Context, id: u64) -> Result<(), ComError> {
+        println!("Received commit upload");
+        println!("{id:#?}");
+        Ok(())
+    }
+
+    async fn remove_cheatsheet(self, _: Context, name: String) -> Result<(), ComError> {
+        println!("Received remove cheatsheet");
         println!("{name:#?}");
+        Ok(())
     }
 
-    async fn clear_screenshot(self, _: Context) {
+    async fn clear_screenshot(self, _: Context) -> Result<(), ComError> {
         println!("Received clear screenshot");
+        Ok(())
     }
 
-    async fn add_cheatsheet_tags(self, _: Context, name: String, tags: HashSet<String>) {
+    async fn add_cheatsheet_tags(
+        self,
+        _: Context,
+        name: String,
+        tags: HashSet<String>,
+    ) -> Result<(), ComError> {
         println!("Received add cheatsheet tags");
         println!("{name:#?}");
         println!("{tags:#?}");
+        Ok(())
     }
 
-    async fn remove_cheatsheet_tags(self, _: Context, name: String, either: TagsEither) {
+    async fn remove_cheatsheet_tags(
+        self,
+        _: Context,
+        name: String,
+        either: TagsEither,
+    ) -> Result<(), ComError> {
         println!("Received remove cheatsheet tags");
         println!("{name:#?}");
         println!("{either:#?}");
+        Ok(())
     }
 
-    async fn add_wm_class_tags(self, _: Context, wm_class: String, tags: HashSet<String>) {
+    async fn set_cheatsheet_priority(
+        self,
+        _: Context,
+        name: String,
+        priority: i32,
+    ) -> Result<(), ComError> {
+        println!("Received set cheatsheet priority");
+        println!("{name:#?}");
+        println!("{priority:#?}");
+        Ok(())
+    }
+
+    async fn set_cheatsheet_selector(
+        self,
+        _: Context,
+        name: String,
+        selector: Option<String>,
+    ) -> Result<(), ComError> {
+        println!("Received set cheatsheet selector");
+        println!("{name:#?}");
+        println!("{selector:#?}");
+        Ok(())
+    }
+
+    async fn add_wm_class_tags(
+        self,
+        _: Context,
+        wm_class: String,
+        tags: HashSet<String>,
+    ) -> Result<(), ComError> {
         println!("Received add wm class tags");
         println!("{wm_class:#?}");
         println!("{tags:#?}");
+        Ok(())
     }
 
-    async fn remove_wm_class_tags(self, _: Context, wm_class: String, either: TagsEither) {
+    async fn remove_wm_class_tags(
+        self,
+        _: Context,
+        wm_class: String,
+        either: TagsEither,
+    ) -> Result<(), ComError> {
         println!("Received remove wm class tags");
         println!("{wm_class:#?}");
         println!("{either:#?}");
+        Ok(())
+    }
+
+    async fn get_match_rules(self, _: Context) -> Result<Vec<MatchRule>, ComError> {
+        println!("Received get match rules");
+        Ok(Vec::new())
+    }
+
+    async fn set_match_rule(self, _: Context, rule: MatchRule) -> Result<(), ComError> {
+        println!("Received set match rule");
+        println!("{rule:#?}");
+        Ok(())
+    }
+
+    async fn remove_match_rule(self, _: Context, name: String) -> Result<(), ComError> {
+        println!("Received remove match rule");
+        println!("{name:#?}");
+        Ok(())
     }
 }
 
 async fn spawn_server() -> anyhow::Result<()> {
     let server_addr = (Ipv4Addr::new(0, 0, 0, 0), RPC_PORT);
-    let mut listener = tarpc::serde_transport::tcp::listen(&server_addr, Json::default).await?;
+    let listener = TcpListener::bind(&server_addr).await?;
     println!("Started RPC server with listening address: '{server_addr:?}'");
-    listener.config_mut().max_frame_length(usize::MAX);
-    listener
-        // Ignore accept errors.
-        .filter_map(|r| future::ready(r.ok()))
-        .map(server::BaseChannel::with_defaults)
-        // Limit channels to 1 per IP.
-        .max_channels_per_key(1, |t| t.transport().peer_addr().unwrap().ip())
-        // serve is generated by the service attribute. It takes as input any type implementing
-        // the generated World trait.
-        .map(|channel| {
-            let server = TarpcServer {
-                peer_addr: channel.transport().peer_addr().unwrap(),
-            };
-            channel.execute(server.serve()).for_each(|fut| async {
-                tokio::spawn(fut);
-            })
+    let store = Arc::new(MemoryPairingStore::new());
+    let _advertisement = Advertisement::new("testclient", &auth::hex(&store.device_id()), RPC_PORT)
+        .inspect_err(|e| eprintln!("Advertising RPC server through mDNS failed, Err: {e:?}"))
+        .ok();
+    let identity = Identity::generate()?;
+    println!(
+        "Generated TLS certificate with fingerprint: '{}'",
+        auth::hex(&identity.fingerprint())
+    );
+    let acceptor = tls::Acceptor::new(&identity)?;
+    stream::unfold(listener, |listener| async move {
+        Some((listener.accept().await, listener))
+    })
+    // Ignore accept errors.
+    .filter_map(|r| future::ready(r.ok()))
+    .map(|(stream, peer_addr)| {
+        let store = Arc::clone(&store);
+        let acceptor = acceptor.clone();
+        async move {
+            transport::accept(stream, store.as_ref(), &acceptor)
+                .await
+                .inspect_err(|e| eprintln!("Rejected connection from '{peer_addr}', Err: {e:?}"))
+                .ok()
+                .flatten()
+        }
+    })
+    .buffer_unordered(10)
+    .filter_map(future::ready)
+    .map(server::BaseChannel::with_defaults)
+    // Limit channels to 1 per IP.
+    .max_channels_per_key(1, |t| t.transport().get_ref().peer_addr().unwrap().ip())
+    // serve is generated by the service attribute. It takes as input any type implementing
+    // the generated World trait.
+    .map(|channel| {
+        let server = TarpcServer {
+            peer_addr: channel.transport().get_ref().peer_addr().unwrap(),
+        };
+        channel.execute(server.serve()).for_each(|fut| async {
+            tokio::spawn(fut);
         })
-        // Max 10 channels.
-        .buffer_unordered(10)
-        .for_each(|_| async {})
-        .await;
+    })
+    // Max 10 channels.
+    .buffer_unordered(10)
+    .for_each(|_| async {})
+    .await;
     Ok(())
 }
 